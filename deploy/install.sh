@@ -402,6 +402,16 @@ fi
 chmod 640 /opt/imgd/conf/imgd.env
 chown root:"$SERVICE_USER" /opt/imgd/conf/imgd.env
 
+if [[ ! -f /opt/imgd/conf/imgd.toml ]]; then
+  step "Writing config file /opt/imgd/conf/imgd.toml"
+  cat > /opt/imgd/conf/imgd.toml <<'TOML'
+# imgd settings (see deploy/imgd.toml in the repository for every key).
+# Values in imgd.env override the ones here.
+TOML
+  chmod 640 /opt/imgd/conf/imgd.toml
+  chown root:"$SERVICE_USER" /opt/imgd/conf/imgd.toml
+fi
+
 TOKENS_FILE="/opt/imgd/conf/tokens.json"
 if [[ ! -f "$TOKENS_FILE" ]]; then
   step "Initializing token store at $TOKENS_FILE"
@@ -421,7 +431,8 @@ Type=simple
 User=${SERVICE_USER}
 Group=${SERVICE_USER}
 WorkingDirectory=/opt/imgd
-ExecStart=/opt/imgd/bin/imgd
+ExecStart=/opt/imgd/bin/imgd --config /opt/imgd/conf/imgd.toml
+ExecReload=/bin/kill -HUP \$MAINPID
 EnvironmentFile=/opt/imgd/conf/imgd.env
 Restart=always
 RestartSec=3
@@ -458,6 +469,10 @@ server {
 
     types {
         image/webp webp;
+        image/png png;
+        image/jpeg jpg;
+        image/gif gif;
+        image/avif avif;
     }
     default_type application/octet-stream;
 
@@ -466,6 +481,11 @@ server {
     open_file_cache_min_uses 2;
     open_file_cache_errors on;
 
+    # index.jsonl and in-flight uploads live in dot-directories under the image root
+    location ~ /\. {
+        return 404;
+    }
+
     location /images/ {
         alias ${DATA_DIR}/;
         autoindex off;
@@ -480,10 +500,32 @@ server {
         proxy_http_version 1.1;
         proxy_set_header Host \$host;
         proxy_set_header X-Forwarded-For \$proxy_add_x_forwarded_for;
+        proxy_set_header Forwarded "";
+        proxy_set_header X-Real-IP "";
         proxy_set_header X-Forwarded-Proto \$scheme;
         client_max_body_size 6m;
     }
 
+    location /api/ {
+        proxy_pass http://127.0.0.1:${PORT}/api/;
+        proxy_http_version 1.1;
+        proxy_set_header Host \$host;
+        proxy_set_header X-Forwarded-For \$proxy_add_x_forwarded_for;
+        proxy_set_header Forwarded "";
+        proxy_set_header X-Real-IP "";
+        proxy_set_header X-Forwarded-Proto \$scheme;
+    }
+
+    location /admin/ {
+        proxy_pass http://127.0.0.1:${PORT}/admin/;
+        proxy_http_version 1.1;
+        proxy_set_header Host \$host;
+        proxy_set_header X-Forwarded-For \$proxy_add_x_forwarded_for;
+        proxy_set_header Forwarded "";
+        proxy_set_header X-Real-IP "";
+        proxy_set_header X-Forwarded-Proto \$scheme;
+    }
+
     location /healthz {
         proxy_pass http://127.0.0.1:${PORT}/healthz;
     }
@@ -503,7 +545,10 @@ fi
 step "Reloading systemd daemon"
 systemctl daemon-reload
 
-tokens_count="$(grep -o '"token"[[:space:]]*:' "$TOKENS_FILE" | wc -l | tr -d ' ')"
+step "Migrating plaintext tokens to salted hashes (if any)"
+/opt/imgd/bin/imgd token migrate --tokens-file "$TOKENS_FILE"
+
+tokens_count="$(grep -o '"name"[[:space:]]*:' "$TOKENS_FILE" | wc -l | tr -d ' ')"
 CREATED_TOKEN=""
 if [[ "${tokens_count}" == "0" && -z "$UPLOAD_TOKEN" ]]; then
   echo ""
@@ -0,0 +1,187 @@
+use axum::{
+    extract::{Path, State},
+    http::StatusCode,
+    Extension, Json,
+};
+use chrono::{DateTime, Utc};
+use serde::{Deserialize, Deserializer, Serialize};
+use tracing::info;
+
+use crate::{
+    error::AppError,
+    token::{expiry_after_days, generate_token, AuthorizedToken, Scope, TokenEntry},
+    AppState,
+};
+
+/// Public view of a [`TokenEntry`]; never includes the token or its hash.
+#[derive(Serialize)]
+pub struct TokenInfo {
+    pub name: String,
+    pub token_id: String,
+    pub expires_at: Option<String>,
+    pub rate_limit_per_minute: Option<usize>,
+    pub scopes: Vec<Scope>,
+    pub max_upload_bytes: Option<usize>,
+    pub quota_bytes: Option<u64>,
+    pub quota_files: Option<u64>,
+}
+
+impl From<&TokenEntry> for TokenInfo {
+    fn from(entry: &TokenEntry) -> Self {
+        Self {
+            name: entry.name.clone(),
+            token_id: entry.id(),
+            expires_at: entry.expires_at.clone(),
+            rate_limit_per_minute: entry.rate_limit_per_minute,
+            scopes: entry.effective_scopes(),
+            max_upload_bytes: entry.max_upload_bytes,
+            quota_bytes: entry.quota_bytes,
+            quota_files: entry.quota_files,
+        }
+    }
+}
+
+#[derive(Serialize)]
+pub struct CreatedToken {
+    /// Shown once; only its salted hash is stored.
+    pub token: String,
+    #[serde(flatten)]
+    pub info: TokenInfo,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct CreateTokenRequest {
+    pub name: String,
+    #[serde(default)]
+    pub expires_at: Option<DateTime<Utc>>,
+    #[serde(default)]
+    pub days: Option<i64>,
+    #[serde(default)]
+    pub rate_limit_per_minute: Option<usize>,
+    #[serde(default)]
+    pub scopes: Option<Vec<Scope>>,
+    #[serde(default)]
+    pub max_upload_bytes: Option<usize>,
+    #[serde(default)]
+    pub quota_bytes: Option<u64>,
+    #[serde(default)]
+    pub quota_files: Option<u64>,
+}
+
+/// Absent fields are left alone; an explicit `null` clears the value.
+#[derive(Debug, Default, Deserialize)]
+pub struct UpdateTokenRequest {
+    #[serde(default, deserialize_with = "present")]
+    pub expires_at: Option<Option<DateTime<Utc>>>,
+    #[serde(default, deserialize_with = "present")]
+    pub rate_limit_per_minute: Option<Option<usize>>,
+}
+
+fn present<'de, D, T>(deserializer: D) -> Result<Option<Option<T>>, D::Error>
+where
+    D: Deserializer<'de>,
+    T: Deserialize<'de>,
+{
+    Option::<T>::deserialize(deserializer).map(Some)
+}
+
+/// `GET /admin/tokens`
+pub async fn list_tokens(State(state): State<AppState>) -> Result<Json<Vec<TokenInfo>>, AppError> {
+    let file = state.token_store.read_file(&state.config).await?;
+    Ok(Json(file.tokens.iter().map(TokenInfo::from).collect()))
+}
+
+/// `POST /admin/tokens`
+pub async fn create_token(
+    State(state): State<AppState>,
+    Extension(auth): Extension<AuthorizedToken>,
+    Json(req): Json<CreateTokenRequest>,
+) -> Result<(StatusCode, Json<CreatedToken>), AppError> {
+    if req.name.trim().is_empty() || (req.expires_at.is_some() && req.days.is_some()) {
+        return Err(AppError::BadRequest);
+    }
+    let expires_at = match (req.expires_at, req.days) {
+        (Some(at), _) => Some(at),
+        (None, Some(days)) => Some(expiry_after_days(days).ok_or(AppError::BadRequest)?),
+        (None, None) => None,
+    };
+    let mut scopes = req.scopes.unwrap_or_else(|| Scope::DEFAULT.to_vec());
+    scopes.sort();
+    scopes.dedup();
+
+    let token = generate_token();
+    let entry = TokenEntry {
+        expires_at: expires_at.map(|at| at.to_rfc3339()),
+        rate_limit_per_minute: req.rate_limit_per_minute,
+        scopes: Some(scopes),
+        max_upload_bytes: req.max_upload_bytes,
+        quota_bytes: req.quota_bytes,
+        quota_files: req.quota_files,
+        ..TokenEntry::new(req.name, &token)
+    };
+    let info = TokenInfo::from(&entry);
+
+    state
+        .token_store
+        .edit_file(&state.config, move |file| {
+            file.tokens.push(entry);
+            Ok(())
+        })
+        .await?;
+
+    info!(admin = %auth.name, name = %info.name, token_id = %info.token_id, "token created");
+    Ok((StatusCode::CREATED, Json(CreatedToken { token, info })))
+}
+
+/// `PATCH /admin/tokens/{token_id}`
+pub async fn update_token(
+    State(state): State<AppState>,
+    Extension(auth): Extension<AuthorizedToken>,
+    Path(token_id): Path<String>,
+    Json(req): Json<UpdateTokenRequest>,
+) -> Result<Json<TokenInfo>, AppError> {
+    let id = token_id.clone();
+    let info = state
+        .token_store
+        .edit_file(&state.config, move |file| {
+            let entry = file
+                .tokens
+                .iter_mut()
+                .find(|e| e.id() == id)
+                .ok_or(AppError::NotFound)?;
+            if let Some(expires_at) = req.expires_at {
+                entry.expires_at = expires_at.map(|at| at.to_rfc3339());
+            }
+            if let Some(rate_limit) = req.rate_limit_per_minute {
+                entry.rate_limit_per_minute = rate_limit;
+            }
+            Ok(TokenInfo::from(&*entry))
+        })
+        .await?;
+
+    info!(admin = %auth.name, token_id = %token_id, "token updated");
+    Ok(Json(info))
+}
+
+/// `DELETE /admin/tokens/{token_id}`
+pub async fn delete_token(
+    State(state): State<AppState>,
+    Extension(auth): Extension<AuthorizedToken>,
+    Path(token_id): Path<String>,
+) -> Result<StatusCode, AppError> {
+    let id = token_id.clone();
+    state
+        .token_store
+        .edit_file(&state.config, move |file| {
+            let before = file.tokens.len();
+            file.tokens.retain(|e| e.id() != id);
+            if file.tokens.len() == before {
+                return Err(AppError::NotFound);
+            }
+            Ok(())
+        })
+        .await?;
+
+    info!(admin = %auth.name, token_id = %token_id, "token revoked");
+    Ok(StatusCode::NO_CONTENT)
+}
@@ -0,0 +1,328 @@
+use std::{sync::atomic::Ordering, time::Instant};
+
+use axum::{
+    extract::{Path, Query, State},
+    http::{HeaderMap, StatusCode},
+    Extension, Json,
+};
+use chrono::{DateTime, TimeDelta, Utc};
+use serde::{Deserialize, Serialize};
+use tracing::{error, info, warn};
+use uuid::Uuid;
+
+use crate::{
+    error::AppError,
+    index::ImageRecord,
+    serve::{is_sha256_hex, is_valid_prefix, StoredPath},
+    storage::Storage,
+    ticket::{TicketClaims, DEFAULT_TTL_SECS, MAX_TTL_SECS},
+    token::AuthorizedToken,
+    AppState,
+};
+
+const DEFAULT_PAGE_SIZE: usize = 50;
+const MAX_PAGE_SIZE: usize = 500;
+
+/// Query parameters for `GET /api/images`. `from` is inclusive and `to`
+/// exclusive; both are RFC 3339 timestamps.
+#[derive(Debug, Default, Deserialize)]
+pub struct ListParams {
+    /// Token name of the uploader.
+    pub token: Option<String>,
+    pub from: Option<DateTime<Utc>>,
+    pub to: Option<DateTime<Utc>>,
+    pub content_type: Option<String>,
+    pub min_size: Option<u64>,
+    pub max_size: Option<u64>,
+    #[serde(default)]
+    pub order: SortOrder,
+    pub limit: Option<usize>,
+    pub cursor: Option<String>,
+}
+
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum SortOrder {
+    Asc,
+    #[default]
+    Desc,
+}
+
+#[derive(Serialize)]
+pub struct ListResponse {
+    pub items: Vec<ImageRecord>,
+    pub next_cursor: Option<String>,
+}
+
+/// Position after the last item of a page, encoded as `<unix_nanos>.<sha256>`.
+/// Records are ordered by `(created_at, sha256)` so the position is stable
+/// while uploads and deletions happen between requests.
+#[derive(Debug, PartialEq, Eq)]
+struct Cursor {
+    created_at: DateTime<Utc>,
+    sha256: String,
+}
+
+impl Cursor {
+    fn of(record: &ImageRecord) -> Self {
+        Self {
+            created_at: record.created_at,
+            sha256: record.sha256.clone(),
+        }
+    }
+
+    fn encode(&self) -> Option<String> {
+        let nanos = self.created_at.timestamp_nanos_opt()?;
+        Some(format!("{nanos}.{}", self.sha256))
+    }
+
+    fn decode(raw: &str) -> Option<Self> {
+        let (nanos, sha256) = raw.split_once('.')?;
+        if !is_sha256_hex(sha256) {
+            return None;
+        }
+        Some(Self {
+            created_at: DateTime::from_timestamp_nanos(nanos.parse().ok()?),
+            sha256: sha256.to_owned(),
+        })
+    }
+
+    fn key(&self) -> (DateTime<Utc>, &str) {
+        (self.created_at, &self.sha256)
+    }
+}
+
+/// `GET /api/images`: pages through the upload index. Tokens without the
+/// `admin` scope only see their own uploads.
+pub async fn list_images(
+    State(state): State<AppState>,
+    Extension(auth): Extension<AuthorizedToken>,
+    Query(params): Query<ListParams>,
+) -> Result<Json<ListResponse>, AppError> {
+    let limit = params.limit.unwrap_or(DEFAULT_PAGE_SIZE);
+    if limit == 0 {
+        return Err(AppError::BadRequest);
+    }
+    let limit = limit.min(MAX_PAGE_SIZE);
+    let after = match params.cursor.as_deref() {
+        Some(raw) => Some(Cursor::decode(raw).ok_or(AppError::BadRequest)?),
+        None => None,
+    };
+
+    let mut items = state
+        .index
+        .select(|r| {
+            (auth.is_admin() || r.token_id == auth.token_id)
+                && params.token.as_ref().is_none_or(|n| &r.token_name == n)
+                && params.from.is_none_or(|from| r.created_at >= from)
+                && params.to.is_none_or(|to| r.created_at < to)
+                && params
+                    .content_type
+                    .as_ref()
+                    .is_none_or(|ct| r.content_type.eq_ignore_ascii_case(ct))
+                && params.min_size.is_none_or(|min| r.size >= min)
+                && params.max_size.is_none_or(|max| r.size <= max)
+                && after.as_ref().is_none_or(|c| {
+                    let key = (r.created_at, r.sha256.as_str());
+                    match params.order {
+                        SortOrder::Asc => key > c.key(),
+                        SortOrder::Desc => key < c.key(),
+                    }
+                })
+        })
+        .await;
+
+    items.sort_by(|a, b| (a.created_at, &a.sha256).cmp(&(b.created_at, &b.sha256)));
+    if params.order == SortOrder::Desc {
+        items.reverse();
+    }
+
+    let next_cursor = if items.len() > limit {
+        items.truncate(limit);
+        items.last().and_then(|r| Cursor::of(r).encode())
+    } else {
+        None
+    };
+
+    Ok(Json(ListResponse { items, next_cursor }))
+}
+
+/// `DELETE /api/images/{sha256}`: removes the caller's reference to an
+/// image, or every reference for an `admin`-scoped token. The stored object
+/// and its cached variants are deleted with the last reference.
+pub async fn delete_image(
+    State(state): State<AppState>,
+    Extension(auth): Extension<AuthorizedToken>,
+    Path(sha256): Path<String>,
+    headers: HeaderMap,
+) -> Result<StatusCode, AppError> {
+    let started = Instant::now();
+    let request_id = headers
+        .get("x-request-id")
+        .and_then(|v| v.to_str().ok())
+        .unwrap_or("-");
+
+    if !is_sha256_hex(&sha256) {
+        return Err(AppError::NotFound);
+    }
+    let refs = state.index.refs(&sha256).await;
+    if refs.is_empty() {
+        return Err(AppError::NotFound);
+    }
+    let owner = (!auth.is_admin()).then_some(auth.token_id.as_str());
+    if owner.is_some_and(|owner| !refs.iter().any(|r| r.token_id == owner)) {
+        state.metrics.delete_fail.fetch_add(1, Ordering::Relaxed);
+        warn!(request_id, token = %auth.name, sha256 = %sha256, result = "fail", reason = "not_owner", "delete rejected");
+        return Err(AppError::Forbidden);
+    }
+    let removal = state
+        .index
+        .begin_remove(&sha256, owner)
+        .await
+        .ok_or(AppError::NotFound)?;
+    // Shared content is stored once; the object goes with its last reference.
+    let path = removal.records()[0].path.clone();
+    if removal.is_last() {
+        let Some(stored) = StoredPath::from_key(&path) else {
+            state.metrics.delete_fail.fetch_add(1, Ordering::Relaxed);
+            error!(request_id, sha256 = %sha256, path = %path, result = "fail", reason = "bad_index_path", "delete failed");
+            return Err(AppError::Internal);
+        };
+
+        if let Err(err) = state.storage.delete(&stored.key()).await {
+            state.metrics.delete_fail.fetch_add(1, Ordering::Relaxed);
+            error!(request_id, sha256 = %sha256, error = %err, result = "fail", reason = "remove_file", "delete failed");
+            return Err(AppError::Internal);
+        }
+        remove_variants(state.storage.as_ref(), &stored).await;
+    }
+
+    if let Err(err) = state.index.remove(&removal).await {
+        state.metrics.delete_fail.fetch_add(1, Ordering::Relaxed);
+        error!(request_id, sha256 = %sha256, error = %err, result = "fail", reason = "index_write", "delete failed");
+        return Err(AppError::Internal);
+    }
+
+    state.metrics.delete_ok.fetch_add(1, Ordering::Relaxed);
+    info!(
+        request_id,
+        token = %auth.name,
+        references = removal.records().len(),
+        object_deleted = removal.is_last(),
+        sha256 = %sha256,
+        path = %path,
+        elapsed_ms = started.elapsed().as_millis(),
+        result = "ok",
+        "image deleted"
+    );
+    Ok(StatusCode::NO_CONTENT)
+}
+
+/// Body of `POST /api/upload-tickets`; every field is optional.
+#[derive(Debug, Default, Deserialize)]
+pub struct TicketRequest {
+    pub ttl_secs: Option<u64>,
+    /// Per-file limit; the token's and the global limit still apply.
+    pub max_bytes: Option<usize>,
+    #[serde(default)]
+    pub content_types: Vec<String>,
+    /// Relative key prefix uploads are stored under, e.g. `avatars`.
+    pub path_prefix: Option<String>,
+}
+
+#[derive(Serialize)]
+pub struct TicketResponse {
+    pub ticket: String,
+    /// Path to POST the upload to, relative to the imgd origin.
+    pub upload_url: String,
+    pub expires_at: DateTime<Utc>,
+}
+
+/// `POST /api/upload-tickets`: mints a single-use upload ticket for the
+/// calling token, for handing to browsers instead of the token itself.
+pub async fn create_upload_ticket(
+    State(state): State<AppState>,
+    Extension(auth): Extension<AuthorizedToken>,
+    redeemed: Option<Extension<TicketClaims>>,
+    Json(req): Json<TicketRequest>,
+) -> Result<(StatusCode, Json<TicketResponse>), AppError> {
+    let tickets = state.tickets.as_ref().ok_or(AppError::NotFound)?;
+    // A ticket must not be able to mint its own successors.
+    if redeemed.is_some() {
+        return Err(AppError::Forbidden);
+    }
+
+    let ttl_secs = req.ttl_secs.unwrap_or(DEFAULT_TTL_SECS);
+    let known_type = |ct: &String| {
+        state
+            .config
+            .allowed_formats
+            .iter()
+            .any(|f| f.content_type().eq_ignore_ascii_case(ct))
+    };
+    if !(1..=MAX_TTL_SECS).contains(&ttl_secs)
+        || req.max_bytes == Some(0)
+        || !req.content_types.iter().all(known_type)
+        || req
+            .path_prefix
+            .as_deref()
+            .is_some_and(|p| !is_valid_prefix(p))
+    {
+        return Err(AppError::BadRequest);
+    }
+
+    let expires_at = Utc::now() + TimeDelta::seconds(ttl_secs as i64);
+    let claims = TicketClaims {
+        token_id: auth.token_id.clone(),
+        expires_at: expires_at.timestamp(),
+        max_bytes: req.max_bytes,
+        content_types: req
+            .content_types
+            .iter()
+            .map(|ct| ct.to_ascii_lowercase())
+            .collect(),
+        path_prefix: req.path_prefix,
+        nonce: Uuid::new_v4().simple().to_string(),
+    };
+    let ticket = tickets.sign(&claims);
+
+    info!(token = %auth.name, token_id = %auth.token_id, ttl_secs, "upload ticket issued");
+    Ok((
+        StatusCode::CREATED,
+        Json(TicketResponse {
+            upload_url: format!("/upload?ticket={ticket}"),
+            ticket,
+            expires_at: DateTime::from_timestamp(claims.expires_at, 0).unwrap_or(expires_at),
+        }),
+    ))
+}
+
+/// Best-effort cleanup of cached `<sha256>_<w>x<h>_<fit>.webp` siblings.
+async fn remove_variants(storage: &dyn Storage, original: &StoredPath) {
+    let prefix = original.sibling(&format!("{}_", original.sha256));
+    let Ok(keys) = storage.list(&prefix).await else {
+        return;
+    };
+    for key in keys.iter().filter(|key| key.ends_with(".webp")) {
+        let _ = storage.delete(key).await;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use chrono::{TimeZone, Utc};
+
+    use super::Cursor;
+
+    #[test]
+    fn cursor_round_trip() {
+        let cursor = Cursor {
+            created_at: Utc.timestamp_opt(1_714_521_600, 123_456_789).unwrap(),
+            sha256: "ab".repeat(32),
+        };
+        let encoded = cursor.encode().expect("encode");
+        assert_eq!(Cursor::decode(&encoded), Some(cursor));
+        assert_eq!(Cursor::decode("12.nothex"), None);
+        assert_eq!(Cursor::decode("garbage"), None);
+    }
+}
@@ -1,18 +1,47 @@
 use axum::{
     body::Body,
-    extract::{Request, State},
+    extract::{Query, Request, State},
     http::{header, HeaderMap},
     middleware,
     response::{IntoResponse, Response},
 };
+use chrono::Utc;
+use serde::Deserialize;
+use tracing::{error, warn};
 
-use crate::{error::AppError, token::AuthorizedToken, AppState};
+use crate::{
+    error::AppError,
+    ticket::TicketClaims,
+    token::{constant_time_eq, AuthorizedToken, Scope},
+    AppState,
+};
+
+#[derive(Deserialize)]
+struct TicketQuery {
+    ticket: Option<String>,
+}
 
+/// Accepts a token, or an upload ticket from `X-Upload-Ticket` or
+/// `?ticket=`. A ticket is only verified here and only grants the `upload`
+/// scope; its [`TicketClaims`] are added to the request, and
+/// [`redeem_ticket_middleware`] uses it up once the request gets past the
+/// limits.
 pub async fn auth_middleware(
     State(state): State<AppState>,
     mut req: Request<Body>,
     next: middleware::Next,
 ) -> Response {
+    if let Some(raw_ticket) = extract_ticket(&req) {
+        return match verify_ticket(&state, &raw_ticket, req.uri().path()) {
+            Some((authorized, claims)) => {
+                req.extensions_mut().insert::<AuthorizedToken>(authorized);
+                req.extensions_mut().insert::<TicketClaims>(claims);
+                next.run(req).await
+            }
+            None => AppError::Unauthorized.into_response(),
+        };
+    }
+
     if let Some(raw_token) = extract_token(req.headers()) {
         if let Some(authorized) = state.token_store.authorize(&raw_token) {
             req.extensions_mut().insert::<AuthorizedToken>(authorized);
@@ -23,6 +52,103 @@ pub async fn auth_middleware(
     AppError::Unauthorized.into_response()
 }
 
+/// Innermost layer on the upload routes: burns the ticket's nonce only after
+/// the rate and concurrency limits have let the request through, so a `429`
+/// leaves the ticket usable.
+pub async fn redeem_ticket_middleware(
+    State(state): State<AppState>,
+    req: Request<Body>,
+    next: middleware::Next,
+) -> Response {
+    if let (Some(claims), Some(tickets)) = (
+        req.extensions().get::<TicketClaims>(),
+        state.tickets.as_ref(),
+    ) {
+        match tickets.redeem(claims, Utc::now().timestamp()).await {
+            Ok(true) => {}
+            Ok(false) => {
+                warn!(path = %req.uri().path(), reason = "reused", "upload ticket rejected");
+                return AppError::Unauthorized.into_response();
+            }
+            Err(err) => {
+                error!(error = %err, "cannot record redeemed ticket");
+                return AppError::Internal.into_response();
+            }
+        }
+    }
+    next.run(req).await
+}
+
+/// Route layer run after [`auth_middleware`]; rejects tokens lacking `scope`.
+pub async fn require_scope(
+    State(scope): State<Scope>,
+    req: Request<Body>,
+    next: middleware::Next,
+) -> Response {
+    match req.extensions().get::<AuthorizedToken>() {
+        Some(auth) if auth.has_scope(scope) => next.run(req).await,
+        Some(auth) => {
+            warn!(token = %auth.name, token_id = %auth.token_id, scope = %scope, path = %req.uri().path(), "missing scope");
+            AppError::Forbidden.into_response()
+        }
+        None => AppError::Unauthorized.into_response(),
+    }
+}
+
+fn extract_ticket(req: &Request<Body>) -> Option<String> {
+    if let Some(ticket) = req
+        .headers()
+        .get("x-upload-ticket")
+        .and_then(|v| v.to_str().ok())
+        .filter(|v| !v.is_empty())
+    {
+        return Some(ticket.to_owned());
+    }
+
+    Query::<TicketQuery>::try_from_uri(req.uri())
+        .ok()
+        .and_then(|Query(q)| q.ticket)
+        .filter(|v| !v.is_empty())
+}
+
+/// Checks signature, expiry and the minting token. The token's own size
+/// limit still applies on top of the ticket's.
+fn verify_ticket(
+    state: &AppState,
+    raw: &str,
+    path: &str,
+) -> Option<(AuthorizedToken, TicketClaims)> {
+    let tickets = state.tickets.as_ref()?;
+    let now = Utc::now().timestamp();
+    let reject = |reason: &str| warn!(path, reason, "upload ticket rejected");
+
+    let Some(claims) = tickets.verify(raw, now) else {
+        reject("invalid_or_expired");
+        return None;
+    };
+    let Some(token) = state
+        .token_store
+        .authorize_id(&claims.token_id)
+        .filter(|t| t.has_scope(Scope::Upload))
+    else {
+        reject("token");
+        return None;
+    };
+
+    let max_upload_bytes = match (token.max_upload_bytes, claims.max_bytes) {
+        (Some(token_max), Some(ticket_max)) => Some(token_max.min(ticket_max)),
+        (token_max, ticket_max) => token_max.or(ticket_max),
+    };
+    Some((
+        AuthorizedToken {
+            scopes: vec![Scope::Upload],
+            max_upload_bytes,
+            ..token
+        },
+        claims,
+    ))
+}
+
 pub fn extract_token(headers: &HeaderMap) -> Option<String> {
     if let Some(token) = headers
         .get("x-upload-token")
@@ -49,7 +175,7 @@ pub fn extract_token(headers: &HeaderMap) -> Option<String> {
 
 pub fn is_authorized(headers: &HeaderMap, expected_token: &str) -> bool {
     if let Some(raw) = extract_token(headers) {
-        return raw == expected_token;
+        return constant_time_eq(raw.as_bytes(), expected_token.as_bytes());
     }
     false
 }
@@ -0,0 +1,371 @@
+use std::{
+    convert::Infallible,
+    fmt,
+    net::{IpAddr, SocketAddr},
+    str::FromStr,
+};
+
+use axum::{
+    extract::{connect_info::ConnectInfo, FromRequestParts},
+    http::{request::Parts, HeaderMap},
+};
+
+use crate::AppState;
+
+/// An address block such as `10.0.0.0/8` or `::1/128`. A bare address is a
+/// single-host block.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct IpNet {
+    addr: IpAddr,
+    prefix: u8,
+}
+
+impl IpNet {
+    pub fn contains(&self, ip: IpAddr) -> bool {
+        match (self.addr, ip.to_canonical()) {
+            (IpAddr::V4(net), IpAddr::V4(ip)) => {
+                let mask = u32::MAX
+                    .checked_shl(32 - u32::from(self.prefix))
+                    .unwrap_or(0);
+                u32::from(net) & mask == u32::from(ip) & mask
+            }
+            (IpAddr::V6(net), IpAddr::V6(ip)) => {
+                let mask = u128::MAX
+                    .checked_shl(128 - u32::from(self.prefix))
+                    .unwrap_or(0);
+                u128::from(net) & mask == u128::from(ip) & mask
+            }
+            _ => false,
+        }
+    }
+}
+
+impl FromStr for IpNet {
+    type Err = String;
+
+    fn from_str(raw: &str) -> Result<Self, Self::Err> {
+        let raw = raw.trim();
+        let (addr, prefix) = match raw.split_once('/') {
+            Some((addr, prefix)) => (addr, Some(prefix)),
+            None => (raw, None),
+        };
+        let addr: IpAddr = addr
+            .parse()
+            .map_err(|_| format!("invalid address in {raw}"))?;
+        let max = if addr.is_ipv4() { 32 } else { 128 };
+        let prefix = match prefix {
+            Some(p) => p
+                .parse()
+                .ok()
+                .filter(|&p| p <= max)
+                .ok_or_else(|| format!("invalid prefix length in {raw}"))?,
+            None => max,
+        };
+        Ok(Self { addr, prefix })
+    }
+}
+
+impl fmt::Display for IpNet {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}/{}", self.addr, self.prefix)
+    }
+}
+
+/// Parses a comma-separated `TRUSTED_PROXIES` list; `none` yields an empty list.
+pub fn parse_net_list(raw: &str) -> Result<Vec<IpNet>, String> {
+    if raw.trim().eq_ignore_ascii_case("none") {
+        return Ok(Vec::new());
+    }
+    raw.split(',')
+        .map(str::trim)
+        .filter(|p| !p.is_empty())
+        .map(str::parse)
+        .collect()
+}
+
+/// The one header the trusted proxy writes, set by `FORWARDED_HEADER`. The
+/// others are ignored: a proxy that only appends to one of them passes the
+/// rest through from the client untouched.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum ForwardedHeader {
+    #[default]
+    XForwardedFor,
+    /// RFC 7239 `Forwarded`.
+    Forwarded,
+    XRealIp,
+}
+
+impl FromStr for ForwardedHeader {
+    type Err = String;
+
+    fn from_str(raw: &str) -> Result<Self, Self::Err> {
+        match raw.trim().to_ascii_lowercase().as_str() {
+            "x-forwarded-for" => Ok(ForwardedHeader::XForwardedFor),
+            "forwarded" => Ok(ForwardedHeader::Forwarded),
+            "x-real-ip" => Ok(ForwardedHeader::XRealIp),
+            _ => Err(format!(
+                "expected x-forwarded-for, forwarded or x-real-ip, got {raw:?}"
+            )),
+        }
+    }
+}
+
+/// The client address used for rate limiting, logs and the upload index.
+///
+/// Forwarding headers are only honoured when the connecting peer is a trusted
+/// proxy, and only the configured [`ForwardedHeader`]. A hop list from
+/// `Forwarded` (RFC 7239) or `X-Forwarded-For` is walked right to left,
+/// stopping at the first address that is not a trusted proxy itself.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ClientIp(pub IpAddr);
+
+impl ClientIp {
+    pub fn resolve(
+        peer: IpAddr,
+        headers: &HeaderMap,
+        trusted: &[IpNet],
+        header: ForwardedHeader,
+    ) -> IpAddr {
+        let is_trusted = |ip: IpAddr| trusted.iter().any(|net| net.contains(ip));
+        if !is_trusted(peer) {
+            return peer;
+        }
+
+        let hops = match header {
+            ForwardedHeader::Forwarded => forwarded_hops(headers),
+            ForwardedHeader::XForwardedFor => x_forwarded_for_hops(headers),
+            ForwardedHeader::XRealIp => {
+                return header_values(headers, "x-real-ip")
+                    .next()
+                    .and_then(parse_node)
+                    .unwrap_or(peer);
+            }
+        };
+        let Some(hops) = hops else {
+            return peer;
+        };
+
+        let mut client = peer;
+        for hop in hops.iter().rev() {
+            if !is_trusted(client) {
+                break;
+            }
+            // An obfuscated or malformed hop ends the chain at the last
+            // proxy we trust.
+            match hop {
+                Some(ip) => client = *ip,
+                None => break,
+            }
+        }
+        client
+    }
+}
+
+impl FromRequestParts<AppState> for ClientIp {
+    type Rejection = Infallible;
+
+    async fn from_request_parts(
+        parts: &mut Parts,
+        state: &AppState,
+    ) -> Result<Self, Self::Rejection> {
+        let peer = parts
+            .extensions
+            .get::<ConnectInfo<SocketAddr>>()
+            .map_or(IpAddr::from([127, 0, 0, 1]), |ConnectInfo(addr)| addr.ip());
+        Ok(ClientIp(Self::resolve(
+            peer,
+            &parts.headers,
+            &state.config.trusted_proxies,
+            state.config.forwarded_header,
+        )))
+    }
+}
+
+fn header_values<'a>(headers: &'a HeaderMap, name: &str) -> impl Iterator<Item = &'a str> {
+    headers
+        .get_all(name)
+        .into_iter()
+        .filter_map(|v| v.to_str().ok())
+}
+
+/// `for=` values of every `Forwarded` element, in order. An element without
+/// one still counts as a hop, as `None`, so the walk stops there instead of
+/// pairing later hops with the wrong proxies.
+fn forwarded_hops(headers: &HeaderMap) -> Option<Vec<Option<IpAddr>>> {
+    let hops: Vec<_> = header_values(headers, "forwarded")
+        .flat_map(|v| v.split(','))
+        .filter(|element| !element.trim().is_empty())
+        .map(|element| {
+            element.split(';').find_map(|pair| {
+                let (key, value) = pair.split_once('=')?;
+                key.trim()
+                    .eq_ignore_ascii_case("for")
+                    .then(|| parse_node(value.trim().trim_matches('"')))
+            })?
+        })
+        .collect();
+    (!hops.is_empty()).then_some(hops)
+}
+
+fn x_forwarded_for_hops(headers: &HeaderMap) -> Option<Vec<Option<IpAddr>>> {
+    let hops: Vec<_> = header_values(headers, "x-forwarded-for")
+        .flat_map(|v| v.split(','))
+        .map(str::trim)
+        .filter(|v| !v.is_empty())
+        .map(parse_node)
+        .collect();
+    (!hops.is_empty()).then_some(hops)
+}
+
+/// Parses `1.2.3.4`, `1.2.3.4:80`, `::1` or `[::1]:80`.
+fn parse_node(raw: &str) -> Option<IpAddr> {
+    let raw = raw.trim();
+    if let Some(rest) = raw.strip_prefix('[') {
+        return rest.split_once(']')?.0.parse().ok();
+    }
+    raw.parse().ok().or_else(|| {
+        let (host, port) = raw.rsplit_once(':')?;
+        port.parse::<u16>().ok()?;
+        host.parse::<std::net::Ipv4Addr>().ok().map(IpAddr::V4)
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use axum::http::{HeaderMap, HeaderValue};
+
+    use super::{parse_net_list, ClientIp, ForwardedHeader, IpNet};
+
+    fn headers(pairs: &[(&'static str, &'static str)]) -> HeaderMap {
+        let mut map = HeaderMap::new();
+        for (name, value) in pairs {
+            map.append(*name, HeaderValue::from_static(value));
+        }
+        map
+    }
+
+    fn resolve(peer: &str, pairs: &[(&'static str, &'static str)], trusted: &str) -> String {
+        resolve_from(ForwardedHeader::XForwardedFor, peer, pairs, trusted)
+    }
+
+    fn resolve_from(
+        header: ForwardedHeader,
+        peer: &str,
+        pairs: &[(&'static str, &'static str)],
+        trusted: &str,
+    ) -> String {
+        let trusted = parse_net_list(trusted).unwrap();
+        ClientIp::resolve(peer.parse().unwrap(), &headers(pairs), &trusted, header).to_string()
+    }
+
+    #[test]
+    fn cidr_matching() {
+        let net: IpNet = "10.1.0.0/16".parse().unwrap();
+        assert!(net.contains("10.1.200.3".parse().unwrap()));
+        assert!(!net.contains("10.2.0.1".parse().unwrap()));
+        assert!(net.contains("::ffff:10.1.0.9".parse().unwrap()));
+        let net: IpNet = "2001:db8::/32".parse().unwrap();
+        assert!(net.contains("2001:db8:1::7".parse().unwrap()));
+        assert!(!net.contains("10.1.0.1".parse().unwrap()));
+        assert!("0.0.0.0/0"
+            .parse::<IpNet>()
+            .unwrap()
+            .contains("8.8.8.8".parse().unwrap()));
+        assert!("10.0.0.0/33".parse::<IpNet>().is_err());
+        assert!("example.com".parse::<IpNet>().is_err());
+        assert_eq!(parse_net_list("none"), Ok(Vec::new()));
+    }
+
+    #[test]
+    fn untrusted_peer_headers_are_ignored() {
+        let spoofed = [("x-forwarded-for", "1.1.1.1"), ("x-real-ip", "2.2.2.2")];
+        assert_eq!(resolve("203.0.113.9", &spoofed, "127.0.0.1"), "203.0.113.9");
+        assert_eq!(resolve("127.0.0.1", &spoofed, "none"), "127.0.0.1");
+    }
+
+    #[test]
+    fn x_forwarded_for_walks_right_to_left() {
+        let trusted = "127.0.0.1, 10.0.0.0/8";
+        // The client-supplied leftmost entry is never reached.
+        let xff = [("x-forwarded-for", "6.6.6.6, 198.51.100.4, 10.0.0.2")];
+        assert_eq!(resolve("127.0.0.1", &xff, trusted), "198.51.100.4");
+
+        // Multiple header lines form one list.
+        let split = [
+            ("x-forwarded-for", "6.6.6.6"),
+            ("x-forwarded-for", "198.51.100.4"),
+        ];
+        assert_eq!(resolve("127.0.0.1", &split, trusted), "198.51.100.4");
+
+        let all_trusted = [("x-forwarded-for", "10.0.0.5, 10.0.0.2")];
+        assert_eq!(resolve("127.0.0.1", &all_trusted, trusted), "10.0.0.5");
+
+        let garbage = [("x-forwarded-for", "6.6.6.6, not-an-ip")];
+        assert_eq!(resolve("127.0.0.1", &garbage, trusted), "127.0.0.1");
+    }
+
+    #[test]
+    fn only_the_configured_header_is_read() {
+        let trusted = "127.0.0.1";
+        // nginx appends to X-Forwarded-For but passes a client's own
+        // Forwarded and X-Real-IP through.
+        let injected = [
+            ("forwarded", "for=9.9.9.9"),
+            ("x-real-ip", "8.8.8.8"),
+            ("x-forwarded-for", "198.51.100.4"),
+        ];
+        assert_eq!(resolve("127.0.0.1", &injected, trusted), "198.51.100.4");
+        assert_eq!(
+            resolve("127.0.0.1", &[("forwarded", "for=9.9.9.9")], trusted),
+            "127.0.0.1"
+        );
+        assert_eq!(
+            resolve_from(ForwardedHeader::Forwarded, "127.0.0.1", &injected, trusted),
+            "9.9.9.9"
+        );
+        assert_eq!(
+            resolve_from(ForwardedHeader::XRealIp, "127.0.0.1", &injected, trusted),
+            "8.8.8.8"
+        );
+        assert!("via".parse::<ForwardedHeader>().is_err());
+    }
+
+    #[test]
+    fn forwarded_and_x_real_ip() {
+        let trusted = "127.0.0.1";
+        let resolve = |pairs: &[(&'static str, &'static str)]| {
+            resolve_from(ForwardedHeader::Forwarded, "127.0.0.1", pairs, trusted)
+        };
+        let forwarded = [(
+            "forwarded",
+            r#"for=6.6.6.6, for="[2001:db8::7]:4711";proto=https"#,
+        )];
+        assert_eq!(resolve(&forwarded), "2001:db8::7");
+
+        let with_port = [("forwarded", "for=192.0.2.60:8080;by=127.0.0.1")];
+        assert_eq!(resolve(&with_port), "192.0.2.60");
+
+        let obfuscated = [("forwarded", "for=_hidden")];
+        assert_eq!(resolve(&obfuscated), "127.0.0.1");
+
+        // An element without `for=` is a hop too: the spoofed entry to its
+        // left is never reached.
+        let trusted = "127.0.0.1, 10.0.0.0/8";
+        let resolve = |pairs: &[(&'static str, &'static str)]| {
+            resolve_from(ForwardedHeader::Forwarded, "127.0.0.1", pairs, trusted)
+        };
+        let mixed = [("forwarded", "for=1.2.3.4, by=10.0.0.1")];
+        assert_eq!(resolve(&mixed), "127.0.0.1");
+        let mixed = [(
+            "forwarded",
+            "for=1.2.3.4, by=10.0.0.1;proto=https, for=10.0.0.5",
+        )];
+        assert_eq!(resolve(&mixed), "10.0.0.5");
+
+        let real_ip = [("x-real-ip", "192.0.2.9")];
+        assert_eq!(
+            resolve_from(ForwardedHeader::XRealIp, "127.0.0.1", &real_ip, trusted),
+            "192.0.2.9"
+        );
+    }
+}
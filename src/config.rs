@@ -1,4 +1,20 @@
-use std::{env, fs, net::SocketAddr, path::PathBuf};
+use std::{
+    env, fmt, fs,
+    net::{IpAddr, SocketAddr},
+    path::{Path, PathBuf},
+    str::FromStr,
+    time::Duration,
+};
+
+use crate::{
+    client_ip::{parse_net_list, ForwardedHeader, IpNet},
+    format::{parse_format_list, ImageFormat},
+    rate_limit::{Limit, MAX_LIMIT, MAX_WINDOW},
+    s3::{parse_endpoint, S3Config},
+    storage::StorageConfig,
+    ticket::MIN_SECRET_LEN,
+    transcode::{parse_size_list, WebpEncoding},
+};
 
 #[derive(Clone)]
 pub struct AppConfig {
@@ -6,41 +22,293 @@ pub struct AppConfig {
     pub upload_token: Option<String>,
     pub tokens_file: Option<PathBuf>,
     pub public_base_url: String,
+    /// Local state: the index, temporary files and, with [`StorageConfig::Fs`],
+    /// the images themselves.
     pub data_dir: PathBuf,
+    pub storage: StorageConfig,
     pub max_upload_bytes: usize,
     pub max_concurrent_uploads: usize,
+    /// `file` fields accepted in one multipart upload.
+    pub max_files_per_upload: usize,
+    /// Idle time after which an unfinished resumable upload is discarded.
+    pub upload_session_ttl: Duration,
+    /// Unfinished resumable uploads one token may hold at a time.
+    pub max_upload_sessions_per_token: usize,
+    /// Requests allowed per IP per minute, whatever `rate_limit_window` is.
     pub rate_limit_per_minute: usize,
+    /// Only sets the burst: a window's worth of each per-minute limit may
+    /// be sent back to back, for IPs and for tokens with their own limit.
+    /// Refill stays per minute. Derived from `rate_limit_burst` when only
+    /// that is set; setting both requires them to agree.
+    pub rate_limit_window: Duration,
+    pub allowed_formats: Vec<ImageFormat>,
+    pub webp_conversion: Option<WebpEncoding>,
+    pub transcode_workers: usize,
+    pub strip_metadata: bool,
+    pub strip_icc: bool,
+    pub serve_images: bool,
+    pub webp_quality: f32,
+    pub variant_sizes: Vec<(u32, u32)>,
+    pub tokens_reload_interval: Duration,
+    /// Require a token with the `metrics` scope for `/metrics`.
+    pub metrics_auth: bool,
+    /// Add a `token` label to `imgd_uploads_total`, one series set per token
+    /// name ever seen until restart.
+    pub metrics_token_label: bool,
+    /// HMAC key for upload tickets; tickets are disabled when unset.
+    pub upload_ticket_secret: Option<String>,
+    /// Peers whose forwarding headers are believed, see [`crate::client_ip::ClientIp`].
+    pub trusted_proxies: Vec<IpNet>,
+    /// The forwarding header trusted proxies write.
+    pub forwarded_header: ForwardedHeader,
+    /// Whole-request limit for `POST /upload/url` fetches.
+    pub fetch_timeout: Duration,
+    pub fetch_max_redirects: usize,
+    /// Internal networks `POST /upload/url` may fetch from anyway.
+    pub fetch_allowed_networks: Vec<IpNet>,
 }
 
+/// Every setting, by its key in the config file. The environment variable
+/// is the upper-cased key and takes precedence over the file.
+const KEYS: &[&str] = &[
+    "bind_host",
+    "port",
+    "upload_token",
+    "tokens_file",
+    "public_base_url",
+    "data_dir",
+    "storage",
+    "s3_endpoint",
+    "s3_bucket",
+    "s3_region",
+    "s3_access_key_id",
+    "s3_secret_access_key",
+    "s3_prefix",
+    "s3_connect_timeout_secs",
+    "s3_timeout_secs",
+    "max_upload_bytes",
+    "max_concurrent_uploads",
+    "max_files_per_upload",
+    "upload_session_ttl_secs",
+    "max_upload_sessions_per_token",
+    "rate_limit_per_minute",
+    "rate_limit_window_secs",
+    "rate_limit_burst",
+    "allowed_formats",
+    "convert_to_webp",
+    "webp_quality",
+    "transcode_workers",
+    "strip_metadata",
+    "strip_icc",
+    "serve_images",
+    "variant_sizes",
+    "tokens_reload_secs",
+    "metrics_auth",
+    "metrics_token_label",
+    "upload_ticket_secret",
+    "trusted_proxies",
+    "forwarded_header",
+    "fetch_timeout_secs",
+    "fetch_max_redirects",
+    "fetch_allowed_networks",
+];
+
 impl AppConfig {
     pub fn from_env() -> Result<Self, Box<dyn std::error::Error>> {
-        let port = env::var("PORT").unwrap_or_else(|_| "3000".to_owned());
-        let bind_addr: SocketAddr = format!("0.0.0.0:{port}").parse()?;
+        Self::load(None)
+    }
+
+    /// Reads the TOML file at `path`, if any, with environment variables
+    /// overriding individual keys. Errors name the offending key.
+    pub fn load(path: Option<&Path>) -> Result<Self, Box<dyn std::error::Error>> {
+        let file = match path {
+            Some(path) => {
+                let raw =
+                    fs::read_to_string(path).map_err(|e| format!("{}: {e}", path.display()))?;
+                let table: toml::Table =
+                    toml::from_str(&raw).map_err(|e| format!("{}: {e}", path.display()))?;
+                Some((path, table))
+            }
+            None => None,
+        };
+        let settings = Settings {
+            file,
+            env: |key: &str| env::var(key).ok(),
+        };
+        Ok(Self::from_settings(&settings)?)
+    }
+
+    fn from_settings<E>(s: &Settings<'_, E>) -> Result<Self, String>
+    where
+        E: Fn(&str) -> Option<String>,
+    {
+        s.check_unknown_keys()?;
 
-        let upload_token = env::var("UPLOAD_TOKEN").ok();
-        let tokens_file = env::var("TOKENS_FILE").ok().map(PathBuf::from);
-        let public_base_url = env::var("PUBLIC_BASE_URL")?;
-        let data_dir = env::var("DATA_DIR").unwrap_or_else(|_| "/data/images".to_owned());
+        let host: IpAddr = s.parse("bind_host")?.unwrap_or(IpAddr::from([0, 0, 0, 0]));
+        let port: u16 = s.parse("port")?.unwrap_or(3000);
 
+        let upload_token = s.string("upload_token")?;
+        let tokens_file = s.string("tokens_file")?.map(PathBuf::from);
         if upload_token.is_none() && tokens_file.is_none() {
-            return Err("UPLOAD_TOKEN or TOKENS_FILE must be set".into());
+            return Err("UPLOAD_TOKEN or TOKENS_FILE must be set".to_owned());
+        }
+        let public_base_url = s
+            .string("public_base_url")?
+            .ok_or("PUBLIC_BASE_URL must be set")?;
+
+        let allowed_formats = s
+            .with("allowed_formats", parse_format_list)?
+            .unwrap_or_else(|| ImageFormat::ALL.to_vec());
+
+        let webp_quality: f32 = s.parse("webp_quality")?.unwrap_or(80.0);
+        if !(0.0..=100.0).contains(&webp_quality) {
+            return Err(s.invalid(
+                "webp_quality",
+                format!("must be within 0..=100, got {webp_quality}"),
+            ));
+        }
+        let webp_conversion = s
+            .with("convert_to_webp", |raw| {
+                WebpEncoding::from_mode(raw, webp_quality)
+            })?
+            .flatten();
+
+        let upload_ticket_secret = s.string("upload_ticket_secret")?;
+        if upload_ticket_secret
+            .as_ref()
+            .is_some_and(|secret| secret.len() < MIN_SECRET_LEN)
+        {
+            return Err(s.invalid(
+                "upload_ticket_secret",
+                format!("must be at least {MIN_SECRET_LEN} bytes"),
+            ));
         }
 
+        let rate_limit_per_minute = s.at_most("rate_limit_per_minute", MAX_LIMIT)?.unwrap_or(60);
+        // A burst is a window's worth of the per-minute rate, so either key
+        // sets the window every limit shares; setting both must agree.
+        let window_secs = s.at_most("rate_limit_window_secs", MAX_WINDOW.as_secs())?;
+        let burst = s.at_most("rate_limit_burst", MAX_LIMIT)?;
+        let rate_limit_window = match (window_secs, burst) {
+            (Some(secs), Some(burst)) => {
+                let window = Duration::from_secs(secs);
+                let implied = Limit::per_window(rate_limit_per_minute, window).burst;
+                if burst != implied {
+                    return Err(s.invalid(
+                        "rate_limit_burst",
+                        format!("is {implied} for a {secs}s window at {rate_limit_per_minute} requests per minute; set only one of the two"),
+                    ));
+                }
+                window
+            }
+            (Some(secs), None) => Duration::from_secs(secs),
+            (None, Some(burst)) => {
+                let window = Limit {
+                    per_minute: rate_limit_per_minute,
+                    burst,
+                }
+                .window();
+                if window > MAX_WINDOW {
+                    return Err(s.invalid(
+                        "rate_limit_burst",
+                        format!(
+                            "must be at most {} at {rate_limit_per_minute} requests per minute",
+                            Limit::per_window(rate_limit_per_minute, MAX_WINDOW).burst
+                        ),
+                    ));
+                }
+                window
+            }
+            (None, None) => Duration::from_secs(60),
+        };
+
+        let storage = match s.string("storage")?.map(|v| v.trim().to_ascii_lowercase()) {
+            None => StorageConfig::Fs,
+            Some(backend) if backend == "fs" => StorageConfig::Fs,
+            Some(backend) if backend == "s3" => {
+                let required = |key: &str| {
+                    s.string(key)?.ok_or_else(|| {
+                        format!("{} must be set when STORAGE=s3", key.to_ascii_uppercase())
+                    })
+                };
+                StorageConfig::S3(Box::new(S3Config {
+                    endpoint: s
+                        .with("s3_endpoint", parse_endpoint)?
+                        .ok_or("S3_ENDPOINT must be set when STORAGE=s3")?,
+                    bucket: required("s3_bucket")?,
+                    region: s
+                        .string("s3_region")?
+                        .unwrap_or_else(|| "us-east-1".to_owned()),
+                    access_key_id: required("s3_access_key_id")?,
+                    secret_access_key: required("s3_secret_access_key")?,
+                    prefix: s
+                        .string("s3_prefix")?
+                        .map(|p| p.trim_matches('/').to_owned())
+                        .filter(|p| !p.is_empty())
+                        .map(|p| format!("{p}/"))
+                        .unwrap_or_default(),
+                    connect_timeout: Duration::from_secs(
+                        s.positive("s3_connect_timeout_secs")?.unwrap_or(5),
+                    ),
+                    timeout: Duration::from_secs(s.positive("s3_timeout_secs")?.unwrap_or(60)),
+                }))
+            }
+            Some(other) => {
+                return Err(s.invalid("storage", format!("expected fs or s3, got {other:?}")))
+            }
+        };
+
         Ok(Self {
-            bind_addr,
+            bind_addr: SocketAddr::new(host, port),
             upload_token,
             tokens_file,
             public_base_url,
-            data_dir: PathBuf::from(data_dir),
-            max_upload_bytes: 5 * 1024 * 1024,
-            max_concurrent_uploads: env::var("MAX_CONCURRENT_UPLOADS")
-                .ok()
-                .and_then(|v| v.parse().ok())
-                .unwrap_or(16),
-            rate_limit_per_minute: env::var("RATE_LIMIT_PER_MINUTE")
-                .ok()
-                .and_then(|v| v.parse().ok())
-                .unwrap_or(60),
+            data_dir: s
+                .string("data_dir")?
+                .map_or_else(|| PathBuf::from("/data/images"), PathBuf::from),
+            storage,
+            max_upload_bytes: s.positive("max_upload_bytes")?.unwrap_or(5 * 1024 * 1024),
+            max_concurrent_uploads: s.positive("max_concurrent_uploads")?.unwrap_or(16),
+            max_files_per_upload: s.positive("max_files_per_upload")?.unwrap_or(10),
+            upload_session_ttl: Duration::from_secs(
+                s.positive("upload_session_ttl_secs")?
+                    .unwrap_or(24 * 60 * 60),
+            ),
+            max_upload_sessions_per_token: s
+                .positive("max_upload_sessions_per_token")?
+                .unwrap_or(10),
+            rate_limit_per_minute,
+            rate_limit_window,
+            allowed_formats,
+            webp_conversion,
+            transcode_workers: s.positive("transcode_workers")?.unwrap_or(2),
+            strip_metadata: s.flag("strip_metadata")?,
+            strip_icc: s.flag("strip_icc")?,
+            serve_images: s.flag("serve_images")?,
+            webp_quality,
+            variant_sizes: s
+                .with("variant_sizes", parse_size_list)?
+                .unwrap_or_default(),
+            tokens_reload_interval: Duration::from_secs(
+                s.positive("tokens_reload_secs")?.unwrap_or(5),
+            ),
+            metrics_auth: s.flag("metrics_auth")?,
+            metrics_token_label: s.flag("metrics_token_label")?,
+            upload_ticket_secret,
+            trusted_proxies: s
+                .with("trusted_proxies", parse_net_list)?
+                .unwrap_or_else(|| {
+                    vec![
+                        IpNet::from_str("127.0.0.1").expect("loopback"),
+                        IpNet::from_str("::1").expect("loopback"),
+                    ]
+                }),
+            forwarded_header: s.parse("forwarded_header")?.unwrap_or_default(),
+            fetch_timeout: Duration::from_secs(s.positive("fetch_timeout_secs")?.unwrap_or(10)),
+            fetch_max_redirects: s.parse("fetch_max_redirects")?.unwrap_or(3),
+            fetch_allowed_networks: s
+                .with("fetch_allowed_networks", parse_net_list)?
+                .unwrap_or_default(),
         })
     }
 
@@ -55,3 +323,269 @@ impl AppConfig {
         Ok(())
     }
 }
+
+/// Raw setting values from the environment and an optional config file.
+struct Settings<'a, E> {
+    file: Option<(&'a Path, toml::Table)>,
+    env: E,
+}
+
+impl<E> Settings<'_, E>
+where
+    E: Fn(&str) -> Option<String>,
+{
+    fn check_unknown_keys(&self) -> Result<(), String> {
+        if let Some((path, table)) = &self.file {
+            if let Some(key) = table.keys().find(|k| !KEYS.contains(&k.as_str())) {
+                return Err(format!("{}: unknown key {key}", path.display()));
+            }
+        }
+        Ok(())
+    }
+
+    /// Where `key` was read from, for error messages.
+    fn source(&self, key: &str) -> String {
+        let env_key = key.to_ascii_uppercase();
+        match &self.file {
+            Some((path, table)) if (self.env)(&env_key).is_none() && table.contains_key(key) => {
+                format!("{}: {key}", path.display())
+            }
+            _ => env_key,
+        }
+    }
+
+    fn invalid(&self, key: &str, reason: impl std::fmt::Display) -> String {
+        format!("{}: {reason}", self.source(key))
+    }
+
+    /// The value of `key` as text. Empty environment variables count as
+    /// unset; file arrays are joined with commas to match the env syntax.
+    fn string(&self, key: &str) -> Result<Option<String>, String> {
+        if let Some(raw) = (self.env)(&key.to_ascii_uppercase()).filter(|v| !v.is_empty()) {
+            return Ok(Some(raw));
+        }
+        let Some(value) = self.file.as_ref().and_then(|(_, t)| t.get(key)) else {
+            return Ok(None);
+        };
+        let scalar = |value: &toml::Value| match value {
+            toml::Value::String(s) => Ok(s.clone()),
+            toml::Value::Integer(i) => Ok(i.to_string()),
+            toml::Value::Float(f) => Ok(f.to_string()),
+            toml::Value::Boolean(b) => Ok(b.to_string()),
+            other => Err(self.invalid(key, format!("unsupported value {other}"))),
+        };
+        match value {
+            toml::Value::Array(items) => Ok(Some(
+                items
+                    .iter()
+                    .map(scalar)
+                    .collect::<Result<Vec<_>, _>>()?
+                    .join(","),
+            )),
+            value => scalar(value).map(Some),
+        }
+    }
+
+    fn with<T>(
+        &self,
+        key: &str,
+        parse: impl FnOnce(&str) -> Result<T, String>,
+    ) -> Result<Option<T>, String> {
+        match self.string(key)? {
+            Some(raw) => parse(&raw).map(Some).map_err(|e| self.invalid(key, e)),
+            None => Ok(None),
+        }
+    }
+
+    fn parse<T: FromStr>(&self, key: &str) -> Result<Option<T>, String> {
+        self.with(key, |raw| {
+            raw.trim()
+                .parse()
+                .map_err(|_| format!("invalid value {raw:?}"))
+        })
+    }
+
+    fn positive<T: FromStr + Default + PartialEq>(&self, key: &str) -> Result<Option<T>, String> {
+        match self.parse::<T>(key)? {
+            Some(value) if value == T::default() => {
+                Err(self.invalid(key, "must be greater than 0"))
+            }
+            value => Ok(value),
+        }
+    }
+
+    /// A positive value no larger than `max`.
+    fn at_most<T>(&self, key: &str, max: T) -> Result<Option<T>, String>
+    where
+        T: FromStr + Default + PartialOrd + fmt::Display,
+    {
+        match self.positive::<T>(key)? {
+            Some(value) if value > max => Err(self.invalid(key, format!("must be at most {max}"))),
+            value => Ok(value),
+        }
+    }
+
+    fn flag(&self, key: &str) -> Result<bool, String> {
+        Ok(self
+            .with(key, |raw| match raw.trim().to_ascii_lowercase().as_str() {
+                "1" | "true" | "yes" | "on" => Ok(true),
+                "0" | "false" | "no" | "off" => Ok(false),
+                _ => Err(format!("invalid boolean {raw:?}")),
+            })?
+            .unwrap_or(false))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{collections::HashMap, path::Path, time::Duration};
+
+    use super::{AppConfig, Settings};
+    use crate::format::ImageFormat;
+    use crate::rate_limit::Limit;
+    use crate::storage::StorageConfig;
+
+    fn load(file: &str, env: &[(&str, &str)]) -> Result<AppConfig, String> {
+        let env: HashMap<String, String> = env
+            .iter()
+            .map(|(k, v)| (k.to_string(), v.to_string()))
+            .collect();
+        let settings = Settings {
+            file: Some((Path::new("imgd.toml"), toml::from_str(file).expect("toml"))),
+            env: |key: &str| env.get(key).cloned(),
+        };
+        AppConfig::from_settings(&settings)
+    }
+
+    const BASE: &str = r#"
+        public_base_url = "https://img.example.com/images"
+        upload_token = "secret"
+    "#;
+
+    #[test]
+    fn file_values_with_env_overrides() {
+        let file = format!(
+            r#"{BASE}
+            bind_host = "127.0.0.1"
+            port = 8080
+            max_upload_bytes = 1048576
+            rate_limit_window_secs = 10
+            allowed_formats = ["webp", "png"]
+            strip_metadata = true
+            "#
+        );
+        let config = load(&file, &[("MAX_UPLOAD_BYTES", "2048"), ("BIND_HOST", "::1")]).unwrap();
+        assert_eq!(config.bind_addr, "[::1]:8080".parse().unwrap());
+        assert_eq!(config.max_upload_bytes, 2048);
+        assert_eq!(config.rate_limit_window, Duration::from_secs(10));
+        assert_eq!(config.rate_limit_per_minute, 60);
+        let burst = Limit::per_window(config.rate_limit_per_minute, config.rate_limit_window).burst;
+        assert_eq!(burst, 10, "a 10 s window's worth");
+        assert_eq!(
+            config.allowed_formats,
+            vec![ImageFormat::Webp, ImageFormat::Png]
+        );
+        assert!(config.strip_metadata);
+        assert!(!config.serve_images);
+
+        let config = load(BASE, &[]).unwrap();
+        assert_eq!(config.bind_addr, "0.0.0.0:3000".parse().unwrap());
+        assert_eq!(config.trusted_proxies.len(), 2);
+        let config = load(&format!("{BASE}\ntrusted_proxies = []"), &[]).unwrap();
+        assert!(config.trusted_proxies.is_empty());
+        assert_eq!(config.max_upload_bytes, 5 * 1024 * 1024);
+
+        let file = format!(
+            r#"{BASE}
+            storage = "s3"
+            s3_endpoint = "http://127.0.0.1:9000"
+            s3_bucket = "images"
+            s3_access_key_id = "minio"
+            s3_prefix = "/imgd"
+            "#
+        );
+        let config = load(&file, &[("S3_SECRET_ACCESS_KEY", "minio-secret")]).unwrap();
+        let StorageConfig::S3(s3) = config.storage else {
+            panic!("expected s3 storage");
+        };
+        assert_eq!(s3.region, "us-east-1");
+        assert_eq!(s3.prefix, "imgd/");
+        assert_eq!(s3.secret_access_key, "minio-secret");
+        assert_eq!(s3.connect_timeout, Duration::from_secs(5));
+        assert_eq!(s3.timeout, Duration::from_secs(60));
+    }
+
+    #[test]
+    fn errors_name_the_invalid_key() {
+        let err = load(&format!("{BASE}\nmax_upload_bytes = \"lots\""), &[])
+            .err()
+            .expect("invalid");
+        assert!(err.starts_with("imgd.toml: max_upload_bytes:"), "{err}");
+
+        let err = load(BASE, &[("PORT", "http")]).err().expect("invalid");
+        assert!(err.starts_with("PORT:"), "{err}");
+
+        let err = load(BASE, &[("MAX_CONCURRENT_UPLOADS", "0")])
+            .err()
+            .expect("invalid");
+        assert_eq!(err, "MAX_CONCURRENT_UPLOADS: must be greater than 0");
+
+        let err = load(BASE, &[("RATE_LIMIT_BURST", "4294967296")])
+            .err()
+            .expect("invalid");
+        assert_eq!(err, "RATE_LIMIT_BURST: must be at most 1000000");
+
+        // A burst alone sets the window shared with per-token limits.
+        let config = load(BASE, &[("RATE_LIMIT_BURST", "120")]).unwrap();
+        assert_eq!(config.rate_limit_window, Duration::from_secs(120));
+        let config = load(
+            BASE,
+            &[("RATE_LIMIT_BURST", "30"), ("RATE_LIMIT_WINDOW_SECS", "30")],
+        )
+        .unwrap();
+        assert_eq!(config.rate_limit_window, Duration::from_secs(30));
+        let err = load(
+            BASE,
+            &[("RATE_LIMIT_BURST", "5"), ("RATE_LIMIT_WINDOW_SECS", "30")],
+        )
+        .err()
+        .expect("contradiction");
+        assert_eq!(
+            err,
+            "RATE_LIMIT_BURST: is 30 for a 30s window at 60 requests per minute; set only one of the two"
+        );
+        let err = load(
+            BASE,
+            &[("RATE_LIMIT_PER_MINUTE", "1"), ("RATE_LIMIT_BURST", "1441")],
+        )
+        .err()
+        .expect("invalid");
+        assert_eq!(
+            err,
+            "RATE_LIMIT_BURST: must be at most 1440 at 1 requests per minute"
+        );
+
+        let err = load(BASE, &[("SERVE_IMAGES", "maybe")])
+            .err()
+            .expect("invalid");
+        assert!(err.starts_with("SERVE_IMAGES:"), "{err}");
+
+        let err = load(&format!("{BASE}\nmax_upload_size = 1"), &[])
+            .err()
+            .expect("invalid");
+        assert_eq!(err, "imgd.toml: unknown key max_upload_size");
+
+        let err = load(
+            &format!("{BASE}\nstorage = \"s3\"\ns3_endpoint = \"http://127.0.0.1:9000\""),
+            &[],
+        )
+        .err()
+        .expect("invalid");
+        assert_eq!(err, "S3_BUCKET must be set when STORAGE=s3");
+
+        let err = load("upload_token = \"secret\"", &[])
+            .err()
+            .expect("invalid");
+        assert!(err.contains("PUBLIC_BASE_URL"), "{err}");
+    }
+}
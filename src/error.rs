@@ -6,18 +6,46 @@ use thiserror::Error;
 pub enum AppError {
     #[error("unauthorized")]
     Unauthorized,
+    #[error("forbidden")]
+    Forbidden,
+    #[error("not_found")]
+    NotFound,
     #[error("unsupported_media_type")]
     UnsupportedMediaType,
+    /// Metadata stripping was requested for a format it is not implemented for.
+    #[error("unsupported_media_type")]
+    StripUnsupported,
+    #[error("invalid_image")]
+    InvalidImage(String),
     #[error("file_too_large")]
     FileTooLarge,
     #[error("bad_request")]
     BadRequest,
+    #[error("conflict")]
+    Conflict,
+    #[error("fetch_failed")]
+    FetchFailed(String),
+    #[error("quota_exceeded")]
+    QuotaExceeded,
     #[error("too_many_requests")]
     TooManyRequests,
     #[error("internal_error")]
     Internal,
 }
 
+const STRIP_UNSUPPORTED: &str = "strip_metadata_unsupported";
+
+impl AppError {
+    /// The `detail` field of the response body, if any.
+    pub fn detail(&self) -> Option<String> {
+        match self {
+            AppError::InvalidImage(detail) | AppError::FetchFailed(detail) => Some(detail.clone()),
+            AppError::StripUnsupported => Some(STRIP_UNSUPPORTED.to_owned()),
+            _ => None,
+        }
+    }
+}
+
 #[derive(Serialize)]
 struct ErrorBody<'a> {
     error: &'a str,
@@ -29,13 +57,30 @@ impl IntoResponse for AppError {
     fn into_response(self) -> axum::response::Response {
         let (status, error, detail) = match self {
             AppError::Unauthorized => (StatusCode::UNAUTHORIZED, "unauthorized", None),
+            AppError::Forbidden => (StatusCode::FORBIDDEN, "forbidden", None),
+            AppError::NotFound => (StatusCode::NOT_FOUND, "not_found", None),
             AppError::UnsupportedMediaType => (
                 StatusCode::UNSUPPORTED_MEDIA_TYPE,
                 "unsupported_media_type",
                 None,
             ),
+            AppError::StripUnsupported => (
+                StatusCode::UNSUPPORTED_MEDIA_TYPE,
+                "unsupported_media_type",
+                Some(STRIP_UNSUPPORTED.to_owned()),
+            ),
+            AppError::InvalidImage(reason) => (
+                StatusCode::UNPROCESSABLE_ENTITY,
+                "invalid_image",
+                Some(reason),
+            ),
             AppError::FileTooLarge => (StatusCode::PAYLOAD_TOO_LARGE, "file_too_large", None),
             AppError::BadRequest => (StatusCode::BAD_REQUEST, "bad_request", None),
+            AppError::QuotaExceeded => (StatusCode::FORBIDDEN, "quota_exceeded", None),
+            AppError::FetchFailed(reason) => {
+                (StatusCode::BAD_GATEWAY, "fetch_failed", Some(reason))
+            }
+            AppError::Conflict => (StatusCode::CONFLICT, "conflict", None),
             AppError::TooManyRequests => (StatusCode::TOO_MANY_REQUESTS, "too_many_requests", None),
             AppError::Internal => (StatusCode::INTERNAL_SERVER_ERROR, "internal_error", None),
         };
@@ -0,0 +1,220 @@
+use std::{
+    error::Error as StdError,
+    fmt,
+    net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr},
+    sync::Arc,
+    time::Duration,
+};
+
+use reqwest::{
+    dns::{Addrs, Name, Resolve, Resolving},
+    redirect, Client, Response, Url,
+};
+
+use crate::{client_ip::IpNet, config::AppConfig};
+
+/// HTTP client for `POST /upload/url`. Every address it connects to, after
+/// DNS and after each redirect, must be public or inside
+/// `fetch_allowed_networks`, so uploaders cannot reach internal services.
+#[derive(Clone)]
+pub struct Fetcher {
+    client: Client,
+    allowed: Arc<[IpNet]>,
+}
+
+#[derive(Debug)]
+pub enum FetchError {
+    /// Not an absolute `http`/`https` URL.
+    InvalidUrl,
+    /// The host resolves only to addresses that may not be fetched.
+    Blocked,
+    /// Timeouts, connection errors, too many redirects or a non-2xx status.
+    Upstream(String),
+}
+
+/// Resolver error marking a refused address, recognised in
+/// [`Fetcher::get`] to tell policy refusals from network failures.
+#[derive(Debug)]
+struct BlockedAddress;
+
+impl fmt::Display for BlockedAddress {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str("blocked address")
+    }
+}
+
+impl StdError for BlockedAddress {}
+
+impl Fetcher {
+    pub fn new(config: &AppConfig) -> Self {
+        let allowed: Arc<[IpNet]> = config.fetch_allowed_networks.clone().into();
+        let max_redirects = config.fetch_max_redirects;
+        let redirect_allowed = allowed.clone();
+        let policy = redirect::Policy::custom(move |attempt| {
+            if attempt.previous().len() > max_redirects {
+                attempt.error("too_many_redirects")
+            } else if check_url(attempt.url(), &redirect_allowed).is_err() {
+                attempt.error(BlockedAddress)
+            } else {
+                attempt.follow()
+            }
+        });
+        let client = Client::builder()
+            .timeout(config.fetch_timeout)
+            .connect_timeout(config.fetch_timeout.min(Duration::from_secs(5)))
+            .redirect(policy)
+            // A proxy would resolve names itself, past the address checks.
+            .no_proxy()
+            .dns_resolver(Arc::new(GuardedResolver {
+                allowed: allowed.clone(),
+            }))
+            .user_agent(concat!("imgd/", env!("CARGO_PKG_VERSION")))
+            .build()
+            .expect("http client");
+        Self { client, allowed }
+    }
+
+    /// Starts a GET for `url`; the caller streams the body.
+    pub async fn get(&self, url: &str) -> Result<Response, FetchError> {
+        let url = Url::parse(url).map_err(|_| FetchError::InvalidUrl)?;
+        check_url(&url, &self.allowed)?;
+        let resp = self.client.get(url).send().await.map_err(|err| {
+            if caused_by_block(&err) {
+                FetchError::Blocked
+            } else if err.is_timeout() {
+                FetchError::Upstream("timeout".to_owned())
+            } else if err.is_redirect() {
+                FetchError::Upstream("too_many_redirects".to_owned())
+            } else {
+                FetchError::Upstream("connect".to_owned())
+            }
+        })?;
+        if !resp.status().is_success() {
+            return Err(FetchError::Upstream(format!(
+                "status_{}",
+                resp.status().as_u16()
+            )));
+        }
+        Ok(resp)
+    }
+}
+
+fn caused_by_block(err: &reqwest::Error) -> bool {
+    let mut source: Option<&(dyn StdError + 'static)> = Some(err);
+    while let Some(err) = source {
+        if err.is::<BlockedAddress>() {
+            return true;
+        }
+        source = err.source();
+    }
+    false
+}
+
+/// Checks the scheme and, for IP literals which never reach the resolver,
+/// the address itself.
+fn check_url(url: &Url, allowed: &[IpNet]) -> Result<(), FetchError> {
+    if !matches!(url.scheme(), "http" | "https") {
+        return Err(FetchError::InvalidUrl);
+    }
+    let host = url.host_str().ok_or(FetchError::InvalidUrl)?;
+    match host.trim_start_matches('[').trim_end_matches(']').parse() {
+        Ok(ip) if !is_fetchable(ip, allowed) => Err(FetchError::Blocked),
+        _ => Ok(()),
+    }
+}
+
+pub fn is_fetchable(ip: IpAddr, allowed: &[IpNet]) -> bool {
+    allowed.iter().any(|net| net.contains(ip)) || is_public(ip)
+}
+
+fn is_public(ip: IpAddr) -> bool {
+    match ip.to_canonical() {
+        IpAddr::V4(ip) => is_public_v4(ip),
+        IpAddr::V6(ip) => is_public_v6(ip),
+    }
+}
+
+fn is_public_v4(ip: Ipv4Addr) -> bool {
+    let [a, b, ..] = ip.octets();
+    !(ip.is_unspecified()
+        || ip.is_loopback()
+        || ip.is_private()
+        || ip.is_link_local()
+        || ip.is_broadcast()
+        || ip.is_multicast()
+        || ip.is_documentation()
+        // 0.0.0.0/8, 100.64.0.0/10 (carrier-grade NAT), 198.18.0.0/15
+        // (benchmarking) and 240.0.0.0/4 (reserved).
+        || a == 0
+        || (a == 100 && (64..128).contains(&b))
+        || (a == 198 && (18..20).contains(&b))
+        || a >= 240)
+}
+
+fn is_public_v6(ip: Ipv6Addr) -> bool {
+    let first = ip.segments()[0];
+    !(ip.is_unspecified()
+        || ip.is_loopback()
+        || ip.is_multicast()
+        // fc00::/7 unique local, fe80::/10 link-local, 2001:db8::/32 documentation.
+        || (first & 0xfe00) == 0xfc00
+        || (first & 0xffc0) == 0xfe80
+        || (first == 0x2001 && ip.segments()[1] == 0x0db8)
+        // NAT64 and 6to4 embed IPv4 addresses that could be internal.
+        || (first == 0x0064 && ip.segments()[1] == 0xff9b)
+        || first == 0x2002)
+}
+
+/// System DNS with refused addresses filtered out. Checking here, rather
+/// than before the request, means the address that is checked is the one
+/// connected to, even across redirects or when DNS answers change.
+struct GuardedResolver {
+    allowed: Arc<[IpNet]>,
+}
+
+impl Resolve for GuardedResolver {
+    fn resolve(&self, name: Name) -> Resolving {
+        let allowed = self.allowed.clone();
+        let host = name.as_str().to_owned();
+        Box::pin(async move {
+            let addrs: Vec<SocketAddr> = tokio::net::lookup_host((host.as_str(), 0))
+                .await?
+                .filter(|addr| is_fetchable(addr.ip(), &allowed))
+                .collect();
+            if addrs.is_empty() {
+                return Err(Box::new(BlockedAddress) as Box<dyn StdError + Send + Sync>);
+            }
+            Ok(Box::new(addrs.into_iter()) as Addrs)
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::is_fetchable;
+    use crate::client_ip::IpNet;
+
+    #[test]
+    fn internal_ranges_are_refused_unless_allowed() {
+        for ip in [
+            "127.0.0.1",
+            "10.1.2.3",
+            "172.16.0.1",
+            "192.168.1.1",
+            "169.254.169.254",
+            "100.64.0.1",
+            "0.0.0.0",
+            "::1",
+            "fd00::1",
+            "fe80::1",
+            "::ffff:127.0.0.1",
+        ] {
+            assert!(!is_fetchable(ip.parse().unwrap(), &[]), "{ip}");
+        }
+        for ip in ["93.184.216.34", "1.1.1.1", "2606:4700::1111"] {
+            assert!(is_fetchable(ip.parse().unwrap(), &[]), "{ip}");
+        }
+        let allowed: IpNet = "10.0.0.0/8".parse().unwrap();
+        assert!(is_fetchable("10.1.2.3".parse().unwrap(), &[allowed]));
+    }
+}
@@ -0,0 +1,556 @@
+use std::{fmt, path::Path, str::FromStr};
+
+use serde::{Deserialize, Serialize};
+
+use crate::webp;
+
+/// Number of leading bytes needed to recognize every supported format.
+pub const SNIFF_LEN: usize = 32;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum ImageFormat {
+    Webp,
+    Png,
+    Jpeg,
+    Gif,
+    Avif,
+}
+
+impl ImageFormat {
+    pub const ALL: [ImageFormat; 5] = [
+        ImageFormat::Webp,
+        ImageFormat::Png,
+        ImageFormat::Jpeg,
+        ImageFormat::Gif,
+        ImageFormat::Avif,
+    ];
+
+    /// Extension used for stored files.
+    pub fn extension(self) -> &'static str {
+        match self {
+            ImageFormat::Webp => "webp",
+            ImageFormat::Png => "png",
+            ImageFormat::Jpeg => "jpg",
+            ImageFormat::Gif => "gif",
+            ImageFormat::Avif => "avif",
+        }
+    }
+
+    pub fn content_type(self) -> &'static str {
+        match self {
+            ImageFormat::Webp => "image/webp",
+            ImageFormat::Png => "image/png",
+            ImageFormat::Jpeg => "image/jpeg",
+            ImageFormat::Gif => "image/gif",
+            ImageFormat::Avif => "image/avif",
+        }
+    }
+
+    pub fn from_extension(ext: &str) -> Option<Self> {
+        let ext = ext.to_ascii_lowercase();
+        match ext.as_str() {
+            "webp" => Some(ImageFormat::Webp),
+            "png" => Some(ImageFormat::Png),
+            "jpg" | "jpeg" => Some(ImageFormat::Jpeg),
+            "gif" => Some(ImageFormat::Gif),
+            "avif" => Some(ImageFormat::Avif),
+            _ => None,
+        }
+    }
+
+    /// Parses a `Content-Type` value such as `image/png; charset=binary`.
+    pub fn from_content_type(content_type: &str) -> Option<Self> {
+        let essence = content_type.split(';').next()?.trim().to_ascii_lowercase();
+        if essence == "image/jpg" {
+            return Some(ImageFormat::Jpeg);
+        }
+        Self::ALL.into_iter().find(|f| f.content_type() == essence)
+    }
+
+    pub fn from_filename(filename: &str) -> Option<Self> {
+        Path::new(filename)
+            .extension()
+            .and_then(|s| s.to_str())
+            .and_then(Self::from_extension)
+    }
+
+    /// Identifies the format from the leading bytes of a file.
+    pub fn detect(header: &[u8]) -> Option<Self> {
+        if webp::has_webp_signature(header) {
+            return Some(ImageFormat::Webp);
+        }
+        if header.starts_with(b"\x89PNG\r\n\x1a\n") {
+            return Some(ImageFormat::Png);
+        }
+        if header.starts_with(&[0xff, 0xd8, 0xff]) {
+            return Some(ImageFormat::Jpeg);
+        }
+        if header.starts_with(b"GIF87a") || header.starts_with(b"GIF89a") {
+            return Some(ImageFormat::Gif);
+        }
+        if has_avif_signature(header) {
+            return Some(ImageFormat::Avif);
+        }
+        None
+    }
+}
+
+impl fmt::Display for ImageFormat {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            ImageFormat::Jpeg => "jpeg",
+            other => other.extension(),
+        })
+    }
+}
+
+impl FromStr for ImageFormat {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        Self::from_extension(s.trim()).ok_or_else(|| format!("unknown image format: {s}"))
+    }
+}
+
+/// Parses a comma-separated format list such as `webp,png,jpeg`.
+pub fn parse_format_list(raw: &str) -> Result<Vec<ImageFormat>, String> {
+    let mut formats = Vec::new();
+    for part in raw.split(',').filter(|p| !p.trim().is_empty()) {
+        let format: ImageFormat = part.parse()?;
+        if !formats.contains(&format) {
+            formats.push(format);
+        }
+    }
+    if formats.is_empty() {
+        return Err("format list is empty".to_string());
+    }
+    Ok(formats)
+}
+
+/// AVIF files are ISO-BMFF containers whose leading `ftyp` box lists an
+/// `avif` (still) or `avis` (sequence) brand.
+fn has_avif_signature(header: &[u8]) -> bool {
+    if header.len() < 12 || &header[4..8] != b"ftyp" {
+        return false;
+    }
+
+    let box_len = u32::from_be_bytes([header[0], header[1], header[2], header[3]]) as usize;
+    let end = box_len.min(header.len());
+    let is_avif_brand = |brand: &[u8]| brand == b"avif" || brand == b"avis";
+
+    if is_avif_brand(&header[8..12]) {
+        return true;
+    }
+
+    // Skip major brand and minor version, then scan the compatible brands.
+    header
+        .get(16..end)
+        .map(|brands| brands.chunks_exact(4).any(is_avif_brand))
+        .unwrap_or(false)
+}
+
+/// Dimensions and animation details read from the image headers.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub struct ImageInfo {
+    pub width: u32,
+    pub height: u32,
+    pub animated: bool,
+    pub frame_count: u32,
+    pub has_alpha: bool,
+}
+
+impl ImageInfo {
+    fn still(width: u32, height: u32, has_alpha: bool) -> Self {
+        Self {
+            width,
+            height,
+            animated: false,
+            frame_count: 1,
+            has_alpha,
+        }
+    }
+}
+
+/// Validates `data` as `format` and reads its [`ImageInfo`]. The error is
+/// the machine-readable reason reported to clients.
+pub fn inspect(format: ImageFormat, data: &[u8]) -> Result<ImageInfo, String> {
+    match format {
+        ImageFormat::Webp => webp::parse(data)
+            .map(|container| container.info())
+            .map_err(|e| e.to_string()),
+        ImageFormat::Png => png_info(data).ok_or_else(|| "invalid_png".to_string()),
+        ImageFormat::Jpeg => jpeg_info(data).ok_or_else(|| "invalid_jpeg".to_string()),
+        ImageFormat::Gif => gif_info(data).ok_or_else(|| "invalid_gif".to_string()),
+        ImageFormat::Avif => avif_info(data).ok_or_else(|| "invalid_avif".to_string()),
+    }
+}
+
+/// Reads `IHDR`, then scans for `tRNS` (alpha) and `acTL` (APNG frames).
+fn png_info(data: &[u8]) -> Option<ImageInfo> {
+    let mut rest = data.get(8..)?;
+    let mut info: Option<ImageInfo> = None;
+
+    while rest.len() >= 12 {
+        let len = be_u32(rest)? as usize;
+        let kind = rest.get(4..8)?;
+        let body = rest.get(8..8usize.checked_add(len)?)?;
+
+        match kind {
+            b"IHDR" if info.is_none() => {
+                if body.len() < 13 {
+                    return None;
+                }
+                let (width, height) = (be_u32(body)?, be_u32(&body[4..])?);
+                if width == 0 || height == 0 {
+                    return None;
+                }
+                let color_type = body[9];
+                info = Some(ImageInfo::still(width, height, matches!(color_type, 4 | 6)));
+            }
+            _ if info.is_none() => return None,
+            b"tRNS" => info.as_mut()?.has_alpha = true,
+            b"acTL" if body.len() >= 8 => {
+                let frames = be_u32(body)?;
+                let info = info.as_mut()?;
+                info.frame_count = frames.max(1);
+                info.animated = frames > 1;
+            }
+            b"IDAT" | b"IEND" => break,
+            _ => {}
+        }
+        rest = rest.get(12 + len..)?;
+    }
+
+    info
+}
+
+/// Walks marker segments until a start-of-frame marker yields the size.
+fn jpeg_info(data: &[u8]) -> Option<ImageInfo> {
+    let mut pos = 2usize;
+    loop {
+        while *data.get(pos)? != 0xff {
+            pos += 1;
+        }
+        while *data.get(pos)? == 0xff {
+            pos += 1;
+        }
+        let marker = *data.get(pos)?;
+        pos += 1;
+
+        match marker {
+            0x01 | 0xd0..=0xd7 => continue,
+            0xd9 | 0xda => return None,
+            _ => {}
+        }
+
+        let len = u16::from_be_bytes([*data.get(pos)?, *data.get(pos + 1)?]) as usize;
+        if len < 2 {
+            return None;
+        }
+        let is_sof = matches!(marker, 0xc0..=0xcf) && !matches!(marker, 0xc4 | 0xc8 | 0xcc);
+        if is_sof {
+            let segment = data.get(pos + 2..pos + len)?;
+            if segment.len() < 6 {
+                return None;
+            }
+            let height = u16::from_be_bytes([segment[1], segment[2]]) as u32;
+            let width = u16::from_be_bytes([segment[3], segment[4]]) as u32;
+            if width == 0 || height == 0 {
+                return None;
+            }
+            return Some(ImageInfo::still(width, height, false));
+        }
+        pos += len;
+    }
+}
+
+/// Counts image descriptors and looks for a transparent color index in
+/// graphic control extensions.
+fn gif_info(data: &[u8]) -> Option<ImageInfo> {
+    let screen = data.get(6..13)?;
+    let width = u16::from_le_bytes([screen[0], screen[1]]) as u32;
+    let height = u16::from_le_bytes([screen[2], screen[3]]) as u32;
+    if width == 0 || height == 0 {
+        return None;
+    }
+
+    let mut pos = 13 + color_table_len(screen[4]);
+    let mut frames = 0u32;
+    let mut has_alpha = false;
+
+    loop {
+        match *data.get(pos)? {
+            0x2c => {
+                let packed = *data.get(pos + 9)?;
+                pos += 10 + color_table_len(packed) + 1;
+                pos = skip_sub_blocks(data, pos)?;
+                frames += 1;
+            }
+            0x21 => {
+                let label = *data.get(pos + 1)?;
+                if label == 0xf9 && *data.get(pos + 3)? & 1 == 1 {
+                    has_alpha = true;
+                }
+                pos = skip_sub_blocks(data, pos + 2)?;
+            }
+            0x3b => break,
+            _ => return None,
+        }
+    }
+
+    if frames == 0 {
+        return None;
+    }
+    Some(ImageInfo {
+        width,
+        height,
+        animated: frames > 1,
+        frame_count: frames,
+        has_alpha,
+    })
+}
+
+fn color_table_len(packed: u8) -> usize {
+    if packed & 0x80 == 0 {
+        0
+    } else {
+        3 * (1usize << ((packed & 0x07) + 1))
+    }
+}
+
+fn skip_sub_blocks(data: &[u8], mut pos: usize) -> Option<usize> {
+    loop {
+        let len = *data.get(pos)? as usize;
+        pos += 1;
+        if len == 0 {
+            return Some(pos);
+        }
+        pos += len;
+    }
+}
+
+/// Reads the first `ispe` property for the size, an `auxC` alpha plane for
+/// transparency, and the `stsz` sample count of image sequences.
+fn avif_info(data: &[u8]) -> Option<ImageInfo> {
+    let meta = find_box(data, b"meta")?;
+    let ipco = find_box(find_box(meta.get(4..)?, b"iprp")?, b"ipco")?;
+    let ispe = find_box(ipco, b"ispe")?;
+    let width = be_u32(ispe.get(4..)?)?;
+    let height = be_u32(ispe.get(8..)?)?;
+    if width == 0 || height == 0 {
+        return None;
+    }
+
+    let has_alpha = boxes(ipco)
+        .filter(|(kind, _)| kind == b"auxC")
+        .any(|(_, body)| body.windows(5).any(|w| w == b"alpha"));
+
+    let frame_count = find_box(data, b"moov")
+        .and_then(|moov| find_box(moov, b"trak"))
+        .and_then(|trak| find_box(trak, b"mdia"))
+        .and_then(|mdia| find_box(mdia, b"minf"))
+        .and_then(|minf| find_box(minf, b"stbl"))
+        .and_then(|stbl| find_box(stbl, b"stsz"))
+        .and_then(|stsz| be_u32(stsz.get(8..)?))
+        .unwrap_or(1)
+        .max(1);
+
+    Some(ImageInfo {
+        width,
+        height,
+        animated: frame_count > 1,
+        frame_count,
+        has_alpha,
+    })
+}
+
+fn find_box<'a>(data: &'a [u8], kind: &[u8; 4]) -> Option<&'a [u8]> {
+    boxes(data).find(|(k, _)| k == kind).map(|(_, body)| body)
+}
+
+/// Iterates ISO-BMFF boxes as `(type, body)`, stopping at the first box
+/// whose size does not fit.
+fn boxes(mut data: &[u8]) -> impl Iterator<Item = ([u8; 4], &[u8])> {
+    std::iter::from_fn(move || {
+        let size = be_u32(data)? as usize;
+        let kind: [u8; 4] = data.get(4..8)?.try_into().ok()?;
+        let (header, size) = match size {
+            0 => (8, data.len()),
+            1 => {
+                let large = u64::from_be_bytes(data.get(8..16)?.try_into().ok()?);
+                (16, usize::try_from(large).ok()?)
+            }
+            n => (8, n),
+        };
+        let body = data.get(header..size)?;
+        data = &data[size..];
+        Some((kind, body))
+    })
+}
+
+fn be_u32(b: &[u8]) -> Option<u32> {
+    Some(u32::from_be_bytes(b.get(0..4)?.try_into().ok()?))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{inspect, parse_format_list, ImageFormat, ImageInfo};
+
+    #[test]
+    fn detect_by_magic_bytes() {
+        assert_eq!(
+            ImageFormat::detect(b"RIFF\x10\x00\x00\x00WEBPVP8 "),
+            Some(ImageFormat::Webp)
+        );
+        assert_eq!(
+            ImageFormat::detect(b"\x89PNG\r\n\x1a\n\x00\x00\x00\x0dIHDR"),
+            Some(ImageFormat::Png)
+        );
+        assert_eq!(
+            ImageFormat::detect(&[0xff, 0xd8, 0xff, 0xe0, 0x00, 0x10]),
+            Some(ImageFormat::Jpeg)
+        );
+        assert_eq!(
+            ImageFormat::detect(b"GIF89a\x01\x00"),
+            Some(ImageFormat::Gif)
+        );
+        assert_eq!(
+            ImageFormat::detect(b"\x00\x00\x00\x1cftypavif\x00\x00\x00\x00avifmif1"),
+            Some(ImageFormat::Avif)
+        );
+        assert_eq!(ImageFormat::detect(b"hello, world"), None);
+    }
+
+    #[test]
+    fn format_from_content_type() {
+        assert_eq!(
+            ImageFormat::from_content_type("image/png"),
+            Some(ImageFormat::Png)
+        );
+        assert_eq!(
+            ImageFormat::from_content_type("Image/JPG; q=1"),
+            Some(ImageFormat::Jpeg)
+        );
+        assert_eq!(ImageFormat::from_content_type("text/plain"), None);
+    }
+
+    #[test]
+    fn detect_avif_from_compatible_brand() {
+        let header = b"\x00\x00\x00\x20ftypmif1\x00\x00\x00\x00mif1avifmiaf";
+        assert_eq!(ImageFormat::detect(header), Some(ImageFormat::Avif));
+
+        let heic = b"\x00\x00\x00\x18ftypheic\x00\x00\x00\x00mif1heic";
+        assert_eq!(ImageFormat::detect(heic), None);
+    }
+
+    #[test]
+    fn extension_lookup_is_case_insensitive() {
+        assert_eq!(
+            ImageFormat::from_filename("shot.JPEG"),
+            Some(ImageFormat::Jpeg)
+        );
+        assert_eq!(
+            ImageFormat::from_filename("a.webp"),
+            Some(ImageFormat::Webp)
+        );
+        assert_eq!(ImageFormat::from_filename("noext"), None);
+        assert_eq!(ImageFormat::from_filename("a.bmp"), None);
+    }
+
+    fn gif(frames: usize, transparent: bool) -> Vec<u8> {
+        let mut data = Vec::from(*b"GIF89a");
+        // 5x4 screen with a 2-entry global color table.
+        data.extend_from_slice(&[0x05, 0x00, 0x04, 0x00, 0x80, 0x00, 0x00]);
+        data.extend_from_slice(&[0, 0, 0, 255, 255, 255]);
+        for _ in 0..frames {
+            let flags = if transparent { 0x01 } else { 0x00 };
+            data.extend_from_slice(&[0x21, 0xf9, 0x04, flags, 0x0a, 0x00, 0x00, 0x00]);
+            data.extend_from_slice(&[0x2c, 0, 0, 0, 0, 0x05, 0x00, 0x04, 0x00, 0x00]);
+            data.extend_from_slice(&[0x02, 0x02, 0x44, 0x01, 0x00]);
+        }
+        data.push(0x3b);
+        data
+    }
+
+    fn encode(format: image::ImageFormat, color: image::DynamicImage) -> Vec<u8> {
+        let mut out = std::io::Cursor::new(Vec::new());
+        color.write_to(&mut out, format).expect("encode");
+        out.into_inner()
+    }
+
+    fn bmff_box(kind: &[u8; 4], body: &[u8]) -> Vec<u8> {
+        let mut out = ((body.len() + 8) as u32).to_be_bytes().to_vec();
+        out.extend_from_slice(kind);
+        out.extend_from_slice(body);
+        out
+    }
+
+    #[test]
+    fn inspect_png_and_jpeg() {
+        let rgba = image::DynamicImage::new_rgba8(7, 3);
+        let info = inspect(ImageFormat::Png, &encode(image::ImageFormat::Png, rgba)).unwrap();
+        assert_eq!((info.width, info.height, info.has_alpha), (7, 3, true));
+
+        let rgb = image::DynamicImage::new_rgb8(9, 4);
+        let info = inspect(ImageFormat::Jpeg, &encode(image::ImageFormat::Jpeg, rgb)).unwrap();
+        assert_eq!(info, ImageInfo::still(9, 4, false));
+
+        assert_eq!(
+            inspect(ImageFormat::Jpeg, &[0xff, 0xd8, 0xff, 0xda]),
+            Err("invalid_jpeg".to_string())
+        );
+        assert_eq!(
+            inspect(ImageFormat::Png, b"\x89PNG\r\n\x1a\n\x00\x00"),
+            Err("invalid_png".to_string())
+        );
+    }
+
+    #[test]
+    fn inspect_gif_frames_and_transparency() {
+        assert_eq!(
+            inspect(ImageFormat::Gif, &gif(1, false)).unwrap(),
+            ImageInfo::still(5, 4, false)
+        );
+
+        let info = inspect(ImageFormat::Gif, &gif(3, true)).unwrap();
+        assert!(info.animated && info.has_alpha);
+        assert_eq!(info.frame_count, 3);
+
+        let mut truncated = gif(2, false);
+        truncated.truncate(truncated.len() - 4);
+        assert!(inspect(ImageFormat::Gif, &truncated).is_err());
+    }
+
+    #[test]
+    fn inspect_avif_properties() {
+        let mut ispe = vec![0, 0, 0, 0];
+        ispe.extend_from_slice(&640u32.to_be_bytes());
+        ispe.extend_from_slice(&480u32.to_be_bytes());
+        let aux = bmff_box(
+            b"auxC",
+            b"\0\0\0\0urn:mpeg:mpegB:cicp:systems:auxiliary:alpha\0",
+        );
+        let ipco = bmff_box(b"ipco", &[bmff_box(b"ispe", &ispe), aux].concat());
+        let meta = bmff_box(
+            b"meta",
+            &[vec![0, 0, 0, 0], bmff_box(b"iprp", &ipco)].concat(),
+        );
+        let file = [bmff_box(b"ftyp", b"avif\0\0\0\0avifmif1"), meta].concat();
+
+        let info = inspect(ImageFormat::Avif, &file).unwrap();
+        assert_eq!((info.width, info.height), (640, 480));
+        assert!(info.has_alpha && !info.animated);
+
+        assert!(inspect(ImageFormat::Avif, &file[..40]).is_err());
+    }
+
+    #[test]
+    fn parse_list_dedups_and_rejects_unknown() {
+        assert_eq!(
+            parse_format_list("webp, png,jpg,jpeg"),
+            Ok(vec![ImageFormat::Webp, ImageFormat::Png, ImageFormat::Jpeg])
+        );
+        assert!(parse_format_list("webp,bmp").is_err());
+        assert!(parse_format_list(" , ").is_err());
+    }
+}
@@ -0,0 +1,788 @@
+use std::{
+    collections::{HashMap, HashSet},
+    fs,
+    io::{self, Write},
+    net::IpAddr,
+    path::{Path, PathBuf},
+    sync::{Arc, Mutex as StdMutex, PoisonError},
+};
+
+use chrono::{DateTime, Utc};
+use serde::{Deserialize, Serialize};
+use tokio::{fs::OpenOptions, io::AsyncWriteExt, sync::Mutex};
+use tracing::warn;
+
+use crate::format::ImageInfo;
+
+/// One token's reference to a stored image. Content is stored once per
+/// sha256; every token that uploads it holds its own record and is charged
+/// for it. Fields added after the first release default when replaying
+/// older entries.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct ImageRecord {
+    pub sha256: String,
+    pub token_id: String,
+    #[serde(default)]
+    pub token_name: String,
+    #[serde(default)]
+    pub client_ip: Option<IpAddr>,
+    /// Client-supplied file name, truncated to [`MAX_FILENAME_CHARS`].
+    #[serde(default)]
+    pub original_filename: Option<String>,
+    #[serde(default)]
+    pub size: u64,
+    #[serde(default)]
+    pub content_type: String,
+    #[serde(default)]
+    pub image: Option<ImageInfo>,
+    /// Public path relative to the image root, e.g. `/2024/05/<sha256>.webp`.
+    pub path: String,
+    pub created_at: DateTime<Utc>,
+}
+
+/// Longest client file name kept in the index.
+pub const MAX_FILENAME_CHARS: usize = 255;
+
+#[derive(Serialize, Deserialize)]
+#[serde(tag = "op", rename_all = "snake_case")]
+enum Entry {
+    Put(ImageRecord),
+    Delete {
+        sha256: String,
+        /// The token whose reference is removed; entries without one, as
+        /// written before references were per token, remove them all.
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        token_id: Option<String>,
+    },
+}
+
+/// Bytes and files currently attributed to a token.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct Usage {
+    pub bytes: u64,
+    pub files: u64,
+}
+
+/// Per-token storage limits; `None` means unlimited.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct Quota {
+    pub max_bytes: Option<u64>,
+    pub max_files: Option<u64>,
+}
+
+impl Quota {
+    /// Whether one more file of `bytes` fits on top of `usage`.
+    pub fn allows(&self, usage: Usage, bytes: u64) -> bool {
+        self.max_files.is_none_or(|max| usage.files < max)
+            && self
+                .max_bytes
+                .is_none_or(|max| usage.bytes.saturating_add(bytes) <= max)
+    }
+}
+
+/// Outcome of [`ImageIndex::insert`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Insert {
+    Added,
+    /// The token already references this sha256; nothing is charged.
+    Exists,
+    OverQuota,
+}
+
+/// Why [`ImageIndex::reserve`] refused an upload.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Refusal {
+    OverQuota,
+    /// The last reference to the content was just removed and its object
+    /// is being deleted.
+    Deleting,
+}
+
+#[derive(Default)]
+struct Records {
+    /// References per sha256, oldest first. The first one's path is where
+    /// the object is stored.
+    by_sha256: HashMap<String, Vec<ImageRecord>>,
+    usage: HashMap<String, Usage>,
+    /// References across all of `by_sha256`.
+    live: usize,
+    /// Lines in the log, including ones later puts and deletes superseded.
+    logged: usize,
+}
+
+impl Records {
+    fn add(&mut self, record: ImageRecord) {
+        let usage = self.usage.entry(record.token_id.clone()).or_default();
+        usage.bytes += record.size;
+        usage.files += 1;
+        let refs = self.by_sha256.entry(record.sha256.clone()).or_default();
+        match refs.iter_mut().find(|r| r.token_id == record.token_id) {
+            Some(old) => {
+                let old = std::mem::replace(old, record);
+                self.subtract(&old);
+            }
+            None => {
+                refs.push(record);
+                self.live += 1;
+            }
+        }
+    }
+
+    /// Removes the references held by `token_id`, or all of them.
+    fn remove(&mut self, sha256: &str, token_id: Option<&str>) -> Vec<ImageRecord> {
+        let Some(refs) = self.by_sha256.get_mut(sha256) else {
+            return Vec::new();
+        };
+        let (removed, kept) = std::mem::take(refs)
+            .into_iter()
+            .partition(|r| token_id.is_none_or(|t| r.token_id == t));
+        *refs = kept;
+        if refs.is_empty() {
+            self.by_sha256.remove(sha256);
+        }
+        self.live -= removed.len();
+        for record in &removed {
+            self.subtract(record);
+        }
+        removed
+    }
+
+    fn holds(&self, sha256: &str, token_id: &str) -> bool {
+        self.by_sha256
+            .get(sha256)
+            .is_some_and(|refs| refs.iter().any(|r| r.token_id == token_id))
+    }
+
+    fn subtract(&mut self, record: &ImageRecord) {
+        if let Some(usage) = self.usage.get_mut(&record.token_id) {
+            usage.bytes = usage.bytes.saturating_sub(record.size);
+            usage.files = usage.files.saturating_sub(1);
+        }
+    }
+}
+
+/// State of uploads and deletions that are between their index check and
+/// their index write.
+#[derive(Default)]
+struct InFlight {
+    /// Quota held per token by uploads not yet recorded.
+    usage: HashMap<String, Usage>,
+    /// Uploads per sha256 that will reference its object once recorded.
+    uploads: HashMap<String, usize>,
+    /// Content whose object is being deleted.
+    deleting: HashSet<String>,
+}
+
+type Pending = Arc<StdMutex<InFlight>>;
+
+fn lock(pending: &Pending) -> std::sync::MutexGuard<'_, InFlight> {
+    pending.lock().unwrap_or_else(PoisonError::into_inner)
+}
+
+/// Quota held for one upload from [`ImageIndex::reserve`] until
+/// [`ImageIndex::commit`] records it. Dropping it, e.g. when storing fails
+/// or the request is cancelled, gives the quota back.
+#[must_use]
+pub struct Reservation {
+    pending: Pending,
+    token_id: String,
+    sha256: String,
+    size: u64,
+    /// False when the token already referenced the content at reserve time,
+    /// which is never charged.
+    charged: bool,
+    existing: Option<ImageRecord>,
+}
+
+impl Reservation {
+    /// The first reference to the content if it is already stored. Its
+    /// path is reused, whatever month it was first uploaded in, and nothing
+    /// needs to be written to storage.
+    pub fn existing(&self) -> Option<&ImageRecord> {
+        self.existing.as_ref()
+    }
+}
+
+impl Drop for Reservation {
+    fn drop(&mut self) {
+        let mut pending = lock(&self.pending);
+        if let Some(uploads) = pending.uploads.get_mut(&self.sha256) {
+            *uploads -= 1;
+            if *uploads == 0 {
+                pending.uploads.remove(&self.sha256);
+            }
+        }
+        if !self.charged {
+            return;
+        }
+        if let Some(usage) = pending.usage.get_mut(&self.token_id) {
+            usage.bytes = usage.bytes.saturating_sub(self.size);
+            usage.files = usage.files.saturating_sub(1);
+            if *usage == Usage::default() {
+                pending.usage.remove(&self.token_id);
+            }
+        }
+    }
+}
+
+/// References picked by [`ImageIndex::begin_remove`]. When they are the
+/// last ones, uploads of the content are refused until this is dropped, so
+/// the caller can delete the object without an upload reusing it meanwhile.
+#[must_use]
+pub struct Removal {
+    pending: Pending,
+    sha256: String,
+    token_id: Option<String>,
+    records: Vec<ImageRecord>,
+    last: bool,
+}
+
+impl Removal {
+    pub fn records(&self) -> &[ImageRecord] {
+        &self.records
+    }
+
+    /// Whether no reference remains afterwards, so the object and its
+    /// variants should be deleted.
+    pub fn is_last(&self) -> bool {
+        self.last
+    }
+}
+
+impl Drop for Removal {
+    fn drop(&mut self) {
+        if self.last {
+            lock(&self.pending).deleting.remove(&self.sha256);
+        }
+    }
+}
+
+/// JSONL log of uploads and deletions under `<data_dir>/.meta/index.jsonl`,
+/// replayed into memory on startup and rewritten once superseded entries
+/// outnumber the live ones. Per-token usage is derived from the replay, so
+/// quotas survive restarts.
+pub struct ImageIndex {
+    path: PathBuf,
+    records: Mutex<Records>,
+    pending: Pending,
+}
+
+impl ImageIndex {
+    pub fn open(data_dir: &Path) -> io::Result<Self> {
+        let dir = data_dir.join(".meta");
+        fs::create_dir_all(&dir)?;
+        let path = dir.join("index.jsonl");
+
+        let mut records = Records::default();
+        match fs::read_to_string(&path) {
+            Ok(data) => {
+                if !data.is_empty() && !data.ends_with('\n') {
+                    // Terminate a torn line so the next append starts cleanly.
+                    let mut file = fs::OpenOptions::new().append(true).open(&path)?;
+                    file.write_all(b"\n")?;
+                }
+                for (lineno, line) in data.lines().enumerate() {
+                    if line.trim().is_empty() {
+                        continue;
+                    }
+                    records.logged += 1;
+                    // A crash mid-append can leave a torn last line; skip it
+                    // rather than refusing to start.
+                    match serde_json::from_str::<Entry>(line) {
+                        Ok(Entry::Put(record)) => records.add(record),
+                        Ok(Entry::Delete { sha256, token_id }) => {
+                            records.remove(&sha256, token_id.as_deref());
+                        }
+                        Err(err) => {
+                            warn!(path = %path.display(), line = lineno + 1, error = %err, "skipping malformed index entry");
+                        }
+                    }
+                }
+            }
+            Err(err) if err.kind() == io::ErrorKind::NotFound => {}
+            Err(err) => return Err(err),
+        }
+
+        Ok(Self {
+            path,
+            records: Mutex::new(records),
+            pending: Pending::default(),
+        })
+    }
+
+    /// The first reference to `sha256`, whose path is where it is stored.
+    pub async fn get(&self, sha256: &str) -> Option<ImageRecord> {
+        self.records
+            .lock()
+            .await
+            .by_sha256
+            .get(sha256)
+            .and_then(|refs| refs.first())
+            .cloned()
+    }
+
+    /// Every token's reference to `sha256`, oldest first.
+    pub async fn refs(&self, sha256: &str) -> Vec<ImageRecord> {
+        self.records
+            .lock()
+            .await
+            .by_sha256
+            .get(sha256)
+            .cloned()
+            .unwrap_or_default()
+    }
+
+    /// Returns clones of all records matching `filter`, in no particular order.
+    pub async fn select<F>(&self, filter: F) -> Vec<ImageRecord>
+    where
+        F: Fn(&ImageRecord) -> bool,
+    {
+        self.records
+            .lock()
+            .await
+            .by_sha256
+            .values()
+            .flatten()
+            .filter(|r| filter(r))
+            .cloned()
+            .collect()
+    }
+
+    pub async fn usage(&self, token_id: &str) -> Usage {
+        self.records
+            .lock()
+            .await
+            .usage
+            .get(token_id)
+            .copied()
+            .unwrap_or_default()
+    }
+
+    /// Bytes and files across all tokens.
+    pub async fn totals(&self) -> Usage {
+        self.records
+            .lock()
+            .await
+            .usage
+            .values()
+            .fold(Usage::default(), |total, usage| Usage {
+                bytes: total.bytes + usage.bytes,
+                files: total.files + usage.files,
+            })
+    }
+
+    /// Recorded plus reserved usage of `token_id`, for quota checks.
+    fn committed_and_pending(records: &Records, pending: &InFlight, token_id: &str) -> Usage {
+        let committed = records.usage.get(token_id).copied().unwrap_or_default();
+        let pending = pending.usage.get(token_id).copied().unwrap_or_default();
+        Usage {
+            bytes: committed.bytes.saturating_add(pending.bytes),
+            files: committed.files.saturating_add(pending.files),
+        }
+    }
+
+    /// Holds quota for an upload of `size` bytes before it is stored, so the
+    /// object is only published once it is known to fit. Content the token
+    /// already references is never refused and costs nothing; content other
+    /// tokens uploaded is charged to this token too, but stored only once.
+    pub async fn reserve(
+        &self,
+        token_id: &str,
+        sha256: &str,
+        size: u64,
+        quota: Quota,
+    ) -> Result<Reservation, Refusal> {
+        let records = self.records.lock().await;
+        let mut pending = lock(&self.pending);
+        if pending.deleting.contains(sha256) {
+            return Err(Refusal::Deleting);
+        }
+        let charged = !records.holds(sha256, token_id);
+        if charged {
+            let usage = Self::committed_and_pending(&records, &pending, token_id);
+            if !quota.allows(usage, size) {
+                return Err(Refusal::OverQuota);
+            }
+            let held = pending.usage.entry(token_id.to_owned()).or_default();
+            held.bytes = held.bytes.saturating_add(size);
+            held.files += 1;
+        }
+        *pending.uploads.entry(sha256.to_owned()).or_default() += 1;
+        Ok(Reservation {
+            pending: self.pending.clone(),
+            token_id: token_id.to_owned(),
+            sha256: sha256.to_owned(),
+            size,
+            charged,
+            existing: records
+                .by_sha256
+                .get(sha256)
+                .and_then(|refs| refs.first())
+                .cloned(),
+        })
+    }
+
+    /// Whether an upload of `size` bytes would currently fit, for checking
+    /// ahead of a long transfer. [`reserve`](Self::reserve) still decides.
+    pub async fn admits(&self, token_id: &str, sha256: &str, size: u64, quota: Quota) -> bool {
+        let records = self.records.lock().await;
+        let pending = lock(&self.pending);
+        records.holds(sha256, token_id)
+            || quota.allows(
+                Self::committed_and_pending(&records, &pending, token_id),
+                size,
+            )
+    }
+
+    /// Writes the record for a stored upload and turns its reservation into
+    /// recorded usage. A reference the token already holds is kept as is.
+    /// A reference removed since reserve time is admitted without a second
+    /// check, so a stored object is never rejected after the fact.
+    pub async fn commit(
+        &self,
+        record: ImageRecord,
+        reservation: Reservation,
+    ) -> io::Result<Insert> {
+        let mut records = self.records.lock().await;
+        if records.holds(&record.sha256, &record.token_id) {
+            return Ok(Insert::Exists);
+        }
+        self.append(&mut records, &Entry::Put(record.clone()))
+            .await?;
+        records.add(record);
+        drop(reservation);
+        Ok(Insert::Added)
+    }
+
+    /// [`reserve`](Self::reserve) and [`commit`](Self::commit) in one step,
+    /// for records whose content is already stored.
+    pub async fn insert(&self, record: ImageRecord, quota: Quota) -> io::Result<Insert> {
+        match self
+            .reserve(&record.token_id, &record.sha256, record.size, quota)
+            .await
+        {
+            Ok(reservation) => self.commit(record, reservation).await,
+            Err(_) => Ok(Insert::OverQuota),
+        }
+    }
+
+    /// Picks the references to `sha256` held by `token_id`, or all of them,
+    /// for [`remove`](Self::remove). `None` if there are none, or if the
+    /// content is already being deleted.
+    pub async fn begin_remove(&self, sha256: &str, token_id: Option<&str>) -> Option<Removal> {
+        let records = self.records.lock().await;
+        let mut pending = lock(&self.pending);
+        if pending.deleting.contains(sha256) {
+            return None;
+        }
+        let refs = records.by_sha256.get(sha256)?;
+        let chosen: Vec<_> = refs
+            .iter()
+            .filter(|r| token_id.is_none_or(|t| r.token_id == t))
+            .cloned()
+            .collect();
+        if chosen.is_empty() {
+            return None;
+        }
+        // An upload in flight will reference the object, so it stays.
+        let last = chosen.len() == refs.len() && !pending.uploads.contains_key(sha256);
+        if last {
+            pending.deleting.insert(sha256.to_owned());
+        }
+        Some(Removal {
+            pending: self.pending.clone(),
+            sha256: sha256.to_owned(),
+            token_id: token_id.map(str::to_owned),
+            records: chosen,
+            last,
+        })
+    }
+
+    /// Drops the references picked by [`begin_remove`](Self::begin_remove).
+    pub async fn remove(&self, removal: &Removal) -> io::Result<()> {
+        let mut records = self.records.lock().await;
+        self.append(
+            &mut records,
+            &Entry::Delete {
+                sha256: removal.sha256.clone(),
+                token_id: removal.token_id.clone(),
+            },
+        )
+        .await?;
+        records.remove(&removal.sha256, removal.token_id.as_deref());
+        Ok(())
+    }
+
+    async fn append(&self, records: &mut Records, entry: &Entry) -> io::Result<()> {
+        // Superseded puts and deletes stay in the log until it is rewritten;
+        // do that once they outnumber the live references.
+        if records.logged > 2 * records.live + 1024 {
+            self.rewrite(records).await?;
+            records.logged = records.live;
+        }
+        let mut line = serde_json::to_vec(entry)?;
+        line.push(b'\n');
+        let mut file = OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(&self.path)
+            .await?;
+        file.write_all(&line).await?;
+        file.sync_data().await?;
+        records.logged += 1;
+        Ok(())
+    }
+
+    /// Replaces the log with one put per live reference, oldest first per
+    /// sha256 so replay keeps each object's stored path.
+    async fn rewrite(&self, records: &Records) -> io::Result<()> {
+        let mut data = Vec::new();
+        for record in records.by_sha256.values().flatten() {
+            serde_json::to_writer(&mut data, &Entry::Put(record.clone()))?;
+            data.push(b'\n');
+        }
+        let tmp = self.path.with_extension("jsonl.tmp");
+        let mut file = tokio::fs::File::create(&tmp).await?;
+        file.write_all(&data).await?;
+        file.sync_data().await?;
+        tokio::fs::rename(&tmp, &self.path).await
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use chrono::Utc;
+
+    use super::{ImageIndex, ImageRecord, Insert, Quota, Refusal, Usage};
+
+    #[tokio::test]
+    async fn reservations_hold_quota_until_committed_or_dropped() {
+        let tmp = tempfile::tempdir().expect("tmpdir");
+        let index = ImageIndex::open(tmp.path()).expect("open");
+        let one = Quota {
+            max_bytes: None,
+            max_files: Some(1),
+        };
+        let held = index.reserve("t1", "aa", 42, one).await.expect("fits");
+        // A concurrent upload cannot claim the same slot.
+        assert!(index.reserve("t1", "bb", 42, one).await.is_err());
+        drop(held);
+        let held = index.reserve("t1", "bb", 42, one).await.expect("released");
+        assert_eq!(
+            index.commit(record("bb", "t1"), held).await.unwrap(),
+            Insert::Added
+        );
+        assert!(index.reserve("t1", "cc", 42, one).await.is_err());
+        assert!(!index.admits("t1", "cc", 42, one).await);
+        // Content the token already references is free to upload again.
+        assert!(index.reserve("t1", "bb", 42, one).await.is_ok());
+        assert!(index.admits("t1", "bb", 42, one).await);
+    }
+
+    #[tokio::test]
+    async fn shared_content_is_referenced_per_token() {
+        let tmp = tempfile::tempdir().expect("tmpdir");
+        let index = ImageIndex::open(tmp.path()).expect("open");
+        let unlimited = Quota::default();
+        index.insert(record("aa", "t1"), unlimited).await.unwrap();
+
+        let mut second = record("aa", "t2");
+        second.path = "/2031/01/aa.webp".to_owned();
+        let held = index.reserve("t2", "aa", 42, unlimited).await.unwrap();
+        assert_eq!(held.existing().unwrap().path, "/2024/05/aa.webp");
+        assert_eq!(index.commit(second, held).await.unwrap(), Insert::Added);
+        assert_eq!(index.usage("t2").await.files, 1);
+        assert_eq!(index.refs("aa").await.len(), 2);
+
+        // Removing one token's reference keeps the object for the other.
+        let removal = index.begin_remove("aa", Some("t1")).await.unwrap();
+        assert!(!removal.is_last());
+        index.remove(&removal).await.unwrap();
+        drop(removal);
+        assert!(index.begin_remove("aa", Some("t1")).await.is_none());
+
+        let reopened = ImageIndex::open(tmp.path()).expect("reopen");
+        let refs = reopened.refs("aa").await;
+        assert_eq!(refs.len(), 1);
+        assert_eq!(refs[0].token_id, "t2");
+        assert_eq!(reopened.usage("t1").await, Usage::default());
+
+        // While the last reference's object is deleted, uploads wait.
+        let removal = reopened.begin_remove("aa", None).await.unwrap();
+        assert!(removal.is_last());
+        assert_eq!(
+            reopened.reserve("t1", "aa", 42, unlimited).await.err(),
+            Some(Refusal::Deleting)
+        );
+        reopened.remove(&removal).await.unwrap();
+        drop(removal);
+        assert!(reopened.get("aa").await.is_none());
+        assert!(reopened.reserve("t1", "aa", 42, unlimited).await.is_ok());
+    }
+
+    fn record(sha256: &str, token_id: &str) -> ImageRecord {
+        ImageRecord {
+            sha256: sha256.to_owned(),
+            token_id: token_id.to_owned(),
+            token_name: "ci".to_owned(),
+            client_ip: Some("203.0.113.7".parse().unwrap()),
+            original_filename: Some("cat.png".to_owned()),
+            size: 42,
+            content_type: "image/webp".to_owned(),
+            image: None,
+            path: format!("/2024/05/{sha256}.webp"),
+            created_at: Utc::now(),
+        }
+    }
+
+    #[tokio::test]
+    async fn replays_puts_and_deletes() {
+        let tmp = tempfile::tempdir().expect("tmpdir");
+        let index = ImageIndex::open(tmp.path()).expect("open");
+        let unlimited = Quota::default();
+        assert_eq!(
+            index.insert(record("aa", "t1"), unlimited).await.unwrap(),
+            Insert::Added
+        );
+        assert_eq!(
+            index.insert(record("aa", "t1"), unlimited).await.unwrap(),
+            Insert::Exists
+        );
+        assert_eq!(
+            index.insert(record("bb", "t1"), unlimited).await.unwrap(),
+            Insert::Added
+        );
+        let removal = index.begin_remove("bb", None).await.expect("recorded");
+        index.remove(&removal).await.unwrap();
+        drop(removal);
+        assert!(index.begin_remove("bb", None).await.is_none());
+
+        let reopened = ImageIndex::open(tmp.path()).expect("reopen");
+        assert_eq!(
+            reopened.get("aa").await.unwrap(),
+            index.get("aa").await.unwrap()
+        );
+        assert!(reopened.get("bb").await.is_none());
+    }
+
+    #[tokio::test]
+    async fn replays_entries_without_newer_fields() {
+        let tmp = tempfile::tempdir().expect("tmpdir");
+        std::fs::create_dir_all(tmp.path().join(".meta")).unwrap();
+        std::fs::write(
+            tmp.path().join(".meta/index.jsonl"),
+            r#"{"op":"put","sha256":"aa","token_id":"t1","path":"/2024/05/aa.webp","created_at":"2024-05-01T00:00:00Z"}"#,
+        )
+        .unwrap();
+
+        let index = ImageIndex::open(tmp.path()).expect("open");
+        let record = index.get("aa").await.expect("record");
+        assert_eq!(record.token_id, "t1");
+        assert_eq!(record.original_filename, None);
+        assert_eq!(record.size, 0);
+    }
+
+    #[tokio::test]
+    async fn skips_torn_trailing_line() {
+        let tmp = tempfile::tempdir().expect("tmpdir");
+        let index = ImageIndex::open(tmp.path()).expect("open");
+        index
+            .insert(record("aa", "t1"), Quota::default())
+            .await
+            .unwrap();
+
+        let path = tmp.path().join(".meta/index.jsonl");
+        let mut data = std::fs::read(&path).unwrap();
+        data.extend_from_slice(b"{\"op\":\"put\",\"sha2");
+        std::fs::write(&path, data).unwrap();
+
+        let reopened = ImageIndex::open(tmp.path()).expect("reopen");
+        assert!(reopened.get("aa").await.is_some());
+        reopened
+            .insert(record("bb", "t1"), Quota::default())
+            .await
+            .unwrap();
+
+        let reopened = ImageIndex::open(tmp.path()).expect("reopen");
+        assert!(reopened.get("bb").await.is_some());
+    }
+
+    #[tokio::test]
+    async fn rewrites_log_once_mostly_dead() {
+        let tmp = tempfile::tempdir().expect("tmpdir");
+        let index = ImageIndex::open(tmp.path()).expect("open");
+        let unlimited = Quota::default();
+        index.insert(record("aa", "t1"), unlimited).await.unwrap();
+        let mut shared = record("aa", "t2");
+        shared.path = "/2031/01/aa.webp".to_owned();
+        index.insert(shared, unlimited).await.unwrap();
+        for _ in 0..600 {
+            index.insert(record("bb", "t1"), unlimited).await.unwrap();
+            let removal = index.begin_remove("bb", None).await.unwrap();
+            index.remove(&removal).await.unwrap();
+        }
+
+        let path = tmp.path().join(".meta/index.jsonl");
+        let lines = std::fs::read_to_string(&path).unwrap().lines().count();
+        assert!(lines < 1024, "{lines} lines");
+        let reopened = ImageIndex::open(tmp.path()).expect("reopen");
+        let refs = reopened.refs("aa").await;
+        assert_eq!(refs.len(), 2);
+        assert_eq!(refs[0].path, "/2024/05/aa.webp");
+        assert!(reopened.get("bb").await.is_none());
+        assert_eq!(reopened.usage("t1").await.files, 1);
+    }
+
+    #[tokio::test]
+    async fn quota_counts_survive_reopen() {
+        let tmp = tempfile::tempdir().expect("tmpdir");
+        let index = ImageIndex::open(tmp.path()).expect("open");
+        let quota = Quota {
+            max_bytes: Some(100),
+            max_files: Some(2),
+        };
+        assert_eq!(
+            index.insert(record("aa", "t1"), quota).await.unwrap(),
+            Insert::Added
+        );
+        assert_eq!(
+            index.insert(record("bb", "t1"), quota).await.unwrap(),
+            Insert::Added
+        );
+        assert_eq!(
+            index.insert(record("cc", "t1"), quota).await.unwrap(),
+            Insert::OverQuota
+        );
+        assert_eq!(
+            index.insert(record("cc", "t2"), quota).await.unwrap(),
+            Insert::Added
+        );
+
+        let reopened = ImageIndex::open(tmp.path()).expect("reopen");
+        assert_eq!(
+            reopened.usage("t1").await,
+            Usage {
+                bytes: 84,
+                files: 2
+            }
+        );
+        let removal = reopened.begin_remove("aa", None).await.unwrap();
+        reopened.remove(&removal).await.unwrap();
+        assert_eq!(
+            reopened.usage("t1").await,
+            Usage {
+                bytes: 42,
+                files: 1
+            }
+        );
+
+        let tight = Quota {
+            max_bytes: Some(50),
+            max_files: None,
+        };
+        assert_eq!(
+            reopened.insert(record("dd", "t1"), tight).await.unwrap(),
+            Insert::OverQuota
+        );
+    }
+}
@@ -1,101 +1,140 @@
+pub mod admin;
+pub mod api;
 pub mod auth;
+pub mod client_ip;
 pub mod config;
 pub mod error;
+pub mod fetch;
+pub mod format;
+pub mod index;
+pub mod metrics;
+pub mod rate_limit;
+pub mod s3;
+pub mod serve;
+pub mod session;
+pub mod storage;
+pub mod ticket;
 pub mod token;
+pub mod transcode;
 pub mod upload;
 pub mod webp;
 
-use std::{
-    collections::{HashMap, VecDeque},
-    net::SocketAddr,
-    sync::{Arc, Mutex},
-    time::{Duration, Instant},
-};
+use std::{net::SocketAddr, sync::Arc, time::Instant};
 
 use axum::{
     body::Body,
-    extract::{connect_info::ConnectInfo, DefaultBodyLimit, Request, State},
+    extract::{DefaultBodyLimit, Request, State},
     http::HeaderName,
     middleware,
     response::{IntoResponse, Response},
-    routing::{get, post},
-    Json, Router,
+    routing::{delete, get, patch, post, put},
+    Router,
 };
-use serde::Serialize;
 use tokio::sync::Semaphore;
 use tower_http::{
     request_id::{MakeRequestUuid, PropagateRequestIdLayer, SetRequestIdLayer},
     trace::TraceLayer,
 };
 
+pub use crate::metrics::Metrics;
+
 use crate::{
-    auth::auth_middleware, config::AppConfig, error::AppError, token::AuthorizedToken,
-    upload::upload_handler,
+    admin::{create_token, delete_token, list_tokens, update_token},
+    api::{create_upload_ticket, delete_image, list_images},
+    auth::{auth_middleware, redeem_ticket_middleware, require_scope},
+    client_ip::ClientIp,
+    config::AppConfig,
+    error::AppError,
+    fetch::Fetcher,
+    index::ImageIndex,
+    metrics::{metrics_handler, metrics_json_handler},
+    rate_limit::{Limit, RateLimiter},
+    serve::serve_image,
+    session::{
+        abort_session, append_chunk, complete_session, create_session, session_status,
+        UploadSessions,
+    },
+    storage::Storage,
+    ticket::Tickets,
+    token::{AuthorizedToken, Scope},
+    transcode::Transcoder,
+    upload::{upload_handler, upload_url_handler},
 };
 
 #[derive(Clone)]
 pub struct AppState {
     pub config: AppConfig,
     pub upload_semaphore: Arc<Semaphore>,
-    pub rate_limiter: SimpleRateLimiter,
+    pub rate_limiter: RateLimiter,
     pub token_store: crate::token::TokenStore,
     pub metrics: Arc<Metrics>,
+    pub transcoder: Transcoder,
+    pub index: Arc<ImageIndex>,
+    pub storage: Arc<dyn Storage>,
+    pub sessions: Arc<UploadSessions>,
+    pub fetcher: Fetcher,
+    /// Set when `UPLOAD_TICKET_SECRET` is configured.
+    pub tickets: Option<Arc<Tickets>>,
 }
 
-#[derive(Default)]
-pub struct Metrics {
-    pub upload_ok: std::sync::atomic::AtomicU64,
-    pub upload_fail: std::sync::atomic::AtomicU64,
-    pub upload_limited: std::sync::atomic::AtomicU64,
-}
-
-#[derive(Clone)]
-pub struct SimpleRateLimiter {
-    window: Duration,
-    inner: Arc<Mutex<HashMap<String, VecDeque<Instant>>>>,
-}
-
-impl SimpleRateLimiter {
-    pub fn new(window: Duration) -> Self {
+impl AppState {
+    pub fn new(
+        config: AppConfig,
+        token_store: crate::token::TokenStore,
+        index: ImageIndex,
+    ) -> Self {
         Self {
-            window,
-            inner: Arc::new(Mutex::new(HashMap::new())),
-        }
-    }
-
-    pub fn check(&self, key: String, max_requests: usize) -> bool {
-        let mut guard = self.inner.lock().expect("rate limiter poisoned");
-        let now = Instant::now();
-        let queue = guard.entry(key).or_default();
-
-        while let Some(front) = queue.front() {
-            if now.duration_since(*front) > self.window {
-                queue.pop_front();
-            } else {
-                break;
-            }
-        }
-
-        if queue.len() >= max_requests {
-            return false;
+            upload_semaphore: Arc::new(Semaphore::new(config.max_concurrent_uploads)),
+            rate_limiter: RateLimiter::new(),
+            token_store,
+            metrics: Arc::new(Metrics::new(config.metrics_token_label)),
+            transcoder: Transcoder::new(config.transcode_workers),
+            index: Arc::new(index),
+            storage: crate::storage::from_config(&config),
+            fetcher: Fetcher::new(&config),
+            sessions: Arc::new(UploadSessions::new(
+                &config.data_dir,
+                config.upload_session_ttl,
+                config.max_upload_sessions_per_token,
+            )),
+            tickets: config
+                .upload_ticket_secret
+                .as_ref()
+                .map(|secret| Arc::new(Tickets::new(secret.as_bytes(), &config.data_dir))),
+            config,
         }
-
-        queue.push_back(now);
-        true
     }
 }
 
-#[derive(Serialize)]
-struct MetricsResponse {
-    upload_ok: u64,
-    upload_fail: u64,
-    upload_limited: u64,
-}
-
 pub fn build_app(state: AppState) -> Router {
     let request_id_header = HeaderName::from_static("x-request-id");
+    // Multipart POSTs may carry `max_files_per_upload` files; raw PUTs and
+    // `/upload/url` carry one. A raw POST is cut off by the per-file limit
+    // while it is read.
+    let single_file = DefaultBodyLimit::max(state.config.max_upload_bytes + 1024 * 1024);
+    let multi_file = DefaultBodyLimit::max(
+        state
+            .config
+            .max_upload_bytes
+            .saturating_mul(state.config.max_files_per_upload)
+            .saturating_add(1024 * 1024),
+    );
     let protected = Router::new()
-        .route("/upload", post(upload_handler))
+        .route(
+            "/upload",
+            post(upload_handler)
+                .layer(multi_file)
+                .merge(put(upload_handler).layer(single_file)),
+        )
+        .route("/upload/url", post(upload_url_handler).layer(single_file))
+        .route_layer(middleware::from_fn_with_state(
+            state.clone(),
+            redeem_ticket_middleware,
+        ))
+        .route_layer(middleware::from_fn_with_state(
+            state.clone(),
+            upload_timing_middleware,
+        ))
         .route_layer(middleware::from_fn_with_state(
             state.clone(),
             concurrency_middleware,
@@ -104,6 +143,52 @@ pub fn build_app(state: AppState) -> Router {
             state.clone(),
             rate_limit_middleware,
         ))
+        .route_layer(middleware::from_fn_with_state(Scope::Upload, require_scope))
+        .route_layer(middleware::from_fn_with_state(
+            state.clone(),
+            auth_middleware,
+        ));
+
+    // Resumable uploads: only creating a session counts against the rate
+    // limit; sending chunks and completing take an upload slot.
+    let sessions = Router::new()
+        .route(
+            "/upload/sessions",
+            post(create_session).route_layer(middleware::from_fn_with_state(
+                state.clone(),
+                rate_limit_middleware,
+            )),
+        )
+        .route(
+            "/upload/sessions/{id}",
+            get(session_status)
+                .delete(abort_session)
+                .merge(
+                    patch(append_chunk).route_layer(middleware::from_fn_with_state(
+                        state.clone(),
+                        concurrency_middleware,
+                    )),
+                ),
+        )
+        .route(
+            "/upload/sessions/{id}/complete",
+            post(complete_session)
+                .route_layer(middleware::from_fn_with_state(
+                    state.clone(),
+                    upload_timing_middleware,
+                ))
+                .route_layer(middleware::from_fn_with_state(
+                    state.clone(),
+                    concurrency_middleware,
+                )),
+        )
+        // Sessions refuse tickets; burning one here keeps it from reaching
+        // the token's other sessions.
+        .route_layer(middleware::from_fn_with_state(
+            state.clone(),
+            redeem_ticket_middleware,
+        ))
+        .route_layer(middleware::from_fn_with_state(Scope::Upload, require_scope))
         .route_layer(middleware::from_fn_with_state(
             state.clone(),
             auth_middleware,
@@ -112,10 +197,67 @@ pub fn build_app(state: AppState) -> Router {
             state.config.max_upload_bytes + 1024 * 1024,
         ));
 
-    Router::new()
-        .route("/healthz", get(|| async { "ok" }))
+    let api = Router::new()
+        .route(
+            "/api/images",
+            get(list_images)
+                .route_layer(middleware::from_fn_with_state(Scope::List, require_scope)),
+        )
+        .route(
+            "/api/images/{sha256}",
+            delete(delete_image)
+                .route_layer(middleware::from_fn_with_state(Scope::Delete, require_scope)),
+        )
+        .route(
+            "/api/upload-tickets",
+            post(create_upload_ticket)
+                .route_layer(middleware::from_fn_with_state(Scope::Upload, require_scope)),
+        )
+        .route_layer(middleware::from_fn_with_state(
+            state.clone(),
+            auth_middleware,
+        ));
+
+    let admin = Router::new()
+        .route("/admin/tokens", get(list_tokens).post(create_token))
+        .route(
+            "/admin/tokens/{token_id}",
+            patch(update_token).delete(delete_token),
+        )
+        .route_layer(middleware::from_fn_with_state(Scope::Admin, require_scope))
+        .route_layer(middleware::from_fn_with_state(
+            state.clone(),
+            auth_middleware,
+        ));
+
+    let mut metrics = Router::new()
         .route("/metrics", get(metrics_handler))
+        .route("/metrics.json", get(metrics_json_handler));
+    if state.config.metrics_auth {
+        metrics = metrics
+            .route_layer(middleware::from_fn_with_state(
+                Scope::Metrics,
+                require_scope,
+            ))
+            .route_layer(middleware::from_fn_with_state(
+                state.clone(),
+                auth_middleware,
+            ));
+    }
+
+    let mut router = Router::new()
+        .route("/healthz", get(|| async { "ok" }))
+        .merge(metrics)
         .merge(protected)
+        .merge(sessions)
+        .merge(api)
+        .merge(admin);
+
+    if state.config.serve_images {
+        router = router.route("/images/{*key}", get(serve_image));
+    }
+
+    router
         .with_state(state)
         .layer(PropagateRequestIdLayer::new(request_id_header.clone()))
         .layer(SetRequestIdLayer::new(request_id_header, MakeRequestUuid))
@@ -128,14 +270,22 @@ pub fn with_connect_info(
     router.into_make_service_with_connect_info::<SocketAddr>()
 }
 
-async fn metrics_handler(State(state): State<AppState>) -> Json<MetricsResponse> {
-    use std::sync::atomic::Ordering;
+/// Name of the authenticated token, for metric labels.
+fn token_name(req: &Request<Body>) -> &str {
+    req.extensions()
+        .get::<AuthorizedToken>()
+        .map_or("", |auth| auth.name.as_str())
+}
 
-    Json(MetricsResponse {
-        upload_ok: state.metrics.upload_ok.load(Ordering::Relaxed),
-        upload_fail: state.metrics.upload_fail.load(Ordering::Relaxed),
-        upload_limited: state.metrics.upload_limited.load(Ordering::Relaxed),
-    })
+async fn upload_timing_middleware(
+    State(state): State<AppState>,
+    req: Request<Body>,
+    next: middleware::Next,
+) -> Response {
+    let started = Instant::now();
+    let resp = next.run(req).await;
+    state.metrics.observe_upload_latency(started.elapsed());
+    resp
 }
 
 async fn concurrency_middleware(
@@ -148,8 +298,7 @@ async fn concurrency_middleware(
         Err(_) => {
             state
                 .metrics
-                .upload_limited
-                .fetch_add(1, std::sync::atomic::Ordering::Relaxed);
+                .upload_limited(token_name(&req), "concurrency");
             AppError::TooManyRequests.into_response()
         }
     }
@@ -157,52 +306,33 @@ async fn concurrency_middleware(
 
 async fn rate_limit_middleware(
     State(state): State<AppState>,
+    ClientIp(ip): ClientIp,
     req: Request<Body>,
     next: middleware::Next,
 ) -> Response {
-    let ip = extract_ip(&req);
-    if !state
-        .rate_limiter
-        .check(format!("ip:{ip}"), state.config.rate_limit_per_minute)
-    {
-        state
-            .metrics
-            .upload_limited
-            .fetch_add(1, std::sync::atomic::Ordering::Relaxed);
-        return AppError::TooManyRequests.into_response();
+    // IPs and tokens get the same window's worth of burst.
+    let window = state.config.rate_limit_window;
+    let ip_key = format!("ip:{ip}");
+    let mut checks = vec![(
+        ip_key.as_str(),
+        Limit::per_window(state.config.rate_limit_per_minute, window),
+    )];
+    let token_key = req.extensions().get::<AuthorizedToken>().and_then(|auth| {
+        Some((
+            format!("token:{}", auth.token_id),
+            auth.rate_limit_per_minute?,
+        ))
+    });
+    if let Some((key, per_minute)) = &token_key {
+        checks.push((key.as_str(), Limit::per_window(*per_minute, window)));
     }
 
-    if let Some(auth) = req.extensions().get::<AuthorizedToken>() {
-        if let Some(per_token_limit) = auth.rate_limit_per_minute {
-            if !state
-                .rate_limiter
-                .check(format!("token:{}", auth.token_id), per_token_limit)
-            {
-                state
-                    .metrics
-                    .upload_limited
-                    .fetch_add(1, std::sync::atomic::Ordering::Relaxed);
-                return AppError::TooManyRequests.into_response();
-            }
-        }
+    let decision = state.rate_limiter.check_all(&checks);
+    if !decision.allowed {
+        state.metrics.upload_limited(token_name(&req), "rate_limit");
+        let mut resp = AppError::TooManyRequests.into_response();
+        decision.write_headers(resp.headers_mut());
+        return resp;
     }
     next.run(req).await
 }
-
-pub fn extract_ip(req: &Request<Body>) -> std::net::IpAddr {
-    if let Some(raw) = req.headers().get("x-forwarded-for") {
-        if let Ok(v) = raw.to_str() {
-            if let Some(first) = v.split(',').next() {
-                if let Ok(ip) = first.trim().parse::<std::net::IpAddr>() {
-                    return ip;
-                }
-            }
-        }
-    }
-
-    if let Some(ConnectInfo(addr)) = req.extensions().get::<ConnectInfo<SocketAddr>>() {
-        return addr.ip();
-    }
-
-    std::net::IpAddr::from([127, 0, 0, 1])
-}
@@ -1,12 +1,15 @@
-use std::{sync::Arc, time::Duration};
-
 use imgd::{
     build_app,
     config::AppConfig,
-    token::{token_cli, TokenStore},
-    with_connect_info, AppState, Metrics, SimpleRateLimiter,
+    index::ImageIndex,
+    rate_limit::evict_idle_keys,
+    session::expire_sessions,
+    token::{token_cli, watch_tokens, TokenStore},
+    with_connect_info, AppState,
 };
-use tokio::{net::TcpListener, sync::Semaphore};
+use std::{path::PathBuf, time::Duration};
+
+use tokio::net::TcpListener;
 use tracing_subscriber::{layer::SubscriberExt, util::SubscriberInitExt};
 
 #[tokio::main]
@@ -25,17 +28,28 @@ async fn main() -> Result<(), Box<dyn std::error::Error>> {
         .with(tracing_subscriber::fmt::layer())
         .init();
 
-    let config = AppConfig::from_env()?;
+    let config = AppConfig::load(config_path(&args[1..])?.as_deref())?;
     config.ensure_data_dir_ready()?;
     let token_store = TokenStore::from_config(&config)?;
 
-    let state = AppState {
-        upload_semaphore: Arc::new(Semaphore::new(config.max_concurrent_uploads)),
-        rate_limiter: SimpleRateLimiter::new(Duration::from_secs(60)),
-        token_store,
-        metrics: Arc::new(Metrics::default()),
-        config: config.clone(),
-    };
+    let index = ImageIndex::open(&config.data_dir)?;
+
+    let state = AppState::new(config.clone(), token_store, index);
+    tokio::spawn(watch_tokens(
+        state.token_store.clone(),
+        config.clone(),
+        state.metrics.clone(),
+    ));
+
+    tokio::spawn(evict_idle_keys(
+        state.rate_limiter.clone(),
+        config.rate_limit_window,
+    ));
+
+    tokio::spawn(expire_sessions(
+        state.sessions.clone(),
+        config.upload_session_ttl.min(Duration::from_secs(60 * 60)),
+    ));
 
     let listener = TcpListener::bind(&config.bind_addr).await?;
     tracing::info!(addr = %config.bind_addr, "imgd listening");
@@ -43,3 +57,21 @@ async fn main() -> Result<(), Box<dyn std::error::Error>> {
     axum::serve(listener, with_connect_info(build_app(state))).await?;
     Ok(())
 }
+
+/// Parses `--config <path>` (or `--config=<path>`), the only server flag.
+fn config_path(args: &[String]) -> Result<Option<PathBuf>, Box<dyn std::error::Error>> {
+    let mut path = None;
+    let mut iter = args.iter();
+    while let Some(arg) = iter.next() {
+        if arg == "--config" {
+            path = Some(PathBuf::from(
+                iter.next().ok_or("--config requires a path")?,
+            ));
+        } else if let Some(value) = arg.strip_prefix("--config=") {
+            path = Some(PathBuf::from(value));
+        } else {
+            return Err(format!("unknown argument: {arg}").into());
+        }
+    }
+    Ok(path)
+}
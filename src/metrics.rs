@@ -0,0 +1,350 @@
+use std::{
+    collections::BTreeMap,
+    fmt::Write as _,
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Mutex, PoisonError,
+    },
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
+
+use axum::{
+    extract::State,
+    http::header,
+    response::{IntoResponse, Response},
+    Json,
+};
+use serde::Serialize;
+
+use crate::AppState;
+
+const SIZE_BUCKETS: &[f64] = &[
+    16_384.0,
+    65_536.0,
+    262_144.0,
+    1_048_576.0,
+    4_194_304.0,
+    16_777_216.0,
+    67_108_864.0,
+];
+const LATENCY_BUCKETS: &[f64] = &[0.01, 0.025, 0.05, 0.1, 0.25, 0.5, 1.0, 2.5, 5.0, 10.0];
+
+/// Upload outcomes by `(result, reason, token)`; `reason` is empty on success
+/// and `token` is `None` unless the token label is enabled.
+type UploadKey = (&'static str, &'static str, Option<String>);
+
+pub struct Metrics {
+    pub upload_ok: AtomicU64,
+    pub upload_fail: AtomicU64,
+    pub upload_limited: AtomicU64,
+    pub delete_ok: AtomicU64,
+    pub delete_fail: AtomicU64,
+    pub token_reload_ok: AtomicU64,
+    pub token_reload_fail: AtomicU64,
+    uploads: Mutex<BTreeMap<UploadKey, u64>>,
+    /// Label uploads by token name. Every token ever seen stays a series,
+    /// so this is opt-in.
+    token_label: bool,
+    upload_bytes: Histogram,
+    upload_seconds: Histogram,
+    started_at: SystemTime,
+}
+
+impl Default for Metrics {
+    fn default() -> Self {
+        Self::new(false)
+    }
+}
+
+impl Metrics {
+    pub fn new(token_label: bool) -> Self {
+        Self {
+            upload_ok: AtomicU64::new(0),
+            upload_fail: AtomicU64::new(0),
+            upload_limited: AtomicU64::new(0),
+            delete_ok: AtomicU64::new(0),
+            delete_fail: AtomicU64::new(0),
+            token_reload_ok: AtomicU64::new(0),
+            token_reload_fail: AtomicU64::new(0),
+            uploads: Mutex::default(),
+            token_label,
+            upload_bytes: Histogram::new(SIZE_BUCKETS),
+            upload_seconds: Histogram::new(LATENCY_BUCKETS),
+            started_at: SystemTime::now(),
+        }
+    }
+
+    pub fn upload_succeeded(&self, token: &str, size: u64) {
+        self.upload_ok.fetch_add(1, Ordering::Relaxed);
+        self.upload_bytes.observe(size as f64);
+        self.count_upload("ok", "", token);
+    }
+
+    pub fn upload_failed(&self, token: &str, reason: &'static str) {
+        self.upload_fail.fetch_add(1, Ordering::Relaxed);
+        self.count_upload("fail", reason, token);
+    }
+
+    pub fn upload_limited(&self, token: &str, reason: &'static str) {
+        self.upload_limited.fetch_add(1, Ordering::Relaxed);
+        self.count_upload("limited", reason, token);
+    }
+
+    /// Time spent in the upload route, whatever the outcome.
+    pub fn observe_upload_latency(&self, elapsed: Duration) {
+        self.upload_seconds.observe(elapsed.as_secs_f64());
+    }
+
+    /// `token` is the authenticated token's name, or empty when the request
+    /// was turned away before authentication; that is labelled `none`.
+    fn count_upload(&self, result: &'static str, reason: &'static str, token: &str) {
+        let token = self.token_label.then(|| match token {
+            "" => "none".to_owned(),
+            name => name.to_owned(),
+        });
+        *self
+            .uploads
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner)
+            .entry((result, reason, token))
+            .or_default() += 1;
+    }
+}
+
+struct HistogramData {
+    /// Per-bucket (non-cumulative) counts, plus one for `+Inf`.
+    counts: Vec<u64>,
+    sum: f64,
+}
+
+struct Histogram {
+    bounds: &'static [f64],
+    data: Mutex<HistogramData>,
+}
+
+impl Histogram {
+    fn new(bounds: &'static [f64]) -> Self {
+        Self {
+            bounds,
+            data: Mutex::new(HistogramData {
+                counts: vec![0; bounds.len() + 1],
+                sum: 0.0,
+            }),
+        }
+    }
+
+    fn observe(&self, value: f64) {
+        let bucket = self
+            .bounds
+            .iter()
+            .position(|&bound| value <= bound)
+            .unwrap_or(self.bounds.len());
+        let mut data = self.data.lock().unwrap_or_else(PoisonError::into_inner);
+        data.counts[bucket] += 1;
+        data.sum += value;
+    }
+
+    fn render(&self, out: &mut String, name: &str, help: &str) {
+        let data = self.data.lock().unwrap_or_else(PoisonError::into_inner);
+        let _ = writeln!(out, "# HELP {name} {help}\n# TYPE {name} histogram");
+        let mut cumulative = 0;
+        for (bound, count) in self.bounds.iter().zip(&data.counts) {
+            cumulative += count;
+            let _ = writeln!(out, "{name}_bucket{{le=\"{bound}\"}} {cumulative}");
+        }
+        cumulative += data.counts[self.bounds.len()];
+        let _ = writeln!(out, "{name}_bucket{{le=\"+Inf\"}} {cumulative}");
+        let _ = writeln!(out, "{name}_sum {}", data.sum);
+        let _ = writeln!(out, "{name}_count {cumulative}");
+    }
+}
+
+/// Escapes a label value for the text exposition format.
+fn label(value: &str) -> String {
+    value
+        .replace('\\', "\\\\")
+        .replace('"', "\\\"")
+        .replace('\n', "\\n")
+}
+
+fn metric(out: &mut String, name: &str, kind: &str, help: &str, samples: &[(&str, u64)]) {
+    let _ = writeln!(out, "# HELP {name} {help}\n# TYPE {name} {kind}");
+    for (labels, value) in samples {
+        if labels.is_empty() {
+            let _ = writeln!(out, "{name} {value}");
+        } else {
+            let _ = writeln!(out, "{name}{{{labels}}} {value}");
+        }
+    }
+}
+
+/// `GET /metrics` in the Prometheus text exposition format.
+pub async fn metrics_handler(State(state): State<AppState>) -> Response {
+    let m = &state.metrics;
+    let load = |counter: &AtomicU64| counter.load(Ordering::Relaxed);
+    let mut out = String::new();
+
+    let _ = writeln!(
+        out,
+        "# HELP imgd_uploads_total Upload requests by outcome.\n# TYPE imgd_uploads_total counter"
+    );
+    for ((result, reason, token), count) in m
+        .uploads
+        .lock()
+        .unwrap_or_else(PoisonError::into_inner)
+        .iter()
+    {
+        let token = token
+            .as_deref()
+            .map(|token| format!(",token=\"{}\"", label(token)))
+            .unwrap_or_default();
+        let _ = writeln!(
+            out,
+            "imgd_uploads_total{{result=\"{result}\",reason=\"{reason}\"{token}}} {count}"
+        );
+    }
+    m.upload_bytes.render(
+        &mut out,
+        "imgd_upload_size_bytes",
+        "Size of stored uploads.",
+    );
+    m.upload_seconds.render(
+        &mut out,
+        "imgd_upload_duration_seconds",
+        "Time spent handling upload requests.",
+    );
+
+    let in_flight = state
+        .config
+        .max_concurrent_uploads
+        .saturating_sub(state.upload_semaphore.available_permits());
+    metric(
+        &mut out,
+        "imgd_uploads_in_flight",
+        "gauge",
+        "Uploads currently being processed.",
+        &[("", in_flight as u64)],
+    );
+    metric(
+        &mut out,
+        "imgd_deletes_total",
+        "counter",
+        "Delete requests by outcome.",
+        &[
+            ("result=\"ok\"", load(&m.delete_ok)),
+            ("result=\"fail\"", load(&m.delete_fail)),
+        ],
+    );
+    metric(
+        &mut out,
+        "imgd_token_reloads_total",
+        "counter",
+        "Token file reloads by outcome.",
+        &[
+            ("result=\"ok\"", load(&m.token_reload_ok)),
+            ("result=\"fail\"", load(&m.token_reload_fail)),
+        ],
+    );
+
+    let stored = state.index.totals().await;
+    metric(
+        &mut out,
+        "imgd_stored_bytes",
+        "gauge",
+        "Bytes of indexed images.",
+        &[("", stored.bytes)],
+    );
+    metric(
+        &mut out,
+        "imgd_stored_files",
+        "gauge",
+        "Number of indexed images.",
+        &[("", stored.files)],
+    );
+    metric(
+        &mut out,
+        "imgd_rate_limiter_keys",
+        "gauge",
+        "Clients currently tracked by the rate limiter.",
+        &[("", state.rate_limiter.len() as u64)],
+    );
+
+    let version = format!("version=\"{}\"", env!("CARGO_PKG_VERSION"));
+    metric(
+        &mut out,
+        "imgd_build_info",
+        "gauge",
+        "Always 1; labelled with the running version.",
+        &[(&version, 1)],
+    );
+    let started = m
+        .started_at
+        .duration_since(UNIX_EPOCH)
+        .map_or(0, |d| d.as_secs());
+    metric(
+        &mut out,
+        "process_start_time_seconds",
+        "gauge",
+        "Start time of the process since the Unix epoch.",
+        &[("", started)],
+    );
+
+    (
+        [(
+            header::CONTENT_TYPE,
+            "text/plain; version=0.0.4; charset=utf-8",
+        )],
+        out,
+    )
+        .into_response()
+}
+
+#[derive(Serialize)]
+pub struct MetricsResponse {
+    upload_ok: u64,
+    upload_fail: u64,
+    upload_limited: u64,
+    delete_ok: u64,
+    delete_fail: u64,
+    token_reload_ok: u64,
+    token_reload_fail: u64,
+}
+
+/// `GET /metrics.json`: the plain counters, for humans and existing scripts.
+pub async fn metrics_json_handler(State(state): State<AppState>) -> Json<MetricsResponse> {
+    let m = &state.metrics;
+    Json(MetricsResponse {
+        upload_ok: m.upload_ok.load(Ordering::Relaxed),
+        upload_fail: m.upload_fail.load(Ordering::Relaxed),
+        upload_limited: m.upload_limited.load(Ordering::Relaxed),
+        delete_ok: m.delete_ok.load(Ordering::Relaxed),
+        delete_fail: m.delete_fail.load(Ordering::Relaxed),
+        token_reload_ok: m.token_reload_ok.load(Ordering::Relaxed),
+        token_reload_fail: m.token_reload_fail.load(Ordering::Relaxed),
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{label, Histogram};
+
+    #[test]
+    fn histogram_buckets_are_cumulative() {
+        let histogram = Histogram::new(&[1.0, 10.0]);
+        for value in [0.5, 1.0, 5.0, 50.0] {
+            histogram.observe(value);
+        }
+        let mut out = String::new();
+        histogram.render(&mut out, "h", "Test.");
+        assert!(out.contains("h_bucket{le=\"1\"} 2\n"), "{out}");
+        assert!(out.contains("h_bucket{le=\"10\"} 3\n"), "{out}");
+        assert!(out.contains("h_bucket{le=\"+Inf\"} 4\n"), "{out}");
+        assert!(out.contains("h_sum 56.5\n"), "{out}");
+        assert!(out.contains("h_count 4\n"), "{out}");
+    }
+
+    #[test]
+    fn label_values_are_escaped() {
+        assert_eq!(label("a\"b\\c\nd"), "a\\\"b\\\\c\\nd");
+    }
+}
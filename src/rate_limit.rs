@@ -0,0 +1,394 @@
+use std::{
+    collections::{hash_map::RandomState, HashMap},
+    hash::BuildHasher,
+    sync::{Arc, Mutex, MutexGuard, PoisonError},
+    time::{Duration, Instant},
+};
+
+use axum::http::{HeaderMap, HeaderValue};
+
+const SHARDS: usize = 16;
+/// Keys tracked per shard before idle ones are dropped early.
+const MAX_KEYS_PER_SHARD: usize = 16_384;
+const MINUTE: Duration = Duration::from_secs(60);
+
+/// Largest limit or burst accepted from configuration and token policies.
+pub const MAX_LIMIT: usize = 1_000_000;
+/// Longest rate limit window accepted from configuration.
+pub const MAX_WINDOW: Duration = Duration::from_secs(24 * 60 * 60);
+
+/// `per_minute` requests a minute, of which up to `burst` may arrive at once.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Limit {
+    pub per_minute: usize,
+    pub burst: usize,
+}
+
+impl Limit {
+    /// A limit whose burst is what `per_minute` adds up to over `window`.
+    pub fn per_window(per_minute: usize, window: Duration) -> Self {
+        let burst = per_minute as u128 * window.as_nanos() / MINUTE.as_nanos();
+        Self {
+            per_minute,
+            burst: usize::try_from(burst).unwrap_or(usize::MAX).max(1),
+        }
+    }
+
+    /// The shortest window over which `per_minute` adds up to `burst`, the
+    /// inverse of [`Limit::per_window`].
+    pub fn window(&self) -> Duration {
+        let per_minute = self.per_minute.max(1) as u128;
+        let nanos = (self.burst as u128 * MINUTE.as_nanos()).div_ceil(per_minute);
+        Duration::from_nanos(u64::try_from(nanos).unwrap_or(u64::MAX))
+    }
+}
+
+/// Result of [`RateLimiter::check`], with the values for the `RateLimit-*`
+/// response headers.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Decision {
+    pub allowed: bool,
+    pub limit: usize,
+    pub remaining: usize,
+    /// Until the bucket is full again.
+    pub reset: Duration,
+    /// Until the next request would be allowed; zero when allowed.
+    pub retry_after: Duration,
+}
+
+impl Decision {
+    /// Adds `RateLimit-Limit`, `RateLimit-Remaining`, `RateLimit-Reset` and,
+    /// when rejected, `Retry-After`. Durations are rounded up to whole seconds.
+    pub fn write_headers(&self, headers: &mut HeaderMap) {
+        let secs = |d: Duration| HeaderValue::from(d.as_secs() + u64::from(d.subsec_nanos() > 0));
+        headers.insert("ratelimit-limit", HeaderValue::from(self.limit));
+        headers.insert("ratelimit-remaining", HeaderValue::from(self.remaining));
+        headers.insert("ratelimit-reset", secs(self.reset));
+        if !self.allowed {
+            headers.insert("retry-after", secs(self.retry_after));
+        }
+    }
+}
+
+/// GCRA limiter: each key stores only its theoretical arrival time (TAT), so
+/// a key whose TAT has passed holds a full bucket and can be forgotten. Keys
+/// are spread over independently locked shards.
+#[derive(Clone)]
+pub struct RateLimiter {
+    hasher: RandomState,
+    shards: Arc<[Mutex<HashMap<String, Instant>>]>,
+}
+
+impl RateLimiter {
+    pub fn new() -> Self {
+        Self {
+            hasher: RandomState::new(),
+            shards: (0..SHARDS).map(|_| Mutex::default()).collect(),
+        }
+    }
+
+    pub fn check(&self, key: &str, limit: Limit) -> Decision {
+        self.check_all(&[(key, limit)])
+    }
+
+    /// Charges every key only if all of them allow the request, so a request
+    /// one limit refuses costs nothing under the others. Returns the first
+    /// refusal, or the last key's decision.
+    pub fn check_all(&self, checks: &[(&str, Limit)]) -> Decision {
+        self.check_all_at(checks, Instant::now())
+    }
+
+    #[cfg(test)]
+    fn check_at(&self, key: &str, limit: Limit, now: Instant) -> Decision {
+        self.check_all_at(&[(key, limit)], now)
+    }
+
+    fn check_all_at(&self, checks: &[(&str, Limit)], now: Instant) -> Decision {
+        // Shards are locked in index order, so overlapping checks cannot
+        // deadlock.
+        let mut indexes: Vec<usize> = checks
+            .iter()
+            .map(|(key, _)| self.shard_index(key))
+            .collect();
+        indexes.sort_unstable();
+        indexes.dedup();
+        let mut shards: Vec<MutexGuard<'_, HashMap<String, Instant>>> = indexes
+            .iter()
+            .map(|&index| {
+                self.shards[index]
+                    .lock()
+                    .unwrap_or_else(PoisonError::into_inner)
+            })
+            .collect();
+        // Position of each key's shard among the locked ones.
+        let slot = |key: &str| {
+            indexes
+                .binary_search(&self.shard_index(key))
+                .expect("shard locked above")
+        };
+
+        let mut allowed = Vec::with_capacity(checks.len());
+        for &(key, limit) in checks {
+            let (decision, next_tat) = decide(shards[slot(key)].get(key).copied(), limit, now);
+            if !decision.allowed {
+                return decision;
+            }
+            allowed.push((key, decision, next_tat));
+        }
+
+        let mut last = Decision {
+            allowed: true,
+            limit: 0,
+            remaining: 0,
+            reset: Duration::ZERO,
+            retry_after: Duration::ZERO,
+        };
+        for (key, decision, next_tat) in allowed {
+            let shard = &mut *shards[slot(key)];
+            if !shard.contains_key(key) && shard.len() >= MAX_KEYS_PER_SHARD {
+                evict(shard, now);
+            }
+            shard.insert(key.to_owned(), next_tat);
+            last = decision;
+        }
+        last
+    }
+
+    /// Number of keys currently tracked.
+    pub fn len(&self) -> usize {
+        self.shards
+            .iter()
+            .map(|shard| shard.lock().unwrap_or_else(PoisonError::into_inner).len())
+            .sum()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    /// Drops keys whose bucket has refilled; returns how many remain.
+    pub fn evict_idle(&self) -> usize {
+        let now = Instant::now();
+        self.shards
+            .iter()
+            .map(|shard| {
+                let mut shard = shard.lock().unwrap_or_else(PoisonError::into_inner);
+                shard.retain(|_, tat| *tat > now);
+                shard.len()
+            })
+            .sum()
+    }
+
+    fn shard_index(&self, key: &str) -> usize {
+        self.hasher.hash_one(key) as usize % self.shards.len()
+    }
+}
+
+/// The GCRA step for one key whose stored TAT is `tat`: the decision, and
+/// the TAT to store if the request goes ahead.
+fn decide(tat: Option<Instant>, limit: Limit, now: Instant) -> (Decision, Instant) {
+    let limit = Limit {
+        per_minute: limit.per_minute.max(1),
+        burst: limit.burst.max(1),
+    };
+    // In nanoseconds so that no limit truncates to a zero divisor.
+    let interval = Duration::from_nanos(
+        u64::try_from(MINUTE.as_nanos() / limit.per_minute as u128)
+            .unwrap_or(u64::MAX)
+            .max(1),
+    );
+    let capacity = interval.saturating_mul(u32::try_from(limit.burst).unwrap_or(u32::MAX));
+
+    let tat = tat.unwrap_or(now).max(now);
+    let next_tat = tat + interval;
+    let allowed_at = next_tat.checked_sub(capacity).unwrap_or(now);
+
+    if now < allowed_at {
+        let decision = Decision {
+            allowed: false,
+            limit: limit.burst,
+            remaining: 0,
+            reset: tat - now,
+            retry_after: allowed_at - now,
+        };
+        return (decision, tat);
+    }
+
+    let headroom = capacity.saturating_sub(next_tat - now);
+    let decision = Decision {
+        allowed: true,
+        limit: limit.burst,
+        remaining: (headroom.as_nanos() / interval.as_nanos().max(1)) as usize,
+        reset: next_tat - now,
+        retry_after: Duration::ZERO,
+    };
+    (decision, next_tat)
+}
+
+impl Default for RateLimiter {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// Makes room in a full shard: idle keys go first, then the key closest to
+/// a full bucket.
+fn evict(shard: &mut HashMap<String, Instant>, now: Instant) {
+    shard.retain(|_, tat| *tat > now);
+    if shard.len() >= MAX_KEYS_PER_SHARD {
+        if let Some(key) = shard
+            .iter()
+            .min_by_key(|(_, tat)| **tat)
+            .map(|(key, _)| key.clone())
+        {
+            shard.remove(&key);
+        }
+    }
+}
+
+/// Periodically evicts idle keys so memory tracks active clients only.
+pub async fn evict_idle_keys(limiter: RateLimiter, every: Duration) {
+    let mut ticker = tokio::time::interval(every);
+    ticker.set_missed_tick_behavior(tokio::time::MissedTickBehavior::Delay);
+    loop {
+        ticker.tick().await;
+        let remaining = limiter.evict_idle();
+        tracing::debug!(remaining, "rate limiter keys evicted");
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::{Duration, Instant};
+
+    use super::{Limit, RateLimiter};
+
+    #[test]
+    fn burst_then_steady_rate() {
+        let limiter = RateLimiter::new();
+        let limit = Limit {
+            per_minute: 60,
+            burst: 3,
+        };
+        let start = Instant::now();
+
+        for remaining in [2, 1, 0] {
+            let d = limiter.check_at("ip:a", limit, start);
+            assert!(d.allowed);
+            assert_eq!(d.remaining, remaining);
+        }
+        let denied = limiter.check_at("ip:a", limit, start);
+        assert!(!denied.allowed);
+        assert_eq!(denied.retry_after, Duration::from_secs(1));
+        assert_eq!(denied.reset, Duration::from_secs(3));
+
+        // Other keys are independent.
+        assert!(limiter.check_at("ip:b", limit, start).allowed);
+
+        // One request's worth of capacity refills per second.
+        let later = start + Duration::from_secs(1);
+        assert!(limiter.check_at("ip:a", limit, later).allowed);
+        assert!(!limiter.check_at("ip:a", limit, later).allowed);
+    }
+
+    #[test]
+    fn huge_limits_do_not_overflow() {
+        let limiter = RateLimiter::new();
+        let start = Instant::now();
+        for limit in [1 << 32, usize::MAX] {
+            let limit = Limit {
+                per_minute: limit,
+                burst: limit,
+            };
+            assert!(limiter.check_at("k", limit, start).allowed);
+        }
+    }
+
+    #[test]
+    fn idle_keys_are_evicted() {
+        let limiter = RateLimiter::new();
+        // One request per 10 ms.
+        let limit = Limit {
+            per_minute: 6_000,
+            burst: 1,
+        };
+        assert!(limiter.check("ip:a", limit).allowed);
+        assert!(!limiter.check("ip:a", limit).allowed);
+        assert_eq!(limiter.evict_idle(), 1);
+        std::thread::sleep(Duration::from_millis(20));
+        assert_eq!(limiter.evict_idle(), 0);
+        assert!(limiter.check("ip:a", limit).allowed);
+    }
+
+    #[test]
+    fn headers_on_rejection() {
+        let limiter = RateLimiter::new();
+        let limit = Limit::per_window(2, Duration::from_secs(60));
+        let start = Instant::now();
+        limiter.check_at("k", limit, start);
+        limiter.check_at("k", limit, start);
+        let denied = limiter.check_at("k", limit, start);
+
+        let mut headers = axum::http::HeaderMap::new();
+        denied.write_headers(&mut headers);
+        assert_eq!(headers["retry-after"], "30");
+        assert_eq!(headers["ratelimit-limit"], "2");
+        assert_eq!(headers["ratelimit-remaining"], "0");
+        assert_eq!(headers["ratelimit-reset"], "60");
+    }
+
+    #[test]
+    fn window_sets_the_burst_not_the_rate() {
+        let limiter = RateLimiter::new();
+        let limit = Limit::per_window(60, Duration::from_secs(120));
+        assert_eq!(limit.burst, 120);
+        let start = Instant::now();
+        for _ in 0..120 {
+            assert!(limiter.check_at("k", limit, start).allowed);
+        }
+        let denied = limiter.check_at("k", limit, start);
+        assert_eq!(denied.retry_after, Duration::from_secs(1));
+
+        // A window under a minute still refills at the per-minute rate.
+        let limit = Limit::per_window(1, Duration::from_secs(10));
+        assert_eq!(limit.burst, 1);
+        assert!(limiter.check_at("j", limit, start).allowed);
+        let denied = limiter.check_at("j", limit, start);
+        assert_eq!(denied.retry_after, Duration::from_secs(60));
+    }
+
+    #[test]
+    fn a_refusal_charges_no_other_key() {
+        let limiter = RateLimiter::new();
+        let roomy = Limit::per_window(60, Duration::from_secs(60));
+        let tight = Limit::per_window(1, Duration::from_secs(60));
+        let start = Instant::now();
+
+        let checks = [("ip:a", roomy), ("token:t", tight)];
+        assert!(limiter.check_all_at(&checks, start).allowed);
+        for _ in 0..5 {
+            let denied = limiter.check_all_at(&checks, start);
+            assert!(!denied.allowed);
+            assert_eq!(denied.limit, 1);
+        }
+        // Only the first request was charged to the IP.
+        assert_eq!(limiter.check_at("ip:a", roomy, start).remaining, 58);
+    }
+
+    #[test]
+    fn window_is_the_inverse_of_per_window() {
+        for (per_minute, secs) in [(60, 60), (7, 10), (1_000_000, 86_400), (3, 1)] {
+            let window = Duration::from_secs(secs);
+            let limit = Limit::per_window(per_minute, window);
+            assert_eq!(
+                Limit::per_window(per_minute, limit.window()).burst,
+                limit.burst
+            );
+        }
+        let limit = Limit {
+            per_minute: 60,
+            burst: 120,
+        };
+        assert_eq!(limit.window(), Duration::from_secs(120));
+    }
+}
@@ -0,0 +1,416 @@
+use std::time::Instant;
+
+use axum::{
+    body::Body,
+    extract::{Path, Query, State},
+    http::{header, HeaderMap, HeaderValue, StatusCode},
+    response::Response,
+};
+use serde::Deserialize;
+use tracing::{error, info};
+
+use crate::{
+    error::AppError,
+    format::ImageFormat,
+    storage::Storage,
+    transcode::{self, Fit, TranscodeError, WebpEncoding},
+    AppState,
+};
+
+pub const CACHE_CONTROL: &str = "public, max-age=31536000, immutable";
+
+/// Longest accepted key prefix, in bytes.
+pub const MAX_PREFIX_LEN: usize = 64;
+/// Most `/`-separated segments a key prefix may have.
+const MAX_PREFIX_SEGMENTS: usize = 4;
+
+/// A validated `[<prefix>/]YYYY/MM/<sha256>.<ext>` storage key.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct StoredPath {
+    /// Set for uploads made with a ticket that fixes a prefix.
+    pub prefix: Option<String>,
+    pub year: String,
+    pub month: String,
+    pub sha256: String,
+    pub format: ImageFormat,
+}
+
+impl StoredPath {
+    /// Accepts only the exact layout written by the upload handler, which
+    /// rules out `..`, separators and any other path tricks.
+    pub fn parse(year: &str, month: &str, file: &str) -> Option<Self> {
+        let (sha256, ext) = file.split_once('.')?;
+        let format = ImageFormat::from_extension(ext)?;
+        let valid = year.len() == 4
+            && year.bytes().all(|b| b.is_ascii_digit())
+            && matches!(month.parse::<u8>(), Ok(1..=12))
+            && month.len() == 2
+            && is_sha256_hex(sha256)
+            && ext == format.extension();
+        valid.then(|| Self {
+            prefix: None,
+            year: year.to_owned(),
+            month: month.to_owned(),
+            sha256: sha256.to_owned(),
+            format,
+        })
+    }
+
+    /// Parses a whole key as written by the upload handler, with or without
+    /// a leading `/`.
+    pub fn from_key(key: &str) -> Option<Self> {
+        let mut parts = key.strip_prefix('/').unwrap_or(key).rsplitn(4, '/');
+        let (file, month, year) = (parts.next()?, parts.next()?, parts.next()?);
+        let prefix = parts.next();
+        if prefix.is_some_and(|p| !is_valid_prefix(p)) {
+            return None;
+        }
+        Self::parse(year, month, file).map(|stored| Self {
+            prefix: prefix.map(str::to_owned),
+            ..stored
+        })
+    }
+
+    pub fn file_name(&self) -> String {
+        format!("{}.{}", self.sha256, self.format.extension())
+    }
+
+    pub fn key(&self) -> String {
+        self.sibling(&self.file_name())
+    }
+
+    /// Key of another object in the same month, such as a cached variant.
+    pub fn sibling(&self, name: &str) -> String {
+        match &self.prefix {
+            Some(prefix) => format!("{prefix}/{}/{}/{name}", self.year, self.month),
+            None => format!("{}/{}/{name}", self.year, self.month),
+        }
+    }
+}
+
+/// A relative key prefix such as `avatars/2f1c`: up to four segments of
+/// lowercase ASCII letters, digits, `-` and `_`, so it can never climb out of
+/// the image root or reach the dot-directories next to the images.
+pub fn is_valid_prefix(prefix: &str) -> bool {
+    prefix.len() <= MAX_PREFIX_LEN
+        && prefix.split('/').count() <= MAX_PREFIX_SEGMENTS
+        && prefix.split('/').all(|segment| {
+            !segment.is_empty()
+                && segment
+                    .bytes()
+                    .all(|b| matches!(b, b'a'..=b'z' | b'0'..=b'9' | b'-' | b'_'))
+        })
+}
+
+pub fn is_sha256_hex(s: &str) -> bool {
+    s.len() == 64 && s.bytes().all(|b| matches!(b, b'0'..=b'9' | b'a'..=b'f'))
+}
+
+/// Optional resize parameters, e.g. `?w=320&h=240&fit=cover`.
+#[derive(Debug, Default, Deserialize)]
+pub struct VariantQuery {
+    pub w: Option<u32>,
+    pub h: Option<u32>,
+    pub fit: Option<String>,
+}
+
+impl VariantQuery {
+    fn is_empty(&self) -> bool {
+        self.w.is_none() && self.h.is_none() && self.fit.is_none()
+    }
+
+    /// Resolves the request against the configured size whitelist so
+    /// arbitrary sizes cannot be used to fill the disk cache.
+    fn resolve(&self, allowed: &[(u32, u32)]) -> Option<((u32, u32), Fit)> {
+        let size = (self.w.unwrap_or(0), self.h.unwrap_or(0));
+        let fit = match self.fit.as_deref() {
+            Some(raw) => Fit::parse(raw)?,
+            None => Fit::Contain,
+        };
+        allowed.contains(&size).then_some((size, fit))
+    }
+}
+
+/// Cached variants live next to the original as
+/// `<sha256>_<w>x<h>_<fit>.webp`, a name [`StoredPath::parse`] never accepts,
+/// so they are only reachable through the query form.
+fn variant_key(sha256: &str, (w, h): (u32, u32), fit: Fit) -> String {
+    format!("{sha256}_{w}x{h}_{}", fit.as_str())
+}
+
+pub async fn serve_image(
+    State(state): State<AppState>,
+    Path(key): Path<String>,
+    Query(query): Query<VariantQuery>,
+    headers: HeaderMap,
+) -> Result<Response, AppError> {
+    let stored = StoredPath::from_key(&key).ok_or(AppError::NotFound)?;
+    let original = stored.key();
+
+    if query.is_empty() {
+        return serve_file(
+            state.storage.as_ref(),
+            &original,
+            stored.format.content_type(),
+            &stored.sha256,
+            &headers,
+        )
+        .await;
+    }
+
+    let (size, fit) = query
+        .resolve(&state.config.variant_sizes)
+        .ok_or(AppError::BadRequest)?;
+    let key = variant_key(&stored.sha256, size, fit);
+    let variant = stored.sibling(&format!("{key}.webp"));
+
+    if !state.storage.exists(&variant).await? {
+        render_variant(&state, &stored, &original, &variant, size, fit).await?;
+    }
+
+    serve_file(
+        state.storage.as_ref(),
+        &variant,
+        ImageFormat::Webp.content_type(),
+        &key,
+        &headers,
+    )
+    .await
+}
+
+async fn render_variant(
+    state: &AppState,
+    stored: &StoredPath,
+    original: &str,
+    variant: &str,
+    size: (u32, u32),
+    fit: Fit,
+) -> Result<(), AppError> {
+    let data = state
+        .storage
+        .get(original)
+        .await?
+        .ok_or(AppError::NotFound)?;
+
+    let format = stored.format;
+    let encoding = WebpEncoding::Lossy {
+        quality: state.config.webp_quality,
+    };
+    let started = Instant::now();
+    let rendered = match state
+        .transcoder
+        .run(move || transcode::resize_to_webp(&data, format, size, fit, encoding))
+        .await
+    {
+        Ok(bytes) => bytes,
+        Err(TranscodeError::Unsupported(_)) => return Err(AppError::UnsupportedMediaType),
+        Err(err) => {
+            error!(sha256 = %stored.sha256, error = %err, "variant render failed");
+            return Err(AppError::Internal);
+        }
+    };
+
+    // Concurrent requests may render the same variant; they produce the
+    // same bytes, so whichever store lands first is kept.
+    let bytes = rendered.len();
+    state.storage.put_if_absent(variant, rendered).await?;
+
+    info!(
+        sha256 = %stored.sha256,
+        width = size.0,
+        height = size.1,
+        fit = fit.as_str(),
+        bytes,
+        elapsed_ms = started.elapsed().as_millis(),
+        "variant rendered"
+    );
+    Ok(())
+}
+
+async fn serve_file(
+    storage: &dyn Storage,
+    key: &str,
+    content_type: &'static str,
+    tag: &str,
+    headers: &HeaderMap,
+) -> Result<Response, AppError> {
+    let len = storage.size(key).await?.ok_or(AppError::NotFound)?;
+    let etag = format!("\"{tag}\"");
+
+    let mut builder = Response::builder()
+        .header(header::ETAG, &etag)
+        .header(header::CACHE_CONTROL, CACHE_CONTROL)
+        .header(header::ACCEPT_RANGES, "bytes");
+
+    if let Some(value) = headers.get(header::IF_NONE_MATCH) {
+        if etag_matches(value, tag) {
+            return builder
+                .status(StatusCode::NOT_MODIFIED)
+                .body(Body::empty())
+                .map_err(|_| AppError::Internal);
+        }
+    }
+
+    builder = builder.header(header::CONTENT_TYPE, content_type);
+
+    let range = headers
+        .get(header::RANGE)
+        .filter(|_| {
+            headers
+                .get(header::IF_RANGE)
+                .is_none_or(|v| etag_matches(v, tag))
+        })
+        .and_then(|v| v.to_str().ok())
+        .map(|v| parse_range(v, len));
+
+    let (status, start, count) = match range {
+        Some(Ok((start, end))) => {
+            builder = builder.header(header::CONTENT_RANGE, format!("bytes {start}-{end}/{len}"));
+            (StatusCode::PARTIAL_CONTENT, start, end - start + 1)
+        }
+        Some(Err(RangeError::Unsatisfiable)) => {
+            return builder
+                .status(StatusCode::RANGE_NOT_SATISFIABLE)
+                .header(header::CONTENT_RANGE, format!("bytes */{len}"))
+                .body(Body::empty())
+                .map_err(|_| AppError::Internal);
+        }
+        Some(Err(RangeError::Unsupported)) | None => (StatusCode::OK, 0, len),
+    };
+
+    let body = storage
+        .stream(key, start, count)
+        .await?
+        .ok_or(AppError::NotFound)?;
+
+    builder
+        .status(status)
+        .header(header::CONTENT_LENGTH, count)
+        .body(body)
+        .map_err(|_| AppError::Internal)
+}
+
+/// `If-None-Match` uses weak comparison, so `W/"<sha>"` and `*` match too.
+fn etag_matches(value: &HeaderValue, tag: &str) -> bool {
+    let Ok(raw) = value.to_str() else {
+        return false;
+    };
+    raw.split(',').map(str::trim).any(|candidate| {
+        candidate == "*" || candidate.trim_start_matches("W/").trim_matches('"') == tag
+    })
+}
+
+#[derive(Debug, PartialEq, Eq)]
+enum RangeError {
+    /// Syntactically valid but outside the file; answered with 416.
+    Unsatisfiable,
+    /// Malformed or multi-range requests; answered with the full body.
+    Unsupported,
+}
+
+/// Parses a single `bytes=` range into an inclusive `(start, end)` pair.
+fn parse_range(value: &str, len: u64) -> Result<(u64, u64), RangeError> {
+    let spec = value
+        .trim()
+        .strip_prefix("bytes=")
+        .ok_or(RangeError::Unsupported)?;
+    if spec.contains(',') {
+        return Err(RangeError::Unsupported);
+    }
+    let (first, last) = spec.split_once('-').ok_or(RangeError::Unsupported)?;
+    let (first, last) = (first.trim(), last.trim());
+
+    if first.is_empty() {
+        let suffix: u64 = last.parse().map_err(|_| RangeError::Unsupported)?;
+        if suffix == 0 || len == 0 {
+            return Err(RangeError::Unsatisfiable);
+        }
+        return Ok((len.saturating_sub(suffix), len - 1));
+    }
+
+    let start: u64 = first.parse().map_err(|_| RangeError::Unsupported)?;
+    let end = if last.is_empty() {
+        len.saturating_sub(1)
+    } else {
+        let end: u64 = last.parse().map_err(|_| RangeError::Unsupported)?;
+        if end < start {
+            return Err(RangeError::Unsupported);
+        }
+        end.min(len.saturating_sub(1))
+    };
+    if start >= len {
+        return Err(RangeError::Unsatisfiable);
+    }
+    Ok((start, end))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{is_valid_prefix, parse_range, RangeError, StoredPath};
+    use crate::format::ImageFormat;
+
+    const SHA: &str = "9f86d081884c7d659a2feaa0c55ad015a3bf4f1b2b0b822cd15d6c15b0f00a08";
+
+    #[test]
+    fn stored_path_accepts_upload_layout_only() {
+        let ok = StoredPath::parse("2024", "03", &format!("{SHA}.webp")).expect("valid");
+        assert_eq!(ok.format, ImageFormat::Webp);
+
+        assert!(StoredPath::parse("2024", "13", &format!("{SHA}.webp")).is_none());
+        assert!(StoredPath::parse("24", "03", &format!("{SHA}.webp")).is_none());
+        assert!(StoredPath::parse("2024", "03", &format!("{SHA}.jpeg")).is_none());
+        assert!(StoredPath::parse("2024", "03", &format!("{SHA}.webp.txt")).is_none());
+        assert!(StoredPath::parse("2024", "03", "..%2f..%2fetc%2fpasswd").is_none());
+        assert!(StoredPath::parse("..", "03", &format!("{SHA}.webp")).is_none());
+        assert!(StoredPath::parse("2024", "03", &format!("{}.webp", SHA.to_uppercase())).is_none());
+    }
+
+    #[test]
+    fn keys_with_and_without_prefix() {
+        let plain = StoredPath::from_key(&format!("/2024/03/{SHA}.webp")).expect("valid");
+        assert_eq!(plain.prefix, None);
+        assert_eq!(plain.key(), format!("2024/03/{SHA}.webp"));
+
+        let key = format!("avatars/u-1/2024/03/{SHA}.webp");
+        let prefixed = StoredPath::from_key(&key).expect("valid");
+        assert_eq!(prefixed.prefix.as_deref(), Some("avatars/u-1"));
+        assert_eq!(prefixed.key(), key);
+        assert_eq!(prefixed.sibling("x.webp"), "avatars/u-1/2024/03/x.webp");
+
+        for bad in [
+            format!("../2024/03/{SHA}.webp"),
+            format!(".meta/2024/03/{SHA}.webp"),
+            format!("a//2024/03/{SHA}.webp"),
+            format!("A/2024/03/{SHA}.webp"),
+            format!("a/b/c/d/e/2024/03/{SHA}.webp"),
+            format!("2024/03/{SHA}"),
+        ] {
+            assert!(StoredPath::from_key(&bad).is_none(), "{bad}");
+        }
+
+        assert!(is_valid_prefix("avatars"));
+        assert!(is_valid_prefix("a/b_c/d-1/e"));
+        for bad in ["", "/a", "a/", "a/../b", "a.b", "a b", &"a".repeat(65)] {
+            assert!(!is_valid_prefix(bad), "{bad}");
+        }
+    }
+
+    #[test]
+    fn range_forms() {
+        assert_eq!(parse_range("bytes=0-9", 100), Ok((0, 9)));
+        assert_eq!(parse_range("bytes=90-", 100), Ok((90, 99)));
+        assert_eq!(parse_range("bytes=-10", 100), Ok((90, 99)));
+        assert_eq!(parse_range("bytes=50-500", 100), Ok((50, 99)));
+        assert_eq!(parse_range("bytes=-500", 100), Ok((0, 99)));
+        assert_eq!(
+            parse_range("bytes=100-", 100),
+            Err(RangeError::Unsatisfiable)
+        );
+        assert_eq!(
+            parse_range("bytes=0-1,5-6", 100),
+            Err(RangeError::Unsupported)
+        );
+        assert_eq!(parse_range("items=0-1", 100), Err(RangeError::Unsupported));
+        assert_eq!(parse_range("bytes=9-1", 100), Err(RangeError::Unsupported));
+    }
+}
@@ -0,0 +1,594 @@
+use std::{
+    collections::HashSet,
+    io,
+    path::{Path, PathBuf},
+    sync::{Arc, Mutex, PoisonError},
+    time::{Duration, Instant},
+};
+
+use axum::{
+    body::Body,
+    extract::{Path as UrlPath, Query, State},
+    http::{HeaderMap, HeaderValue, StatusCode},
+    response::{IntoResponse, Response},
+    Extension, Json,
+};
+use chrono::{DateTime, TimeDelta, Utc};
+use futures_util::StreamExt;
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use tokio::{
+    fs::{self, OpenOptions},
+    io::AsyncWriteExt,
+};
+use tracing::{error, info, warn};
+use uuid::Uuid;
+
+use crate::{
+    client_ip::ClientIp,
+    error::AppError,
+    format::ImageFormat,
+    serve::is_sha256_hex,
+    ticket::TicketClaims,
+    token::AuthorizedToken,
+    upload::{process, UploadContext, UploadParams},
+    AppState,
+};
+
+const OFFSET_HEADER: &str = "upload-offset";
+
+/// A resumable upload in progress, stored as `<id>.json` next to the
+/// received bytes in `<id>.part`.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Session {
+    pub id: String,
+    pub token_id: String,
+    pub filename: String,
+    /// Declared total size in bytes.
+    pub size: u64,
+    /// Declared digest of the complete file, checked before processing.
+    pub sha256: String,
+    pub created_at: DateTime<Utc>,
+    /// Pushed back by every chunk; abandoned sessions are swept after it.
+    pub expires_at: DateTime<Utc>,
+}
+
+/// Resumable upload sessions under `<data_dir>/.tmp/sessions`. State lives
+/// on disk so sessions survive restarts; only the per-request locks are in
+/// memory.
+pub struct UploadSessions {
+    dir: PathBuf,
+    ttl: Duration,
+    max_per_token: usize,
+    busy: Mutex<HashSet<String>>,
+    /// Serializes creation so the per-token cap cannot be raced past.
+    creating: tokio::sync::Mutex<()>,
+}
+
+/// Exclusive access to a session for one request; released on drop.
+pub struct Lease<'a> {
+    sessions: &'a UploadSessions,
+    pub session: Session,
+}
+
+impl UploadSessions {
+    pub fn new(data_dir: &Path, ttl: Duration, max_per_token: usize) -> Self {
+        Self {
+            dir: data_dir.join(".tmp").join("sessions"),
+            ttl,
+            max_per_token,
+            busy: Mutex::default(),
+            creating: tokio::sync::Mutex::default(),
+        }
+    }
+
+    fn meta_path(&self, id: &str) -> PathBuf {
+        self.dir.join(format!("{id}.json"))
+    }
+
+    fn part_path(&self, id: &str) -> PathBuf {
+        self.dir.join(format!("{id}.part"))
+    }
+
+    fn expiry(&self) -> DateTime<Utc> {
+        Utc::now() + TimeDelta::from_std(self.ttl).unwrap_or(TimeDelta::MAX)
+    }
+
+    /// Starts a session; a token already holding the maximum number of
+    /// live sessions gets 429.
+    pub async fn create(
+        &self,
+        token_id: &str,
+        filename: &str,
+        size: u64,
+        sha256: &str,
+    ) -> Result<Session, AppError> {
+        let _creating = self.creating.lock().await;
+        fs::create_dir_all(&self.dir).await?;
+        if self.open_sessions(token_id).await? >= self.max_per_token {
+            return Err(AppError::TooManyRequests);
+        }
+        let session = Session {
+            id: Uuid::new_v4().simple().to_string(),
+            token_id: token_id.to_owned(),
+            filename: filename.to_owned(),
+            size,
+            sha256: sha256.to_owned(),
+            created_at: Utc::now(),
+            expires_at: self.expiry(),
+        };
+        // The part file comes first, so a crash leaves at most an orphaned
+        // part for the sweeper rather than metadata without data.
+        fs::write(self.part_path(&session.id), b"").await?;
+        self.save(&session).await?;
+        Ok(session)
+    }
+
+    /// Live sessions held by `token_id`.
+    async fn open_sessions(&self, token_id: &str) -> io::Result<usize> {
+        let mut entries = fs::read_dir(&self.dir).await?;
+        let now = Utc::now();
+        let mut open = 0;
+        while let Some(entry) = entries.next_entry().await? {
+            let path = entry.path();
+            if path.extension().and_then(|e| e.to_str()) != Some("json") {
+                continue;
+            }
+            // Sessions completing meanwhile vanish; they no longer count.
+            let Ok(raw) = fs::read(&path).await else {
+                continue;
+            };
+            if serde_json::from_slice::<Session>(&raw)
+                .is_ok_and(|s| s.token_id == token_id && s.expires_at > now)
+            {
+                open += 1;
+            }
+        }
+        Ok(open)
+    }
+
+    async fn save(&self, session: &Session) -> io::Result<()> {
+        let path = self.meta_path(&session.id);
+        let tmp = path.with_extension("json.tmp");
+        fs::write(&tmp, serde_json::to_vec(session)?).await?;
+        fs::rename(&tmp, &path).await
+    }
+
+    /// The caller's live session `id`; sessions of other tokens look absent.
+    pub async fn get(&self, id: &str, token_id: &str) -> Result<Session, AppError> {
+        // Ids are generated hex, which also keeps them from naming other paths.
+        if id.len() != 32 || !id.bytes().all(|b| b.is_ascii_hexdigit()) {
+            return Err(AppError::NotFound);
+        }
+        let raw = match fs::read(self.meta_path(id)).await {
+            Ok(raw) => raw,
+            Err(err) if err.kind() == io::ErrorKind::NotFound => return Err(AppError::NotFound),
+            Err(err) => return Err(err.into()),
+        };
+        let session: Session = serde_json::from_slice(&raw).map_err(|_| AppError::NotFound)?;
+        if session.token_id != token_id || session.expires_at <= Utc::now() {
+            return Err(AppError::NotFound);
+        }
+        Ok(session)
+    }
+
+    /// Locks the session against concurrent chunks; a second request while
+    /// one is in flight gets 409.
+    pub async fn lease(&self, id: &str, token_id: &str) -> Result<Lease<'_>, AppError> {
+        if !self.lock().insert(id.to_owned()) {
+            return Err(AppError::Conflict);
+        }
+        let release = |err| {
+            self.lock().remove(id);
+            err
+        };
+        let session = self.get(id, token_id).await.map_err(release)?;
+        Ok(Lease {
+            sessions: self,
+            session,
+        })
+    }
+
+    /// Whether `path` was last written more than a TTL ago.
+    async fn is_stale(&self, path: &Path) -> bool {
+        fs::metadata(path)
+            .await
+            .and_then(|meta| meta.modified())
+            .is_ok_and(|modified| modified.elapsed().is_ok_and(|age| age > self.ttl))
+    }
+
+    fn lock(&self) -> std::sync::MutexGuard<'_, HashSet<String>> {
+        self.busy.lock().unwrap_or_else(PoisonError::into_inner)
+    }
+
+    async fn remove_files(&self, id: &str) -> io::Result<()> {
+        for path in [self.meta_path(id), self.part_path(id)] {
+            match fs::remove_file(path).await {
+                Ok(()) => {}
+                Err(err) if err.kind() == io::ErrorKind::NotFound => {}
+                Err(err) => return Err(err),
+            }
+        }
+        Ok(())
+    }
+
+    /// Deletes expired sessions, and part files and metadata writes left
+    /// behind by crashes; returns how many sessions were removed.
+    pub async fn expire(&self) -> io::Result<usize> {
+        let mut entries = match fs::read_dir(&self.dir).await {
+            Ok(entries) => entries,
+            Err(err) if err.kind() == io::ErrorKind::NotFound => return Ok(0),
+            Err(err) => return Err(err),
+        };
+        let now = Utc::now();
+        let mut removed = 0;
+        while let Some(entry) = entries.next_entry().await? {
+            let path = entry.path();
+            let Some(id) = path.file_stem().and_then(|s| s.to_str()).map(str::to_owned) else {
+                continue;
+            };
+            // Entries can vanish mid-sweep when a session completes; those
+            // are simply skipped.
+            let expired = match path.extension().and_then(|e| e.to_str()) {
+                Some("json") => match fs::read(&path).await {
+                    Ok(raw) => serde_json::from_slice::<Session>(&raw)
+                        .map_or(true, |session| session.expires_at <= now),
+                    Err(_) => false,
+                },
+                Some("part") => {
+                    !fs::try_exists(self.meta_path(&id)).await? && self.is_stale(&path).await
+                }
+                // `<id>.json.tmp`, from a metadata write that never got
+                // renamed into place.
+                Some("tmp") => {
+                    if self.is_stale(&path).await {
+                        match fs::remove_file(&path).await {
+                            Ok(()) => {}
+                            Err(err) if err.kind() == io::ErrorKind::NotFound => {}
+                            Err(err) => return Err(err),
+                        }
+                    }
+                    false
+                }
+                _ => false,
+            };
+            if expired && self.lock().insert(id.clone()) {
+                let result = self.remove_files(&id).await;
+                self.lock().remove(&id);
+                result?;
+                removed += 1;
+            }
+        }
+        Ok(removed)
+    }
+}
+
+impl Lease<'_> {
+    pub fn part_path(&self) -> PathBuf {
+        self.sessions.part_path(&self.session.id)
+    }
+
+    /// Bytes received so far.
+    pub async fn offset(&self) -> io::Result<u64> {
+        Ok(fs::metadata(self.part_path()).await?.len())
+    }
+
+    /// Extends the session's lifetime after activity.
+    pub async fn touch(&mut self) -> io::Result<()> {
+        self.session.expires_at = self.sessions.expiry();
+        self.sessions.save(&self.session).await
+    }
+
+    pub async fn remove(self) -> io::Result<()> {
+        self.sessions.remove_files(&self.session.id).await
+    }
+}
+
+impl Drop for Lease<'_> {
+    fn drop(&mut self) {
+        self.sessions.lock().remove(&self.session.id);
+    }
+}
+
+/// Periodically removes abandoned sessions.
+pub async fn expire_sessions(sessions: Arc<UploadSessions>, every: Duration) {
+    let mut ticker = tokio::time::interval(every);
+    ticker.set_missed_tick_behavior(tokio::time::MissedTickBehavior::Delay);
+    loop {
+        ticker.tick().await;
+        match sessions.expire().await {
+            Ok(0) => {}
+            Ok(removed) => info!(removed, "expired upload sessions removed"),
+            Err(err) => error!(error = %err, "upload session sweep failed"),
+        }
+    }
+}
+
+#[derive(Debug, Deserialize)]
+pub struct CreateSessionRequest {
+    pub filename: String,
+    pub size: u64,
+    pub sha256: String,
+}
+
+#[derive(Serialize)]
+pub struct SessionInfo {
+    pub id: String,
+    pub filename: String,
+    pub size: u64,
+    pub offset: u64,
+    pub expires_at: DateTime<Utc>,
+    /// Where to PATCH chunks and GET progress, relative to the imgd origin.
+    pub upload_url: String,
+}
+
+impl SessionInfo {
+    fn new(session: &Session, offset: u64) -> Self {
+        Self {
+            id: session.id.clone(),
+            filename: session.filename.clone(),
+            size: session.size,
+            offset,
+            expires_at: session.expires_at,
+            upload_url: format!("/upload/sessions/{}", session.id),
+        }
+    }
+}
+
+/// An empty response carrying the current `Upload-Offset`.
+fn with_offset(status: StatusCode, offset: u64) -> Response {
+    (status, [(OFFSET_HEADER, HeaderValue::from(offset))]).into_response()
+}
+
+/// `POST /upload/sessions`
+pub async fn create_session(
+    State(state): State<AppState>,
+    Extension(auth): Extension<AuthorizedToken>,
+    redeemed: Option<Extension<TicketClaims>>,
+    Json(req): Json<CreateSessionRequest>,
+) -> Result<(StatusCode, Json<SessionInfo>), AppError> {
+    // Tickets are single-use, so they cannot authorize the follow-up chunks.
+    if redeemed.is_some() {
+        return Err(AppError::Forbidden);
+    }
+    let allowed = &state.config.allowed_formats;
+    if !ImageFormat::from_filename(&req.filename).is_some_and(|f| allowed.contains(&f)) {
+        return Err(AppError::UnsupportedMediaType);
+    }
+    let sha256 = req.sha256.to_ascii_lowercase();
+    if req.size == 0 || !is_sha256_hex(&sha256) {
+        return Err(AppError::BadRequest);
+    }
+    let max_bytes = auth
+        .max_upload_bytes
+        .map_or(state.config.max_upload_bytes, |limit| {
+            limit.min(state.config.max_upload_bytes)
+        }) as u64;
+    if req.size > max_bytes {
+        return Err(AppError::FileTooLarge);
+    }
+    // Refused now rather than after the whole file has been sent.
+    if !state
+        .index
+        .admits(&auth.token_id, &sha256, req.size, auth.quota)
+        .await
+    {
+        return Err(AppError::QuotaExceeded);
+    }
+
+    let session = state
+        .sessions
+        .create(&auth.token_id, &req.filename, req.size, &sha256)
+        .await?;
+    info!(token = %auth.name, session = %session.id, size = session.size, "upload session created");
+    Ok((StatusCode::CREATED, Json(SessionInfo::new(&session, 0))))
+}
+
+/// `GET /upload/sessions/{id}`; `HEAD` returns just the `Upload-Offset`.
+pub async fn session_status(
+    State(state): State<AppState>,
+    Extension(auth): Extension<AuthorizedToken>,
+    UrlPath(id): UrlPath<String>,
+) -> Result<Response, AppError> {
+    let session = state.sessions.get(&id, &auth.token_id).await?;
+    let offset = match fs::metadata(state.sessions.part_path(&id)).await {
+        Ok(meta) => meta.len(),
+        Err(err) if err.kind() == io::ErrorKind::NotFound => return Err(AppError::NotFound),
+        Err(err) => return Err(err.into()),
+    };
+    Ok((
+        [(OFFSET_HEADER, HeaderValue::from(offset))],
+        Json(SessionInfo::new(&session, offset)),
+    )
+        .into_response())
+}
+
+/// `PATCH /upload/sessions/{id}` with `Upload-Offset` set to the bytes the
+/// client believes were received. Whatever arrives before a dropped
+/// connection is kept, so the client can ask for the offset and resume.
+pub async fn append_chunk(
+    State(state): State<AppState>,
+    Extension(auth): Extension<AuthorizedToken>,
+    UrlPath(id): UrlPath<String>,
+    headers: HeaderMap,
+    body: Body,
+) -> Result<Response, AppError> {
+    let claimed: u64 = headers
+        .get(OFFSET_HEADER)
+        .and_then(|v| v.to_str().ok()?.parse().ok())
+        .ok_or(AppError::BadRequest)?;
+    let mut lease = state.sessions.lease(&id, &auth.token_id).await?;
+    let mut offset = lease.offset().await?;
+    if claimed != offset {
+        return Ok(with_offset(StatusCode::CONFLICT, offset));
+    }
+
+    let mut file = OpenOptions::new()
+        .append(true)
+        .open(lease.part_path())
+        .await?;
+    let mut stream = body.into_data_stream();
+    let mut outcome = Ok(());
+    while let Some(chunk) = stream.next().await {
+        let Ok(chunk) = chunk else {
+            outcome = Err(AppError::BadRequest);
+            break;
+        };
+        if offset + chunk.len() as u64 > lease.session.size {
+            outcome = Err(AppError::FileTooLarge);
+            break;
+        }
+        file.write_all(&chunk).await?;
+        offset += chunk.len() as u64;
+    }
+    file.sync_data().await?;
+    lease.touch().await?;
+
+    outcome?;
+    Ok(with_offset(StatusCode::NO_CONTENT, offset))
+}
+
+/// `POST /upload/sessions/{id}/complete`: checks the digest and runs the
+/// upload pipeline. Accepts the same query options as `POST /upload`.
+pub async fn complete_session(
+    State(state): State<AppState>,
+    ClientIp(ip): ClientIp,
+    Extension(auth): Extension<AuthorizedToken>,
+    UrlPath(id): UrlPath<String>,
+    Query(params): Query<UploadParams>,
+    headers: HeaderMap,
+) -> Result<Response, AppError> {
+    let started = Instant::now();
+    let request_id = headers
+        .get("x-request-id")
+        .and_then(|v| v.to_str().ok())
+        .unwrap_or("-");
+
+    let lease = state.sessions.lease(&id, &auth.token_id).await?;
+    let offset = lease.offset().await?;
+    if offset != lease.session.size {
+        return Ok(with_offset(StatusCode::CONFLICT, offset));
+    }
+
+    let data = match fs::read(lease.part_path()).await {
+        Ok(data) => data,
+        Err(err) => {
+            state.metrics.upload_failed(&auth.name, "tmp_read");
+            error!(ip = %ip, request_id, error = %err, elapsed_ms = started.elapsed().as_millis(), result = "fail", reason = "tmp_read", "upload failed");
+            return Err(AppError::Internal);
+        }
+    };
+    let sha256 = hex::encode(Sha256::digest(&data));
+    if sha256 != lease.session.sha256 {
+        let _ = lease.remove().await;
+        state.metrics.upload_failed(&auth.name, "checksum");
+        warn!(ip = %ip, request_id, size = offset, elapsed_ms = started.elapsed().as_millis(), result = "fail", reason = "checksum", "upload rejected");
+        return Err(AppError::BadRequest);
+    }
+
+    let ctx = UploadContext {
+        ip,
+        request_id,
+        started,
+        auth: &auth,
+        ticket: None,
+        params: &params,
+    };
+    let filename = lease.session.filename.clone();
+    let result = process(&state, ctx, &filename, data, sha256).await;
+    // Server-side failures keep the session so completing can be retried.
+    if !matches!(result, Err(AppError::Internal)) {
+        let _ = lease.remove().await;
+    }
+    result.map(|resp| Json(resp).into_response())
+}
+
+/// `DELETE /upload/sessions/{id}`
+pub async fn abort_session(
+    State(state): State<AppState>,
+    Extension(auth): Extension<AuthorizedToken>,
+    UrlPath(id): UrlPath<String>,
+) -> Result<StatusCode, AppError> {
+    let lease = state.sessions.lease(&id, &auth.token_id).await?;
+    lease.remove().await?;
+    info!(token = %auth.name, session = %id, "upload session aborted");
+    Ok(StatusCode::NO_CONTENT)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use super::UploadSessions;
+    use crate::error::AppError;
+
+    #[tokio::test]
+    async fn sessions_are_scoped_locked_and_expire() {
+        let tmp = tempfile::tempdir().expect("tmpdir");
+        let sessions = UploadSessions::new(tmp.path(), Duration::from_secs(60), 2);
+        let session = sessions
+            .create("t1", "a.png", 10, &"ab".repeat(32))
+            .await
+            .unwrap();
+
+        assert!(matches!(
+            sessions.get(&session.id, "t2").await,
+            Err(AppError::NotFound)
+        ));
+        assert!(matches!(
+            sessions.get("../../etc/passwd", "t1").await,
+            Err(AppError::NotFound)
+        ));
+
+        let lease = sessions.lease(&session.id, "t1").await.unwrap();
+        assert!(matches!(
+            sessions.lease(&session.id, "t1").await,
+            Err(AppError::Conflict)
+        ));
+        // Leased sessions are never swept from under a request.
+        assert_eq!(sessions.expire().await.unwrap(), 0);
+        drop(lease);
+        assert!(sessions.lease(&session.id, "t1").await.is_ok());
+
+        // Open sessions are capped per token.
+        let second = sessions
+            .create("t1", "c.png", 10, &"ef".repeat(32))
+            .await
+            .unwrap();
+        assert!(matches!(
+            sessions.create("t1", "d.png", 10, &"ef".repeat(32)).await,
+            Err(AppError::TooManyRequests)
+        ));
+        assert!(sessions
+            .create("t2", "d.png", 10, &"ef".repeat(32))
+            .await
+            .is_ok());
+        sessions
+            .lease(&second.id, "t1")
+            .await
+            .unwrap()
+            .remove()
+            .await
+            .unwrap();
+
+        let short = UploadSessions::new(tmp.path(), Duration::ZERO, 2);
+        let stale = short
+            .create("t1", "b.png", 10, &"cd".repeat(32))
+            .await
+            .unwrap();
+        assert!(matches!(
+            short.get(&stale.id, "t1").await,
+            Err(AppError::NotFound)
+        ));
+        let torn = tmp.path().join(".tmp/sessions/0123.json.tmp");
+        std::fs::write(&torn, b"{").unwrap();
+        assert_eq!(short.expire().await.unwrap(), 1);
+        assert!(!torn.exists());
+        assert!(!tmp
+            .path()
+            .join(".tmp/sessions")
+            .join(format!("{}.part", stale.id))
+            .exists());
+        assert!(sessions.get(&session.id, "t1").await.is_ok());
+    }
+}
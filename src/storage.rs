@@ -0,0 +1,245 @@
+use std::{
+    io,
+    path::{Path, PathBuf},
+    sync::Arc,
+};
+
+use async_trait::async_trait;
+use axum::body::Body;
+use tokio::{
+    fs::{self, File},
+    io::{AsyncReadExt, AsyncSeekExt},
+};
+use tokio_util::io::ReaderStream;
+use uuid::Uuid;
+
+use crate::{config::AppConfig, s3::S3Storage};
+
+/// Which [`Storage`] holds the images, chosen by the `STORAGE` setting.
+#[derive(Clone)]
+pub enum StorageConfig {
+    /// Files under `data_dir`, the original layout.
+    Fs,
+    S3(Box<crate::s3::S3Config>),
+}
+
+/// Object store for originals and cached variants. Keys are relative paths
+/// such as `2024/05/<sha256>.webp`; the index and temporary files always stay
+/// under the local `data_dir`.
+#[async_trait]
+pub trait Storage: Send + Sync {
+    /// Stores `data` under `key` unless an object already exists there, and
+    /// reports whether this call created it. Keys are content-addressed, so
+    /// an existing object is never overwritten.
+    async fn put_if_absent(&self, key: &str, data: Vec<u8>) -> io::Result<bool>;
+
+    /// Size of the object, or `None` if it does not exist.
+    async fn size(&self, key: &str) -> io::Result<Option<u64>>;
+
+    async fn exists(&self, key: &str) -> io::Result<bool> {
+        Ok(self.size(key).await?.is_some())
+    }
+
+    /// The whole object, or `None` if it does not exist.
+    async fn get(&self, key: &str) -> io::Result<Option<Vec<u8>>>;
+
+    /// A body streaming `len` bytes from `offset`.
+    async fn stream(&self, key: &str, offset: u64, len: u64) -> io::Result<Option<Body>>;
+
+    /// Removes the object; a missing object is not an error.
+    async fn delete(&self, key: &str) -> io::Result<()>;
+
+    /// Keys in the directory of `prefix` whose name starts with the rest of
+    /// it, e.g. `2024/05/<sha256>_` for an image's variants. Not recursive.
+    async fn list(&self, prefix: &str) -> io::Result<Vec<String>>;
+}
+
+pub fn from_config(config: &AppConfig) -> Arc<dyn Storage> {
+    match &config.storage {
+        StorageConfig::Fs => Arc::new(FsStorage::new(&config.data_dir)),
+        StorageConfig::S3(s3) => Arc::new(S3Storage::new(s3.as_ref().clone())),
+    }
+}
+
+/// `<data_dir>/<key>` on the local filesystem.
+pub struct FsStorage {
+    root: PathBuf,
+}
+
+impl FsStorage {
+    pub fn new(root: &Path) -> Self {
+        Self {
+            root: root.to_path_buf(),
+        }
+    }
+
+    fn path(&self, key: &str) -> PathBuf {
+        self.root.join(key)
+    }
+}
+
+#[async_trait]
+impl Storage for FsStorage {
+    async fn put_if_absent(&self, key: &str, data: Vec<u8>) -> io::Result<bool> {
+        let path = self.path(key);
+        if fs::try_exists(&path).await? {
+            return Ok(false);
+        }
+        if let Some(dir) = path.parent() {
+            fs::create_dir_all(dir).await?;
+        }
+
+        // Readers never see a partial file: the data is written aside and
+        // linked into place. Linking fails if the key exists, so exactly one
+        // of several concurrent writers reports that it created the object.
+        let tmp_dir = self.root.join(".tmp");
+        fs::create_dir_all(&tmp_dir).await?;
+        let tmp_path = tmp_dir.join(format!(".storing-{}", Uuid::new_v4()));
+        fs::write(&tmp_path, &data).await?;
+        let linked = fs::hard_link(&tmp_path, &path).await;
+        let _ = fs::remove_file(&tmp_path).await;
+        match linked {
+            Ok(()) => Ok(true),
+            Err(err) if err.kind() == io::ErrorKind::AlreadyExists => Ok(false),
+            Err(err) => Err(err),
+        }
+    }
+
+    async fn size(&self, key: &str) -> io::Result<Option<u64>> {
+        match fs::metadata(self.path(key)).await {
+            Ok(meta) if meta.is_file() => Ok(Some(meta.len())),
+            Ok(_) => Ok(None),
+            Err(err) if err.kind() == io::ErrorKind::NotFound => Ok(None),
+            Err(err) => Err(err),
+        }
+    }
+
+    async fn get(&self, key: &str) -> io::Result<Option<Vec<u8>>> {
+        match fs::read(self.path(key)).await {
+            Ok(data) => Ok(Some(data)),
+            Err(err) if err.kind() == io::ErrorKind::NotFound => Ok(None),
+            Err(err) => Err(err),
+        }
+    }
+
+    async fn stream(&self, key: &str, offset: u64, len: u64) -> io::Result<Option<Body>> {
+        let mut file = match File::open(self.path(key)).await {
+            Ok(file) => file,
+            Err(err) if err.kind() == io::ErrorKind::NotFound => return Ok(None),
+            Err(err) => return Err(err),
+        };
+        if offset > 0 {
+            file.seek(io::SeekFrom::Start(offset)).await?;
+        }
+        Ok(Some(Body::from_stream(ReaderStream::new(file.take(len)))))
+    }
+
+    async fn delete(&self, key: &str) -> io::Result<()> {
+        match fs::remove_file(self.path(key)).await {
+            Ok(()) => Ok(()),
+            Err(err) if err.kind() == io::ErrorKind::NotFound => Ok(()),
+            Err(err) => Err(err),
+        }
+    }
+
+    async fn list(&self, prefix: &str) -> io::Result<Vec<String>> {
+        let (dir, name_prefix) = prefix.rsplit_once('/').unwrap_or(("", prefix));
+        let mut entries = match fs::read_dir(self.root.join(dir)).await {
+            Ok(entries) => entries,
+            Err(err) if err.kind() == io::ErrorKind::NotFound => return Ok(Vec::new()),
+            Err(err) => return Err(err),
+        };
+        let mut keys = Vec::new();
+        while let Some(entry) = entries.next_entry().await? {
+            let name = entry.file_name();
+            let Some(name) = name.to_str() else {
+                continue;
+            };
+            if name.starts_with(name_prefix) && entry.file_type().await?.is_file() {
+                keys.push(if dir.is_empty() {
+                    name.to_owned()
+                } else {
+                    format!("{dir}/{name}")
+                });
+            }
+        }
+        keys.sort();
+        Ok(keys)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use http_body_util::BodyExt;
+
+    use super::{FsStorage, Storage};
+
+    #[tokio::test]
+    async fn fs_storage_round_trip() {
+        let tmp = tempfile::tempdir().expect("tmpdir");
+        let storage = FsStorage::new(tmp.path());
+
+        assert!(storage
+            .put_if_absent("2024/05/aa.webp", b"first".to_vec())
+            .await
+            .unwrap());
+        assert!(!storage
+            .put_if_absent("2024/05/aa.webp", b"second".to_vec())
+            .await
+            .unwrap());
+        assert_eq!(
+            storage.get("2024/05/aa.webp").await.unwrap().as_deref(),
+            Some(&b"first"[..])
+        );
+        assert_eq!(storage.size("2024/05/aa.webp").await.unwrap(), Some(5));
+        assert!(!storage.exists("2024/05/bb.webp").await.unwrap());
+
+        let body = storage
+            .stream("2024/05/aa.webp", 1, 3)
+            .await
+            .unwrap()
+            .expect("object");
+        let bytes = body.collect().await.unwrap().to_bytes();
+        assert_eq!(&bytes[..], b"irs");
+
+        storage
+            .put_if_absent("2024/05/aa_320x240_cover.webp", Vec::new())
+            .await
+            .unwrap();
+        assert_eq!(
+            storage.list("2024/05/aa_").await.unwrap(),
+            vec!["2024/05/aa_320x240_cover.webp"]
+        );
+        assert!(storage.list("2031/01/aa_").await.unwrap().is_empty());
+
+        storage.delete("2024/05/aa.webp").await.unwrap();
+        storage.delete("2024/05/aa.webp").await.unwrap();
+        assert_eq!(storage.get("2024/05/aa.webp").await.unwrap(), None);
+    }
+
+    #[tokio::test]
+    async fn concurrent_puts_create_once() {
+        let tmp = tempfile::tempdir().expect("tmpdir");
+        let storage = std::sync::Arc::new(FsStorage::new(tmp.path()));
+        let puts: Vec<_> = (0..8)
+            .map(|_| {
+                let storage = storage.clone();
+                tokio::spawn(async move {
+                    storage
+                        .put_if_absent("2024/05/aa.webp", b"same".to_vec())
+                        .await
+                        .unwrap()
+                })
+            })
+            .collect();
+        let mut created = 0;
+        for put in puts {
+            created += usize::from(put.await.unwrap());
+        }
+        assert_eq!(created, 1);
+        assert!(std::fs::read_dir(tmp.path().join(".tmp"))
+            .unwrap()
+            .next()
+            .is_none());
+    }
+}
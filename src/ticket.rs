@@ -0,0 +1,246 @@
+use std::{
+    collections::HashMap,
+    fs, io,
+    path::{Path, PathBuf},
+};
+
+use base64::{engine::general_purpose::URL_SAFE_NO_PAD, Engine};
+use hmac::{Hmac, Mac};
+use serde::{Deserialize, Serialize};
+use sha2::Sha256;
+use tokio::{fs::OpenOptions, io::AsyncWriteExt, sync::Mutex};
+use tracing::warn;
+
+/// Lifetime of a ticket when the request does not ask for one.
+pub const DEFAULT_TTL_SECS: u64 = 300;
+/// Longest lifetime a ticket may be minted with.
+pub const MAX_TTL_SECS: u64 = 3600;
+/// Shortest accepted `UPLOAD_TICKET_SECRET`, in bytes.
+pub const MIN_SECRET_LEN: usize = 32;
+
+/// What a ticket allows. Signed as JSON and carried in the ticket itself, so
+/// imgd keeps no state per minted ticket, only the nonces already redeemed.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct TicketClaims {
+    /// Token the upload is attributed to; it must still be valid at redemption.
+    pub token_id: String,
+    /// Unix seconds.
+    pub expires_at: i64,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub max_bytes: Option<usize>,
+    /// Accepted content types of the detected format; empty allows all.
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub content_types: Vec<String>,
+    /// Key prefix the upload is stored under, checked by
+    /// [`is_valid_prefix`](crate::serve::is_valid_prefix) when minted.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub path_prefix: Option<String>,
+    pub nonce: String,
+}
+
+impl TicketClaims {
+    pub fn allows_content_type(&self, content_type: &str) -> bool {
+        self.content_types.is_empty()
+            || self
+                .content_types
+                .iter()
+                .any(|ct| ct.eq_ignore_ascii_case(content_type))
+    }
+}
+
+/// A redeemed nonce as logged in `<data_dir>/.meta/tickets.jsonl`.
+#[derive(Serialize, Deserialize)]
+struct Redeemed {
+    nonce: String,
+    expires_at: i64,
+}
+
+/// Redeemed nonces and their expiry, and how many lines the log holds.
+#[derive(Default)]
+struct Ledger {
+    nonces: HashMap<String, i64>,
+    logged: usize,
+}
+
+/// Mints and redeems `<claims>.<signature>` tickets, both base64url encoded,
+/// with the signature an HMAC-SHA256 of the encoded claims.
+pub struct Tickets {
+    key: Vec<u8>,
+    /// Append-only log of redeemed nonces, replayed on startup so a restart
+    /// does not make used tickets valid again.
+    path: PathBuf,
+    redeemed: Mutex<Ledger>,
+}
+
+impl Tickets {
+    pub fn new(secret: &[u8], data_dir: &Path) -> Self {
+        let path = data_dir.join(".meta").join("tickets.jsonl");
+        let mut ledger = Ledger::default();
+        match fs::read_to_string(&path) {
+            Ok(data) => {
+                if !data.is_empty() && !data.ends_with('\n') {
+                    // Terminate a torn line so the next append starts cleanly.
+                    let terminated = fs::OpenOptions::new()
+                        .append(true)
+                        .open(&path)
+                        .and_then(|mut file| io::Write::write_all(&mut file, b"\n"));
+                    if let Err(err) = terminated {
+                        warn!(path = %path.display(), error = %err, "cannot repair redeemed tickets");
+                    }
+                }
+                for line in data.lines() {
+                    // Torn or malformed lines are skipped like in the index.
+                    if let Ok(entry) = serde_json::from_str::<Redeemed>(line) {
+                        ledger.nonces.insert(entry.nonce, entry.expires_at);
+                    }
+                    ledger.logged += 1;
+                }
+            }
+            Err(err) if err.kind() == io::ErrorKind::NotFound => {}
+            Err(err) => {
+                warn!(path = %path.display(), error = %err, "cannot read redeemed tickets")
+            }
+        }
+        Self {
+            key: secret.to_vec(),
+            path,
+            redeemed: Mutex::new(ledger),
+        }
+    }
+
+    pub fn sign(&self, claims: &TicketClaims) -> String {
+        let payload = URL_SAFE_NO_PAD.encode(serde_json::to_vec(claims).expect("claims serialize"));
+        let signature = URL_SAFE_NO_PAD.encode(self.mac(&payload).finalize().into_bytes());
+        format!("{payload}.{signature}")
+    }
+
+    /// Checks the signature and expiry without redeeming the ticket.
+    pub fn verify(&self, raw: &str, now: i64) -> Option<TicketClaims> {
+        let (payload, signature) = raw.split_once('.')?;
+        let signature = URL_SAFE_NO_PAD.decode(signature).ok()?;
+        self.mac(payload).verify_slice(&signature).ok()?;
+        let claims: TicketClaims =
+            serde_json::from_slice(&URL_SAFE_NO_PAD.decode(payload).ok()?).ok()?;
+        (claims.expires_at > now).then_some(claims)
+    }
+
+    /// Marks the ticket's nonce as used; `false` if it already was. The nonce
+    /// is on disk before this returns `true`.
+    pub async fn redeem(&self, claims: &TicketClaims, now: i64) -> io::Result<bool> {
+        let mut ledger = self.redeemed.lock().await;
+        ledger.nonces.retain(|_, expires_at| *expires_at > now);
+        if ledger.nonces.contains_key(&claims.nonce) {
+            return Ok(false);
+        }
+        // Expired nonces stay in the log until it is rewritten; do that once
+        // they outnumber the live ones.
+        if ledger.logged > 2 * ledger.nonces.len() + 1024 {
+            self.rewrite(&ledger.nonces).await?;
+            ledger.logged = ledger.nonces.len();
+        }
+        let mut line = serde_json::to_vec(&Redeemed {
+            nonce: claims.nonce.clone(),
+            expires_at: claims.expires_at,
+        })?;
+        line.push(b'\n');
+        let mut file = OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(&self.path)
+            .await?;
+        file.write_all(&line).await?;
+        file.sync_data().await?;
+        ledger.logged += 1;
+        ledger
+            .nonces
+            .insert(claims.nonce.clone(), claims.expires_at);
+        Ok(true)
+    }
+
+    async fn rewrite(&self, nonces: &HashMap<String, i64>) -> io::Result<()> {
+        let mut data = Vec::new();
+        for (nonce, &expires_at) in nonces {
+            serde_json::to_writer(
+                &mut data,
+                &Redeemed {
+                    nonce: nonce.clone(),
+                    expires_at,
+                },
+            )?;
+            data.push(b'\n');
+        }
+        let tmp = self.path.with_extension("jsonl.tmp");
+        let mut file = tokio::fs::File::create(&tmp).await?;
+        file.write_all(&data).await?;
+        file.sync_data().await?;
+        tokio::fs::rename(&tmp, &self.path).await
+    }
+
+    fn mac(&self, payload: &str) -> Hmac<Sha256> {
+        let mut mac = Hmac::<Sha256>::new_from_slice(&self.key).expect("hmac accepts any key");
+        mac.update(payload.as_bytes());
+        mac
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{TicketClaims, Tickets};
+
+    fn claims(expires_at: i64) -> TicketClaims {
+        TicketClaims {
+            token_id: "abc123".to_owned(),
+            expires_at,
+            max_bytes: Some(1024),
+            content_types: vec!["image/png".to_owned()],
+            path_prefix: Some("avatars".to_owned()),
+            nonce: "n1".to_owned(),
+        }
+    }
+
+    #[tokio::test]
+    async fn sign_verify_and_redeem_once() {
+        let tmp = tempfile::tempdir().expect("tmpdir");
+        std::fs::create_dir_all(tmp.path().join(".meta")).unwrap();
+        let tickets = Tickets::new(&[7; 32], tmp.path());
+        let raw = tickets.sign(&claims(1_000));
+
+        let verified = tickets.verify(&raw, 999).expect("valid");
+        assert_eq!(verified, claims(1_000));
+        assert!(tickets.verify(&raw, 1_000).is_none(), "expired");
+        assert!(
+            Tickets::new(&[8; 32], tmp.path()).verify(&raw, 0).is_none(),
+            "other key"
+        );
+
+        let (payload, signature) = raw.split_once('.').unwrap();
+        let forged = tickets.sign(&TicketClaims {
+            max_bytes: None,
+            ..claims(1_000)
+        });
+        let (forged_payload, _) = forged.split_once('.').unwrap();
+        assert_ne!(payload, forged_payload);
+        assert!(tickets
+            .verify(&format!("{forged_payload}.{signature}"), 0)
+            .is_none());
+
+        assert!(tickets.redeem(&verified, 999).await.unwrap());
+        assert!(!tickets.redeem(&verified, 999).await.unwrap());
+
+        // Redeemed nonces survive a restart.
+        let reopened = Tickets::new(&[7; 32], tmp.path());
+        assert!(!reopened.redeem(&verified, 999).await.unwrap());
+        let other = TicketClaims {
+            nonce: "n2".to_owned(),
+            ..claims(1_000)
+        };
+        assert!(reopened.redeem(&other, 999).await.unwrap());
+    }
+
+    #[test]
+    fn content_type_restrictions() {
+        let c = claims(1_000);
+        assert!(c.allows_content_type("IMAGE/PNG"));
+        assert!(!c.allows_content_type("image/gif"));
+    }
+}
@@ -2,34 +2,107 @@ use std::{
     collections::HashMap,
     fs,
     path::{Path, PathBuf},
-    sync::Arc,
+    sync::{atomic::Ordering, Arc, Mutex, PoisonError, RwLock},
+    time::SystemTime,
 };
 
-use chrono::{DateTime, Utc};
+use chrono::{DateTime, TimeDelta, Utc};
 use rand::RngCore;
 use serde::{Deserialize, Serialize};
 use sha2::{Digest, Sha256};
+use tokio::signal::unix::{signal, SignalKind};
 
-use crate::config::AppConfig;
+use crate::{config::AppConfig, error::AppError, index::Quota, rate_limit::MAX_LIMIT, Metrics};
+
+type TokenMap = HashMap<String, TokenPolicy>;
+
+/// Permission granted to a token. `Admin` implies every other scope and
+/// lifts the own-uploads restriction on delete and list.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Scope {
+    Upload,
+    Delete,
+    List,
+    Admin,
+    Metrics,
+}
+
+impl Scope {
+    /// Scopes of tokens that predate scopes, matching what they could do then.
+    pub const DEFAULT: [Scope; 3] = [Scope::Upload, Scope::Delete, Scope::List];
+
+    pub fn as_str(self) -> &'static str {
+        match self {
+            Scope::Upload => "upload",
+            Scope::Delete => "delete",
+            Scope::List => "list",
+            Scope::Admin => "admin",
+            Scope::Metrics => "metrics",
+        }
+    }
+}
+
+impl std::fmt::Display for Scope {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str(self.as_str())
+    }
+}
+
+impl std::str::FromStr for Scope {
+    type Err = String;
+
+    fn from_str(raw: &str) -> Result<Self, Self::Err> {
+        match raw.trim().to_ascii_lowercase().as_str() {
+            "upload" => Ok(Scope::Upload),
+            "delete" => Ok(Scope::Delete),
+            "list" => Ok(Scope::List),
+            "admin" => Ok(Scope::Admin),
+            "metrics" => Ok(Scope::Metrics),
+            other => Err(format!("unknown scope: {other}")),
+        }
+    }
+}
 
 #[derive(Clone)]
 pub struct AuthorizedToken {
     pub name: String,
     pub token_id: String,
     pub rate_limit_per_minute: Option<usize>,
+    pub scopes: Vec<Scope>,
+    pub max_upload_bytes: Option<usize>,
+    pub quota: Quota,
+}
+
+impl AuthorizedToken {
+    pub fn has_scope(&self, scope: Scope) -> bool {
+        self.scopes.contains(&scope) || self.scopes.contains(&Scope::Admin)
+    }
+
+    pub fn is_admin(&self) -> bool {
+        self.scopes.contains(&Scope::Admin)
+    }
 }
 
+/// Token set shared by all handlers. The map is replaced wholesale on reload,
+/// so a request always sees either the old or the new set, never a mix.
 #[derive(Clone)]
 pub struct TokenStore {
-    tokens: Arc<HashMap<String, TokenPolicy>>,
+    tokens: Arc<RwLock<Arc<TokenMap>>>,
+    /// Serializes read-modify-write cycles on the tokens file.
+    edit_lock: Arc<Mutex<()>>,
 }
 
 #[derive(Clone)]
 struct TokenPolicy {
     name: String,
     token_id: String,
+    hash: TokenHash,
     expires_at: Option<DateTime<Utc>>,
     rate_limit_per_minute: Option<usize>,
+    scopes: Vec<Scope>,
+    max_upload_bytes: Option<usize>,
+    quota: Quota,
 }
 
 #[derive(Debug, Serialize, Deserialize, Clone)]
@@ -40,79 +113,474 @@ pub struct TokenFile {
 #[derive(Debug, Serialize, Deserialize, Clone)]
 pub struct TokenEntry {
     pub name: String,
-    pub token: String,
+    /// Lookup id, see [`token_fingerprint`].
+    #[serde(default)]
+    pub token_id: String,
+    /// Salted hash in the form produced by [`TokenHash::encode`].
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub token_hash: Option<String>,
+    /// Plaintext token from files written before hashing was introduced;
+    /// still accepted, but `imgd token migrate` replaces it with a hash.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub token: Option<String>,
     #[serde(default)]
     pub expires_at: Option<String>,
     #[serde(default)]
     pub rate_limit_per_minute: Option<usize>,
+    /// Granted scopes; entries without the field get [`Scope::DEFAULT`].
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub scopes: Option<Vec<Scope>>,
+    /// Per-file limit; the global `MAX_UPLOAD_BYTES` still applies on top.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub max_upload_bytes: Option<usize>,
+    /// Total stored bytes attributed to this token.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub quota_bytes: Option<u64>,
+    /// Total stored files attributed to this token.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub quota_files: Option<u64>,
+    /// Pre-scopes admin flag, read as an extra `admin` scope.
+    #[serde(default, skip_serializing_if = "std::ops::Not::not")]
+    pub admin: bool,
 }
 
 impl TokenStore {
     pub fn from_config(config: &AppConfig) -> Result<Self, Box<dyn std::error::Error>> {
-        let mut map = HashMap::new();
-
-        if let Some(path) = &config.tokens_file {
-            let file = load_token_file(path)?;
-            for entry in file.tokens {
-                let raw_token = entry.token.clone();
-                let policy = TokenPolicy::from_entry(entry)?;
-                map.insert(raw_token, policy);
-            }
-        }
+        Ok(Self {
+            tokens: Arc::new(RwLock::new(Arc::new(build_token_map(config)?))),
+            edit_lock: Arc::new(Mutex::new(())),
+        })
+    }
 
-        if let Some(legacy) = &config.upload_token {
-            let policy = TokenPolicy {
-                name: "legacy-default".to_string(),
-                token_id: token_fingerprint(legacy),
-                expires_at: None,
-                rate_limit_per_minute: None,
-            };
-            map.entry(legacy.clone()).or_insert(policy);
-        }
+    /// Re-reads the token sources and swaps them in. On error the current set
+    /// stays active. Returns the number of tokens now loaded.
+    pub fn reload(&self, config: &AppConfig) -> Result<usize, Box<dyn std::error::Error>> {
+        let map = build_token_map(config)?;
+        let count = map.len();
+        *self.tokens.write().unwrap_or_else(PoisonError::into_inner) = Arc::new(map);
+        Ok(count)
+    }
 
-        if map.is_empty() {
-            return Err("no upload token configured; set UPLOAD_TOKEN or TOKENS_FILE".into());
-        }
+    /// Reads `TOKENS_FILE` as currently saved, serialized with [`Self::edit_file`].
+    pub async fn read_file(&self, config: &AppConfig) -> Result<TokenFile, AppError> {
+        let (store, config) = (self.clone(), config.clone());
+        run_blocking(move || store.read_file_blocking(&config)).await
+    }
 
-        Ok(Self {
-            tokens: Arc::new(map),
+    fn read_file_blocking(&self, config: &AppConfig) -> Result<TokenFile, AppError> {
+        let path = config.tokens_file.as_ref().ok_or(AppError::NotFound)?;
+        let _guard = self
+            .edit_lock
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner);
+        load_token_file(path).map_err(|err| {
+            tracing::error!(path = %path.display(), error = %err, "cannot read tokens file");
+            AppError::Internal
         })
     }
 
+    /// Applies `edit` to `TOKENS_FILE`, then validates, saves and activates
+    /// the result in one step. If `edit` or validation fails, neither the file
+    /// nor the live set changes. Runs on the blocking pool, since it waits
+    /// for other edits and reads and renames the file.
+    pub async fn edit_file<T, F>(&self, config: &AppConfig, edit: F) -> Result<T, AppError>
+    where
+        F: FnOnce(&mut TokenFile) -> Result<T, AppError> + Send + 'static,
+        T: Send + 'static,
+    {
+        let (store, config) = (self.clone(), config.clone());
+        run_blocking(move || store.edit_file_blocking(&config, edit)).await
+    }
+
+    fn edit_file_blocking<T>(
+        &self,
+        config: &AppConfig,
+        edit: impl FnOnce(&mut TokenFile) -> Result<T, AppError>,
+    ) -> Result<T, AppError> {
+        let path = config.tokens_file.as_ref().ok_or(AppError::NotFound)?;
+        let _guard = self
+            .edit_lock
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner);
+
+        let mut file = load_token_file(path).map_err(|err| {
+            tracing::error!(path = %path.display(), error = %err, "cannot read tokens file");
+            AppError::Internal
+        })?;
+        let out = edit(&mut file)?;
+        let map = token_map_from(Some(file.clone()), config).map_err(|err| {
+            tracing::warn!(error = %err, "rejected token file edit");
+            AppError::BadRequest
+        })?;
+        save_token_file(path, &file).map_err(|err| {
+            tracing::error!(path = %path.display(), error = %err, "cannot write tokens file");
+            AppError::Internal
+        })?;
+        *self.tokens.write().unwrap_or_else(PoisonError::into_inner) = Arc::new(map);
+        Ok(out)
+    }
+
+    fn snapshot(&self) -> Arc<TokenMap> {
+        self.tokens
+            .read()
+            .unwrap_or_else(PoisonError::into_inner)
+            .clone()
+    }
+
     pub fn authorize(&self, raw: &str) -> Option<AuthorizedToken> {
-        let policy = self.tokens.get(raw)?;
+        let tokens = self.snapshot();
+        let policy = tokens.get(&token_fingerprint(raw))?;
+        if !policy.hash.verify(raw) {
+            return None;
+        }
+        policy.authorized()
+    }
+
+    /// Looks up a token by id without its secret, for credentials such as
+    /// upload tickets that were minted by the token holder.
+    pub fn authorize_id(&self, token_id: &str) -> Option<AuthorizedToken> {
+        self.snapshot().get(token_id)?.authorized()
+    }
+}
 
-        if let Some(exp) = policy.expires_at {
+impl TokenPolicy {
+    fn authorized(&self) -> Option<AuthorizedToken> {
+        if let Some(exp) = self.expires_at {
             if Utc::now() > exp {
                 return None;
             }
         }
 
         Some(AuthorizedToken {
-            name: policy.name.clone(),
-            token_id: policy.token_id.clone(),
-            rate_limit_per_minute: policy.rate_limit_per_minute,
+            name: self.name.clone(),
+            token_id: self.token_id.clone(),
+            rate_limit_per_minute: self.rate_limit_per_minute,
+            scopes: self.scopes.clone(),
+            max_upload_bytes: self.max_upload_bytes,
+            quota: self.quota,
         })
     }
 }
 
+/// Builds the lookup map keyed by token id. Only ids and hashes are kept in
+/// memory; the raw token is never stored.
+fn build_token_map(config: &AppConfig) -> Result<TokenMap, Box<dyn std::error::Error>> {
+    let file = match &config.tokens_file {
+        Some(path) => Some(load_token_file(path)?),
+        None => None,
+    };
+    token_map_from(file, config)
+}
+
+fn token_map_from(
+    file: Option<TokenFile>,
+    config: &AppConfig,
+) -> Result<TokenMap, Box<dyn std::error::Error>> {
+    let mut map = HashMap::new();
+
+    if let (Some(file), Some(path)) = (file, &config.tokens_file) {
+        let plaintext = file.tokens.iter().filter(|e| e.token.is_some()).count();
+        if plaintext > 0 {
+            tracing::warn!(path = %path.display(), count = plaintext, "tokens file contains plaintext tokens; run `imgd token migrate`");
+        }
+        for entry in file.tokens {
+            let policy = TokenPolicy::from_entry(entry)?;
+            if map.contains_key(&policy.token_id) {
+                return Err(
+                    format!("duplicate token_id in tokens file: {}", policy.token_id).into(),
+                );
+            }
+            map.insert(policy.token_id.clone(), policy);
+        }
+    }
+
+    if let Some(legacy) = &config.upload_token {
+        let token_id = token_fingerprint(legacy);
+        let policy = TokenPolicy {
+            name: "legacy-default".to_string(),
+            token_id: token_id.clone(),
+            hash: TokenHash::new(legacy),
+            expires_at: None,
+            rate_limit_per_minute: None,
+            scopes: Scope::DEFAULT.to_vec(),
+            max_upload_bytes: None,
+            quota: Quota::default(),
+        };
+        map.entry(token_id).or_insert(policy);
+    }
+
+    if map.is_empty() {
+        return Err("no upload token configured; set UPLOAD_TOKEN or TOKENS_FILE".into());
+    }
+
+    Ok(map)
+}
+
+/// Runs a tokens-file job off the async workers, so a slow disk stalls only
+/// the admin request waiting for it.
+async fn run_blocking<T, F>(job: F) -> Result<T, AppError>
+where
+    F: FnOnce() -> Result<T, AppError> + Send + 'static,
+    T: Send + 'static,
+{
+    tokio::task::spawn_blocking(job)
+        .await
+        .map_err(|_| AppError::Internal)?
+}
+
+/// Reloads `store` whenever `TOKENS_FILE` changes on disk (polled every
+/// `config.tokens_reload_interval`) or the process receives SIGHUP.
+pub async fn watch_tokens(store: TokenStore, config: AppConfig, metrics: Arc<Metrics>) {
+    let Some(path) = config.tokens_file.clone() else {
+        return;
+    };
+
+    let mut hangup = match signal(SignalKind::hangup()) {
+        Ok(signal) => Some(signal),
+        Err(err) => {
+            tracing::warn!(error = %err, "cannot listen for SIGHUP; token reload by signal disabled");
+            None
+        }
+    };
+
+    let mut last_seen = file_stamp(&path);
+    let mut ticker = tokio::time::interval(config.tokens_reload_interval);
+    ticker.set_missed_tick_behavior(tokio::time::MissedTickBehavior::Delay);
+    ticker.tick().await;
+
+    loop {
+        let trigger = tokio::select! {
+            _ = ticker.tick() => "file_changed",
+            Some(()) = async {
+                match hangup.as_mut() {
+                    Some(signal) => signal.recv().await,
+                    None => std::future::pending().await,
+                }
+            } => "sighup",
+        };
+
+        let stamp = file_stamp(&path);
+        if trigger == "file_changed" && stamp == last_seen {
+            continue;
+        }
+        last_seen = stamp;
+
+        let reload = {
+            let (store, config) = (store.clone(), config.clone());
+            tokio::task::spawn_blocking(move || store.reload(&config).map_err(|e| e.to_string()))
+                .await
+                .unwrap_or_else(|_| Err("reload task panicked".to_owned()))
+        };
+        match reload {
+            Ok(count) => {
+                metrics.token_reload_ok.fetch_add(1, Ordering::Relaxed);
+                tracing::info!(path = %path.display(), trigger, tokens = count, result = "ok", "tokens reloaded");
+            }
+            Err(err) => {
+                metrics.token_reload_fail.fetch_add(1, Ordering::Relaxed);
+                tracing::error!(path = %path.display(), trigger, error = %err, result = "fail", "token reload failed; keeping previous tokens");
+            }
+        }
+    }
+}
+
+/// Modification time and length, enough to notice the atomic rename done by
+/// `save_token_file`.
+fn file_stamp(path: &Path) -> Option<(SystemTime, u64)> {
+    let meta = fs::metadata(path).ok()?;
+    Some((meta.modified().ok()?, meta.len()))
+}
+
 impl TokenPolicy {
     fn from_entry(entry: TokenEntry) -> Result<Self, Box<dyn std::error::Error>> {
+        let (token_id, hash) = match (&entry.token_hash, &entry.token) {
+            (Some(encoded), _) => {
+                if entry.token_id.is_empty() {
+                    return Err(format!("token {}: token_hash without token_id", entry.name).into());
+                }
+                let hash = TokenHash::parse(encoded)
+                    .ok_or_else(|| format!("token {}: malformed token_hash", entry.name))?;
+                (entry.token_id.clone(), hash)
+            }
+            (None, Some(raw)) => (token_fingerprint(raw), TokenHash::new(raw)),
+            (None, None) => return Err(format!("token {}: missing token_hash", entry.name).into()),
+        };
+
         let expires_at = if let Some(raw) = &entry.expires_at {
             Some(DateTime::parse_from_rfc3339(raw)?.with_timezone(&Utc))
         } else {
             None
         };
+        if let Some(limit) = entry.rate_limit_per_minute {
+            check_rate_limit(limit).map_err(|err| format!("token {}: {err}", entry.name))?;
+        }
 
         Ok(Self {
-            token_id: token_fingerprint(&entry.token),
+            token_id,
+            hash,
+            scopes: entry.effective_scopes(),
             name: entry.name,
             expires_at,
             rate_limit_per_minute: entry.rate_limit_per_minute,
+            max_upload_bytes: entry.max_upload_bytes,
+            quota: Quota {
+                max_bytes: entry.quota_bytes,
+                max_files: entry.quota_files,
+            },
         })
     }
 }
 
+/// Expiry `days` from now; `None` unless `days` is positive and the result
+/// is representable.
+pub fn expiry_after_days(days: i64) -> Option<DateTime<Utc>> {
+    if days <= 0 {
+        return None;
+    }
+    Utc::now().checked_add_signed(TimeDelta::try_days(days)?)
+}
+
+fn check_rate_limit(limit: usize) -> Result<(), String> {
+    if (1..=MAX_LIMIT).contains(&limit) {
+        Ok(())
+    } else {
+        Err(format!(
+            "rate_limit_per_minute must be within 1..={MAX_LIMIT}, got {limit}"
+        ))
+    }
+}
+
+impl TokenEntry {
+    pub fn new(name: String, raw: &str) -> Self {
+        Self {
+            name,
+            token_id: token_fingerprint(raw),
+            token_hash: Some(TokenHash::new(raw).encode()),
+            token: None,
+            expires_at: None,
+            rate_limit_per_minute: None,
+            scopes: Some(Scope::DEFAULT.to_vec()),
+            max_upload_bytes: None,
+            quota_bytes: None,
+            quota_files: None,
+            admin: false,
+        }
+    }
+
+    pub fn effective_scopes(&self) -> Vec<Scope> {
+        let mut scopes = self
+            .scopes
+            .clone()
+            .unwrap_or_else(|| Scope::DEFAULT.to_vec());
+        if self.admin {
+            scopes.push(Scope::Admin);
+        }
+        scopes.sort();
+        scopes.dedup();
+        scopes
+    }
+
+    /// The lookup id, derived from the plaintext token for unmigrated entries.
+    pub fn id(&self) -> String {
+        match &self.token {
+            Some(raw) if self.token_id.is_empty() => token_fingerprint(raw),
+            _ => self.token_id.clone(),
+        }
+    }
+
+    /// Replaces a plaintext token with its id and salted hash, and the
+    /// legacy admin flag with explicit scopes. Returns whether the entry
+    /// changed.
+    pub fn migrate(&mut self) -> bool {
+        let mut changed = false;
+        if self.admin || self.scopes.is_none() {
+            self.scopes = Some(self.effective_scopes());
+            self.admin = false;
+            changed = true;
+        }
+        if let Some(raw) = self.token.take() {
+            self.token_id = token_fingerprint(&raw);
+            self.token_hash = Some(TokenHash::new(&raw).encode());
+            changed = true;
+        }
+        changed
+    }
+
+    fn matches(&self, raw: &str) -> bool {
+        if let Some(plain) = &self.token {
+            return constant_time_eq(plain.as_bytes(), raw.as_bytes());
+        }
+        self.id() == token_fingerprint(raw)
+            && self
+                .token_hash
+                .as_deref()
+                .and_then(TokenHash::parse)
+                .is_some_and(|hash| hash.verify(raw))
+    }
+}
+
+/// Salted SHA-256 of a token, stored as `sha256$<salt hex>$<digest hex>`.
+/// Tokens are 192-bit random values, so a fast hash is enough to make a
+/// leaked tokens file useless without allowing offline guessing.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct TokenHash {
+    salt: [u8; 16],
+    digest: [u8; 32],
+}
+
+impl TokenHash {
+    pub fn new(raw: &str) -> Self {
+        let mut salt = [0u8; 16];
+        rand::thread_rng().fill_bytes(&mut salt);
+        Self {
+            digest: salted_digest(&salt, raw),
+            salt,
+        }
+    }
+
+    pub fn parse(encoded: &str) -> Option<Self> {
+        let mut parts = encoded.split('$');
+        if parts.next()? != "sha256" {
+            return None;
+        }
+        let salt = hex::decode(parts.next()?).ok()?.try_into().ok()?;
+        let digest = hex::decode(parts.next()?).ok()?.try_into().ok()?;
+        if parts.next().is_some() {
+            return None;
+        }
+        Some(Self { salt, digest })
+    }
+
+    pub fn encode(&self) -> String {
+        format!(
+            "sha256${}${}",
+            hex::encode(self.salt),
+            hex::encode(self.digest)
+        )
+    }
+
+    pub fn verify(&self, raw: &str) -> bool {
+        constant_time_eq(&salted_digest(&self.salt, raw), &self.digest)
+    }
+}
+
+fn salted_digest(salt: &[u8], raw: &str) -> [u8; 32] {
+    let mut hasher = Sha256::new();
+    hasher.update(salt);
+    hasher.update(raw.as_bytes());
+    hasher.finalize().into()
+}
+
+/// Compares without short-circuiting so timing does not reveal how many
+/// leading bytes matched.
+pub fn constant_time_eq(a: &[u8], b: &[u8]) -> bool {
+    if a.len() != b.len() {
+        return false;
+    }
+    let diff = a.iter().zip(b).fold(0u8, |acc, (x, y)| acc | (x ^ y));
+    std::hint::black_box(diff) == 0
+}
+
 fn load_token_file(path: &Path) -> Result<TokenFile, Box<dyn std::error::Error>> {
     if !path.exists() {
         return Ok(TokenFile { tokens: vec![] });
@@ -143,6 +611,7 @@ pub fn token_cli(args: &[String]) -> Result<(), Box<dyn std::error::Error>> {
         "create" => token_create(&args[1..]),
         "list" => token_list(&args[1..]),
         "revoke" => token_revoke(&args[1..]),
+        "migrate" => token_migrate(&args[1..]),
         _ => {
             print_token_help();
             Ok(())
@@ -155,6 +624,10 @@ fn token_create(args: &[String]) -> Result<(), Box<dyn std::error::Error>> {
     let mut expires_at: Option<String> = None;
     let mut rate_limit: Option<usize> = None;
     let mut never_expire = false;
+    let mut scopes: Vec<Scope> = Vec::new();
+    let mut max_upload_bytes: Option<usize> = None;
+    let mut quota_bytes: Option<u64> = None;
+    let mut quota_files: Option<u64> = None;
     let mut days: Option<i64> = None;
     let mut file_arg: Option<String> = None;
 
@@ -181,14 +654,50 @@ fn token_create(args: &[String]) -> Result<(), Box<dyn std::error::Error>> {
                 never_expire = true;
                 i += 1;
             }
-            "--rate-limit" => {
-                rate_limit = Some(
+            "--scope" => {
+                let raw = args.get(i + 1).ok_or("missing value for --scope")?;
+                for part in raw.split(',').filter(|p| !p.trim().is_empty()) {
+                    scopes.push(part.parse()?);
+                }
+                i += 2;
+            }
+            "--admin" => {
+                scopes.push(Scope::Admin);
+                i += 1;
+            }
+            "--max-upload-bytes" => {
+                max_upload_bytes = Some(
                     args.get(i + 1)
-                        .ok_or("missing value for --rate-limit")?
+                        .ok_or("missing value for --max-upload-bytes")?
                         .parse()?,
                 );
                 i += 2;
             }
+            "--quota-bytes" => {
+                quota_bytes = Some(
+                    args.get(i + 1)
+                        .ok_or("missing value for --quota-bytes")?
+                        .parse()?,
+                );
+                i += 2;
+            }
+            "--quota-files" => {
+                quota_files = Some(
+                    args.get(i + 1)
+                        .ok_or("missing value for --quota-files")?
+                        .parse()?,
+                );
+                i += 2;
+            }
+            "--rate-limit" => {
+                let limit = args
+                    .get(i + 1)
+                    .ok_or("missing value for --rate-limit")?
+                    .parse()?;
+                check_rate_limit(limit)?;
+                rate_limit = Some(limit);
+                i += 2;
+            }
             "--tokens-file" => {
                 file_arg = Some(
                     args.get(i + 1)
@@ -204,7 +713,8 @@ fn token_create(args: &[String]) -> Result<(), Box<dyn std::error::Error>> {
     if never_expire {
         expires_at = None;
     } else if let Some(d) = days {
-        expires_at = Some((Utc::now() + chrono::Duration::days(d)).to_rfc3339());
+        let at = expiry_after_days(d).ok_or("--days must be a positive number of days")?;
+        expires_at = Some(at.to_rfc3339());
     }
 
     if let Some(raw) = &expires_at {
@@ -215,18 +725,31 @@ fn token_create(args: &[String]) -> Result<(), Box<dyn std::error::Error>> {
     let path = resolve_tokens_file(file_arg.as_deref());
     let mut file = load_token_file(&path)?;
 
-    file.tokens.push(TokenEntry {
-        name: name.clone(),
-        token: token.clone(),
+    if scopes.is_empty() {
+        scopes = Scope::DEFAULT.to_vec();
+    }
+    scopes.sort();
+    scopes.dedup();
+
+    let entry = TokenEntry {
         expires_at: expires_at.clone(),
         rate_limit_per_minute: rate_limit,
-    });
+        scopes: Some(scopes.clone()),
+        max_upload_bytes,
+        quota_bytes,
+        quota_files,
+        ..TokenEntry::new(name.clone(), &token)
+    };
+    let token_id = entry.token_id.clone();
+    file.tokens.push(entry);
 
     save_token_file(&path, &file)?;
 
     println!("token created");
     println!("name: {name}");
     println!("token: {token}");
+    println!("token_id: {token_id}");
+    println!("(only a salted hash is stored; save the token now)");
     println!(
         "expires_at: {}",
         expires_at.unwrap_or_else(|| "never".to_string())
@@ -237,8 +760,12 @@ fn token_create(args: &[String]) -> Result<(), Box<dyn std::error::Error>> {
             .map(|v| v.to_string())
             .unwrap_or_else(|| "inherit-global".to_string())
     );
+    println!("scopes: {}", join_scopes(&scopes));
+    println!("max_upload_bytes: {}", or_unlimited(max_upload_bytes));
+    println!("quota_bytes: {}", or_unlimited(quota_bytes));
+    println!("quota_files: {}", or_unlimited(quota_files));
     println!("tokens_file: {}", path.display());
-    println!("restart imgd service to apply: sudo systemctl restart imgd");
+    println!("imgd picks up the change automatically (or run: sudo systemctl reload imgd)");
 
     Ok(())
 }
@@ -266,14 +793,23 @@ fn token_list(args: &[String]) -> Result<(), Box<dyn std::error::Error>> {
     println!("tokens_file: {}", path.display());
     for entry in file.tokens {
         println!(
-            "name={} expires_at={} rate_limit_per_minute={} token_id={}",
+            "name={} expires_at={} rate_limit_per_minute={} scopes={} max_upload_bytes={} quota_bytes={} quota_files={} token_id={} stored={}",
             entry.name,
-            entry.expires_at.unwrap_or_else(|| "never".to_string()),
+            entry.expires_at.as_deref().unwrap_or("never"),
             entry
                 .rate_limit_per_minute
                 .map(|v| v.to_string())
                 .unwrap_or_else(|| "inherit-global".to_string()),
-            token_fingerprint(&entry.token)
+            join_scopes(&entry.effective_scopes()),
+            or_unlimited(entry.max_upload_bytes),
+            or_unlimited(entry.quota_bytes),
+            or_unlimited(entry.quota_files),
+            entry.id(),
+            if entry.token.is_some() {
+                "plaintext"
+            } else {
+                "hash"
+            }
         );
     }
 
@@ -283,6 +819,7 @@ fn token_list(args: &[String]) -> Result<(), Box<dyn std::error::Error>> {
 fn token_revoke(args: &[String]) -> Result<(), Box<dyn std::error::Error>> {
     let mut by_name: Option<String> = None;
     let mut by_token: Option<String> = None;
+    let mut by_id: Option<String> = None;
     let mut file_arg: Option<String> = None;
 
     let mut i = 0usize;
@@ -296,6 +833,10 @@ fn token_revoke(args: &[String]) -> Result<(), Box<dyn std::error::Error>> {
                 by_token = Some(args.get(i + 1).ok_or("missing value for --token")?.clone());
                 i += 2;
             }
+            "--id" => {
+                by_id = Some(args.get(i + 1).ok_or("missing value for --id")?.clone());
+                i += 2;
+            }
             "--tokens-file" => {
                 file_arg = Some(
                     args.get(i + 1)
@@ -308,8 +849,8 @@ fn token_revoke(args: &[String]) -> Result<(), Box<dyn std::error::Error>> {
         }
     }
 
-    if by_name.is_none() && by_token.is_none() {
-        return Err("revoke requires --name or --token".into());
+    if by_name.is_none() && by_token.is_none() && by_id.is_none() {
+        return Err("revoke requires --name, --id or --token".into());
     }
 
     let path = resolve_tokens_file(file_arg.as_deref());
@@ -323,7 +864,12 @@ fn token_revoke(args: &[String]) -> Result<(), Box<dyn std::error::Error>> {
             }
         }
         if let Some(token) = &by_token {
-            if &entry.token == token {
+            if entry.matches(token) {
+                return false;
+            }
+        }
+        if let Some(id) = &by_id {
+            if &entry.id() == id {
                 return false;
             }
         }
@@ -335,12 +881,64 @@ fn token_revoke(args: &[String]) -> Result<(), Box<dyn std::error::Error>> {
         "removed {} token(s)",
         before.saturating_sub(file.tokens.len())
     );
-    println!("restart imgd service to apply: sudo systemctl restart imgd");
+    println!("imgd picks up the change automatically (or run: sudo systemctl reload imgd)");
 
     Ok(())
 }
 
-fn save_token_file(path: &Path, file: &TokenFile) -> Result<(), Box<dyn std::error::Error>> {
+fn token_migrate(args: &[String]) -> Result<(), Box<dyn std::error::Error>> {
+    let mut file_arg: Option<String> = None;
+    let mut i = 0usize;
+    while i < args.len() {
+        match args[i].as_str() {
+            "--tokens-file" => {
+                file_arg = Some(
+                    args.get(i + 1)
+                        .ok_or("missing value for --tokens-file")?
+                        .clone(),
+                );
+                i += 2;
+            }
+            other => return Err(format!("unknown arg: {other}").into()),
+        }
+    }
+
+    let path = resolve_tokens_file(file_arg.as_deref());
+    let mut file = load_token_file(&path)?;
+
+    let mut migrated = 0usize;
+    for entry in &mut file.tokens {
+        if entry.migrate() {
+            migrated += 1;
+        }
+    }
+    if migrated > 0 {
+        save_token_file(&path, &file)?;
+    }
+    println!("tokens_file: {}", path.display());
+    println!("migrated {migrated} token(s) to salted hashes and explicit scopes");
+
+    Ok(())
+}
+
+fn or_unlimited<T: ToString>(value: Option<T>) -> String {
+    value
+        .map(|v| v.to_string())
+        .unwrap_or_else(|| "unlimited".to_string())
+}
+
+fn join_scopes(scopes: &[Scope]) -> String {
+    scopes
+        .iter()
+        .map(|s| s.as_str())
+        .collect::<Vec<_>>()
+        .join(",")
+}
+
+pub(crate) fn save_token_file(
+    path: &Path,
+    file: &TokenFile,
+) -> Result<(), Box<dyn std::error::Error>> {
     if let Some(parent) = path.parent() {
         fs::create_dir_all(parent)?;
     }
@@ -352,7 +950,7 @@ fn save_token_file(path: &Path, file: &TokenFile) -> Result<(), Box<dyn std::err
     Ok(())
 }
 
-fn generate_token() -> String {
+pub(crate) fn generate_token() -> String {
     let mut bytes = [0u8; 24];
     rand::thread_rng().fill_bytes(&mut bytes);
     hex::encode(bytes)
@@ -367,7 +965,64 @@ pub fn token_fingerprint(token: &str) -> String {
 
 fn print_token_help() {
     println!("imgd token commands:");
-    println!("  imgd token create [--name N] [--expires-at RFC3339 | --days N | --never-expire] [--rate-limit N] [--tokens-file PATH]");
+    println!("  imgd token create [--name N] [--expires-at RFC3339 | --days N | --never-expire] [--rate-limit N] [--scope upload,delete,list,admin,metrics] [--admin] [--max-upload-bytes N] [--quota-bytes N] [--quota-files N] [--tokens-file PATH]");
     println!("  imgd token list [--tokens-file PATH]");
-    println!("  imgd token revoke (--name N | --token TOKEN) [--tokens-file PATH]");
+    println!("  imgd token revoke (--name N | --id TOKEN_ID | --token TOKEN) [--tokens-file PATH]");
+    println!("  imgd token migrate [--tokens-file PATH]");
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{token_fingerprint, Scope, TokenEntry, TokenHash};
+
+    #[test]
+    fn hash_round_trip_and_verify() {
+        let hash = TokenHash::new("secret");
+        let parsed = TokenHash::parse(&hash.encode()).expect("parse");
+        assert_eq!(parsed, hash);
+        assert!(parsed.verify("secret"));
+        assert!(!parsed.verify("secreT"));
+        // Salts differ, so equal tokens do not produce equal hashes.
+        assert_ne!(TokenHash::new("secret").encode(), hash.encode());
+        assert!(TokenHash::parse("md5$00$00").is_none());
+        assert!(TokenHash::parse("sha256$zz$00").is_none());
+    }
+
+    #[test]
+    fn migrate_replaces_plaintext() {
+        let mut entry: TokenEntry =
+            serde_json::from_str(r#"{"name":"ci","token":"abc123"}"#).expect("entry");
+        assert_eq!(entry.id(), token_fingerprint("abc123"));
+        assert!(entry.matches("abc123"));
+
+        assert!(entry.migrate());
+        assert!(!entry.migrate());
+        assert!(entry.token.is_none());
+        assert_eq!(entry.token_id, token_fingerprint("abc123"));
+        assert!(entry.matches("abc123"));
+        assert!(!entry.matches("abc124"));
+
+        let json = serde_json::to_string(&entry).expect("json");
+        assert!(!json.contains("abc123"));
+    }
+
+    #[test]
+    fn scopes_default_and_legacy_admin_flag() {
+        let entry: TokenEntry =
+            serde_json::from_str(r#"{"name":"ci","token":"abc"}"#).expect("entry");
+        assert_eq!(entry.effective_scopes(), Scope::DEFAULT.to_vec());
+
+        let mut entry: TokenEntry =
+            serde_json::from_str(r#"{"name":"ops","token":"abc","admin":true}"#).expect("entry");
+        assert!(entry.effective_scopes().contains(&Scope::Admin));
+        assert!(entry.migrate());
+        assert!(!entry.admin);
+        assert!(entry.scopes.as_ref().unwrap().contains(&Scope::Admin));
+
+        let entry: TokenEntry =
+            serde_json::from_str(r#"{"name":"mon","token":"abc","scopes":["metrics"]}"#)
+                .expect("entry");
+        assert_eq!(entry.effective_scopes(), vec![Scope::Metrics]);
+        assert!("bogus".parse::<Scope>().is_err());
+    }
 }
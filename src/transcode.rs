@@ -0,0 +1,296 @@
+use std::{io::Cursor, sync::Arc};
+
+use image::{imageops::FilterType, ImageReader, Limits};
+use thiserror::Error;
+use tokio::sync::Semaphore;
+
+use crate::format::ImageFormat;
+
+/// Largest width or height accepted for decoding, to bound memory use.
+const MAX_DIMENSION: u32 = 16_384;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum WebpEncoding {
+    Lossy { quality: f32 },
+    Lossless,
+}
+
+impl WebpEncoding {
+    /// Parses the `CONVERT_TO_WEBP` mode; `off` yields `None`.
+    pub fn from_mode(mode: &str, quality: f32) -> Result<Option<Self>, String> {
+        match mode.trim().to_ascii_lowercase().as_str() {
+            "" | "off" | "false" | "0" => Ok(None),
+            "lossy" => {
+                if !(0.0..=100.0).contains(&quality) {
+                    return Err(format!("quality must be within 0..=100, got {quality}"));
+                }
+                Ok(Some(WebpEncoding::Lossy { quality }))
+            }
+            "lossless" => Ok(Some(WebpEncoding::Lossless)),
+            other => Err(format!("unknown conversion mode: {other}")),
+        }
+    }
+}
+
+#[derive(Debug, Error)]
+pub enum TranscodeError {
+    #[error("decode failed: {0}")]
+    Decode(#[from] image::ImageError),
+    #[error("encode failed: {0}")]
+    Encode(String),
+    #[error("cannot decode {0}")]
+    Unsupported(ImageFormat),
+    #[error("transcode worker failed")]
+    Worker,
+}
+
+/// Formats that can be decoded and re-encoded as WebP.
+pub fn can_transcode(format: ImageFormat) -> bool {
+    matches!(format, ImageFormat::Png | ImageFormat::Jpeg)
+}
+
+/// Decodes a PNG or JPEG image and re-encodes it as WebP.
+pub fn to_webp(
+    data: &[u8],
+    format: ImageFormat,
+    encoding: WebpEncoding,
+) -> Result<Vec<u8>, TranscodeError> {
+    let image_format = match format {
+        ImageFormat::Png => image::ImageFormat::Png,
+        ImageFormat::Jpeg => image::ImageFormat::Jpeg,
+        other => return Err(TranscodeError::Unsupported(other)),
+    };
+
+    let mut limits = Limits::default();
+    limits.max_image_width = Some(MAX_DIMENSION);
+    limits.max_image_height = Some(MAX_DIMENSION);
+
+    let mut reader = ImageReader::with_format(Cursor::new(data), image_format);
+    reader.limits(limits);
+    let decoded = reader.decode()?;
+
+    encode_webp(&decoded, encoding)
+}
+
+/// How a variant is fitted into its `width` x `height` box.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Fit {
+    /// Scale down to fit entirely inside the box, keeping the aspect ratio.
+    Contain,
+    /// Scale and center-crop so the box is completely filled.
+    Cover,
+}
+
+impl Fit {
+    pub fn as_str(self) -> &'static str {
+        match self {
+            Fit::Contain => "contain",
+            Fit::Cover => "cover",
+        }
+    }
+
+    pub fn parse(raw: &str) -> Option<Self> {
+        match raw {
+            "contain" => Some(Fit::Contain),
+            "cover" => Some(Fit::Cover),
+            _ => None,
+        }
+    }
+}
+
+/// Parses a `VARIANT_SIZES` list such as `320x240,640x0`, where `0` leaves
+/// that side unconstrained.
+pub fn parse_size_list(raw: &str) -> Result<Vec<(u32, u32)>, String> {
+    let mut sizes = Vec::new();
+    for part in raw.split(',').map(str::trim).filter(|p| !p.is_empty()) {
+        let (w, h) = part
+            .split_once('x')
+            .ok_or_else(|| format!("expected WxH, got {part}"))?;
+        let w: u32 = w.parse().map_err(|_| format!("invalid width in {part}"))?;
+        let h: u32 = h.parse().map_err(|_| format!("invalid height in {part}"))?;
+        if (w == 0 && h == 0) || w > MAX_DIMENSION || h > MAX_DIMENSION {
+            return Err(format!("size out of range: {part}"));
+        }
+        if !sizes.contains(&(w, h)) {
+            sizes.push((w, h));
+        }
+    }
+    Ok(sizes)
+}
+
+/// Decodes a stored image and renders a resized WebP variant. Images that
+/// already fit inside a `Contain` box are re-encoded without upscaling.
+pub fn resize_to_webp(
+    data: &[u8],
+    format: ImageFormat,
+    (width, height): (u32, u32),
+    fit: Fit,
+    encoding: WebpEncoding,
+) -> Result<Vec<u8>, TranscodeError> {
+    let image_format = match format {
+        ImageFormat::Webp => image::ImageFormat::WebP,
+        ImageFormat::Png => image::ImageFormat::Png,
+        ImageFormat::Jpeg => image::ImageFormat::Jpeg,
+        ImageFormat::Gif => image::ImageFormat::Gif,
+        ImageFormat::Avif => return Err(TranscodeError::Unsupported(format)),
+    };
+
+    let mut limits = Limits::default();
+    limits.max_image_width = Some(MAX_DIMENSION);
+    limits.max_image_height = Some(MAX_DIMENSION);
+
+    let mut reader = ImageReader::with_format(Cursor::new(data), image_format);
+    reader.limits(limits);
+    let decoded = reader.decode()?;
+
+    let box_w = if width == 0 { u32::MAX } else { width };
+    let box_h = if height == 0 { u32::MAX } else { height };
+    let resized = match fit {
+        Fit::Cover if width > 0 && height > 0 => {
+            decoded.resize_to_fill(width, height, FilterType::Lanczos3)
+        }
+        _ if decoded.width() <= box_w && decoded.height() <= box_h => decoded,
+        _ => decoded.resize(box_w, box_h, FilterType::Lanczos3),
+    };
+
+    encode_webp(&resized, encoding)
+}
+
+fn encode_webp(
+    image: &image::DynamicImage,
+    encoding: WebpEncoding,
+) -> Result<Vec<u8>, TranscodeError> {
+    let (width, height) = (image.width(), image.height());
+    let encoded = if image.color().has_alpha() {
+        let rgba = image.to_rgba8();
+        encode_pixels(webp::Encoder::from_rgba(&rgba, width, height), encoding)?
+    } else {
+        let rgb = image.to_rgb8();
+        encode_pixels(webp::Encoder::from_rgb(&rgb, width, height), encoding)?
+    };
+    Ok(encoded)
+}
+
+fn encode_pixels(
+    encoder: webp::Encoder<'_>,
+    encoding: WebpEncoding,
+) -> Result<Vec<u8>, TranscodeError> {
+    let memory = match encoding {
+        WebpEncoding::Lossy { quality } => encoder.encode_simple(false, quality),
+        WebpEncoding::Lossless => encoder.encode_simple(true, 75.0),
+    }
+    .map_err(|e| TranscodeError::Encode(format!("{e:?}")))?;
+    Ok(memory.to_vec())
+}
+
+/// Runs CPU-heavy image work on tokio's blocking threads, capped at a fixed
+/// number of concurrent jobs so uploads cannot exhaust the blocking pool.
+#[derive(Clone)]
+pub struct Transcoder {
+    permits: Arc<Semaphore>,
+}
+
+impl Transcoder {
+    pub fn new(workers: usize) -> Self {
+        Self {
+            permits: Arc::new(Semaphore::new(workers.max(1))),
+        }
+    }
+
+    pub async fn run<T, F>(&self, job: F) -> Result<T, TranscodeError>
+    where
+        F: FnOnce() -> Result<T, TranscodeError> + Send + 'static,
+        T: Send + 'static,
+    {
+        let _permit = self
+            .permits
+            .clone()
+            .acquire_owned()
+            .await
+            .map_err(|_| TranscodeError::Worker)?;
+        tokio::task::spawn_blocking(job)
+            .await
+            .map_err(|_| TranscodeError::Worker)?
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use image::{DynamicImage, ImageFormat as CodecFormat, RgbaImage};
+
+    use super::{parse_size_list, resize_to_webp, to_webp, Fit, WebpEncoding};
+    use crate::format::ImageFormat;
+
+    fn png_bytes() -> Vec<u8> {
+        let image =
+            DynamicImage::ImageRgba8(RgbaImage::from_pixel(4, 3, image::Rgba([200, 10, 10, 128])));
+        let mut out = std::io::Cursor::new(Vec::new());
+        image.write_to(&mut out, CodecFormat::Png).expect("png");
+        out.into_inner()
+    }
+
+    #[test]
+    fn png_to_lossy_and_lossless_webp() {
+        let png = png_bytes();
+        for encoding in [
+            WebpEncoding::Lossy { quality: 80.0 },
+            WebpEncoding::Lossless,
+        ] {
+            let out = to_webp(&png, ImageFormat::Png, encoding).expect("transcode");
+            assert_eq!(ImageFormat::detect(&out), Some(ImageFormat::Webp));
+        }
+    }
+
+    #[test]
+    fn corrupt_input_is_a_decode_error() {
+        let mut png = png_bytes();
+        png.truncate(20);
+        assert!(to_webp(&png, ImageFormat::Png, WebpEncoding::Lossless).is_err());
+    }
+
+    #[test]
+    fn resize_contain_and_cover() {
+        let png = png_bytes();
+        let encoding = WebpEncoding::Lossy { quality: 80.0 };
+
+        let out = resize_to_webp(&png, ImageFormat::Png, (2, 0), Fit::Contain, encoding).unwrap();
+        let info = crate::format::inspect(ImageFormat::Webp, &out).unwrap();
+        assert_eq!((info.width, info.height), (2, 2));
+
+        let out = resize_to_webp(&png, ImageFormat::Png, (2, 2), Fit::Cover, encoding).unwrap();
+        let info = crate::format::inspect(ImageFormat::Webp, &out).unwrap();
+        assert_eq!((info.width, info.height), (2, 2));
+
+        // Never upscales in contain mode.
+        let out =
+            resize_to_webp(&png, ImageFormat::Png, (100, 100), Fit::Contain, encoding).unwrap();
+        let info = crate::format::inspect(ImageFormat::Webp, &out).unwrap();
+        assert_eq!((info.width, info.height), (4, 3));
+    }
+
+    #[test]
+    fn parse_sizes() {
+        assert_eq!(
+            parse_size_list("320x240, 640x0,320x240"),
+            Ok(vec![(320, 240), (640, 0)])
+        );
+        assert!(parse_size_list("0x0").is_err());
+        assert!(parse_size_list("320").is_err());
+        assert!(parse_size_list("99999x1").is_err());
+    }
+
+    #[test]
+    fn parse_conversion_mode() {
+        assert_eq!(WebpEncoding::from_mode("off", 80.0), Ok(None));
+        assert_eq!(
+            WebpEncoding::from_mode("lossy", 75.0),
+            Ok(Some(WebpEncoding::Lossy { quality: 75.0 }))
+        );
+        assert_eq!(
+            WebpEncoding::from_mode("Lossless", 80.0),
+            Ok(Some(WebpEncoding::Lossless))
+        );
+        assert!(WebpEncoding::from_mode("lossy", 101.0).is_err());
+        assert!(WebpEncoding::from_mode("fast", 80.0).is_err());
+    }
+}
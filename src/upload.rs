@@ -1,26 +1,32 @@
-use std::{
-    net::SocketAddr,
-    path::{Path, PathBuf},
-    sync::atomic::Ordering,
-    time::Instant,
-};
+use std::{net::IpAddr, pin::pin, time::Instant};
 
 use axum::{
-    extract::{connect_info::ConnectInfo, Multipart, State},
-    http::HeaderMap,
-    Json,
+    body::Bytes,
+    extract::{multipart::Field, FromRequest, Multipart, Query, Request, State},
+    http::{header, HeaderMap, Method},
+    response::{IntoResponse, Response},
+    Extension, Json,
 };
 use chrono::{Datelike, Utc};
-use serde::Serialize;
+use futures_util::{Stream, StreamExt};
+use serde::{Deserialize, Serialize};
 use sha2::{Digest, Sha256};
-use tokio::{
-    fs::{self, File, OpenOptions},
-    io::AsyncWriteExt,
-};
 use tracing::{error, info, warn};
-use uuid::Uuid;
 
-use crate::{error::AppError, webp, AppState};
+use crate::{
+    client_ip::ClientIp,
+    error::AppError,
+    fetch::FetchError,
+    format::{inspect, ImageFormat, ImageInfo, SNIFF_LEN},
+    index::{ImageRecord, Refusal, MAX_FILENAME_CHARS},
+    ticket::TicketClaims,
+    token::AuthorizedToken,
+    transcode::{self, TranscodeError},
+    webp, AppState,
+};
+
+/// Original filename of a raw-body upload, e.g. `X-Filename: cat.png`.
+const FILENAME_HEADER: &str = "x-filename";
 
 #[derive(Serialize)]
 pub struct UploadResponse {
@@ -28,180 +34,608 @@ pub struct UploadResponse {
     pub path: String,
     pub sha256: String,
     pub size: u64,
+    #[serde(flatten)]
+    pub image: ImageInfo,
+}
+
+/// Body of `POST /upload/url`.
+#[derive(Debug, Deserialize)]
+pub struct UrlUploadRequest {
+    pub url: String,
+}
+
+/// Outcome of one file in a multi-file upload: the same object as a single
+/// upload, or the error body that file would have received.
+#[derive(Serialize)]
+#[serde(untagged)]
+pub enum BatchItem {
+    Stored(UploadResponse),
+    Failed {
+        error: String,
+        #[serde(skip_serializing_if = "Option::is_none")]
+        detail: Option<String>,
+    },
+}
+
+impl BatchItem {
+    fn error(err: AppError, detail: Option<&str>) -> Self {
+        BatchItem::Failed {
+            error: err.to_string(),
+            detail: detail.map(str::to_owned),
+        }
+    }
 }
 
+impl From<Result<UploadResponse, AppError>> for BatchItem {
+    fn from(result: Result<UploadResponse, AppError>) -> Self {
+        match result {
+            Ok(resp) => BatchItem::Stored(resp),
+            Err(err) => BatchItem::Failed {
+                detail: err.detail(),
+                error: err.to_string(),
+            },
+        }
+    }
+}
+
+/// Per-request options passed as query parameters, e.g.
+/// `/upload?strip_metadata=true&strip_icc=true`. They can only tighten the
+/// configured behaviour, never relax it.
+#[derive(Debug, Default, Deserialize)]
+pub struct UploadParams {
+    #[serde(default)]
+    pub strip_metadata: bool,
+    #[serde(default)]
+    pub strip_icc: bool,
+    /// Answer a multipart upload with an array even for a single file.
+    #[serde(default)]
+    pub batch: bool,
+}
+
+/// `POST /upload` with a multipart `file` field, or the raw image as the
+/// body of `PUT /upload` (or of a `POST` with an image `Content-Type`).
 pub async fn upload_handler(
     State(state): State<AppState>,
-    ConnectInfo(addr): ConnectInfo<SocketAddr>,
+    ClientIp(ip): ClientIp,
+    Extension(auth): Extension<AuthorizedToken>,
+    ticket: Option<Extension<TicketClaims>>,
+    Query(params): Query<UploadParams>,
     headers: HeaderMap,
-    mut multipart: Multipart,
-) -> Result<Json<UploadResponse>, AppError> {
+    req: Request,
+) -> Result<Response, AppError> {
     let started = Instant::now();
-    let ip = addr.ip();
     let request_id = headers
         .get("x-request-id")
         .and_then(|v| v.to_str().ok())
         .unwrap_or("-");
+    let ctx = UploadContext {
+        ip,
+        request_id,
+        started,
+        auth: &auth,
+        ticket: ticket.as_ref().map(|Extension(ticket)| ticket),
+        params: &params,
+    };
 
-    while let Some(field) = multipart.next_field().await? {
-        if field.name() != Some("file") {
-            state.metrics.upload_fail.fetch_add(1, Ordering::Relaxed);
-            warn!(ip = %ip, request_id, elapsed_ms = started.elapsed().as_millis(), result = "fail", reason = "invalid_field", "upload rejected");
-            return Err(AppError::BadRequest);
-        }
+    let content_type = headers
+        .get(header::CONTENT_TYPE)
+        .and_then(|v| v.to_str().ok())
+        .unwrap_or_default();
+    if req.method() == Method::POST && content_type.starts_with("multipart/") {
+        let multipart = Multipart::from_request(req, &state)
+            .await
+            .map_err(|_| AppError::BadRequest)?;
+        return upload_multipart(&state, ctx, multipart).await;
+    }
 
-        let filename = field.file_name().ok_or_else(|| {
-            state.metrics.upload_fail.fetch_add(1, Ordering::Relaxed);
+    let format = ImageFormat::from_content_type(content_type);
+    if req.method() == Method::POST && format.is_none() {
+        state.metrics.upload_failed(&auth.name, "content_type");
+        warn!(ip = %ip, request_id, content_type, elapsed_ms = started.elapsed().as_millis(), result = "fail", reason = "content_type", "upload rejected");
+        return Err(AppError::UnsupportedMediaType);
+    }
+    // The name only feeds the extension check and the index, so without a
+    // header one is made up from the content type.
+    let filename = headers
+        .get(FILENAME_HEADER)
+        .and_then(|v| v.to_str().ok())
+        .filter(|name| !name.is_empty())
+        .map(str::to_owned)
+        .or_else(|| format.map(|f| format!("upload.{}", f.extension())))
+        .ok_or_else(|| {
+            state.metrics.upload_failed(&auth.name, "missing_filename");
             warn!(ip = %ip, request_id, elapsed_ms = started.elapsed().as_millis(), result = "fail", reason = "missing_filename", "upload rejected");
             AppError::BadRequest
         })?;
 
-        if !webp::has_webp_extension(filename) {
-            state.metrics.upload_fail.fetch_add(1, Ordering::Relaxed);
-            warn!(ip = %ip, request_id, elapsed_ms = started.elapsed().as_millis(), result = "fail", reason = "extension", "upload rejected");
-            return Err(AppError::UnsupportedMediaType);
+    let body = req.into_body().into_data_stream();
+    let (data, sha256) = receive(&state, ctx, &filename, body, "body_read").await?;
+    process(&state, ctx, &filename, data, sha256)
+        .await
+        .map(|resp| Json(resp).into_response())
+}
+
+/// A single `file` field answers with its upload as before; several, or any
+/// number with `?batch=true`, answer with one [`BatchItem`] per field, in
+/// order, each stored or rejected on its own. A ticket authorizes exactly
+/// one file.
+async fn upload_multipart(
+    state: &AppState,
+    ctx: UploadContext<'_>,
+    mut multipart: Multipart,
+) -> Result<Response, AppError> {
+    let UploadContext {
+        ip,
+        request_id,
+        started,
+        auth,
+        ..
+    } = ctx;
+
+    let Some(field) = multipart.next_field().await? else {
+        state.metrics.upload_failed(&auth.name, "missing_file");
+        warn!(ip = %ip, request_id, elapsed_ms = started.elapsed().as_millis(), result = "fail", reason = "missing_file", "upload rejected");
+        return Err(AppError::BadRequest);
+    };
+    if ctx.ticket.is_some() {
+        // Read the whole request before storing, so a ticket that arrives
+        // with more than one file stores nothing.
+        let (filename, data, sha256) = read_field(state, ctx, field).await?;
+        if !matches!(multipart.next_field().await, Ok(None)) {
+            state
+                .metrics
+                .upload_failed(&auth.name, "ticket_single_file");
+            warn!(ip = %ip, request_id, elapsed_ms = started.elapsed().as_millis(), result = "fail", reason = "ticket_single_file", "upload rejected");
+            return Err(AppError::BadRequest);
         }
+        let resp = process(state, ctx, &filename, data, sha256).await;
+        return if ctx.params.batch {
+            Ok(Json(vec![BatchItem::from(resp)]).into_response())
+        } else {
+            resp.map(|resp| Json(resp).into_response())
+        };
+    }
 
-        let tmp_dir = state.config.data_dir.join(".tmp");
-        fs::create_dir_all(&tmp_dir).await?;
+    let first = upload_field(state, ctx, field).await;
+    // Anything unreadable after a lone file was never looked at before
+    // batches existed, so it still is not.
+    let mut next = match multipart.next_field().await {
+        Ok(Some(field)) => Some(field),
+        _ if !ctx.params.batch => return first.map(|resp| Json(resp).into_response()),
+        _ => None,
+    };
 
-        let tmp_name = format!(".uploading-{}", Uuid::new_v4());
-        let tmp_path = tmp_dir.join(tmp_name);
+    let mut items = vec![BatchItem::from(first)];
+    while let Some(field) = next {
+        if items.len() == state.config.max_files_per_upload {
+            state.metrics.upload_failed(&auth.name, "too_many_files");
+            warn!(ip = %ip, request_id, files = items.len(), elapsed_ms = started.elapsed().as_millis(), result = "fail", reason = "too_many_files", "upload rejected");
+            items.push(BatchItem::error(
+                AppError::BadRequest,
+                Some("too_many_files"),
+            ));
+            break;
+        }
+        items.push(upload_field(state, ctx, field).await.into());
+        next = match multipart.next_field().await {
+            Ok(next) => next,
+            Err(_) => {
+                items.push(BatchItem::error(
+                    AppError::BadRequest,
+                    Some("multipart_read"),
+                ));
+                break;
+            }
+        };
+    }
+    Ok(Json(items).into_response())
+}
 
-        let mut writer = create_new_file(&tmp_path).await?;
-        let mut hasher = Sha256::new();
-        let mut header = Vec::with_capacity(12);
-        let mut size: u64 = 0;
+async fn upload_field(
+    state: &AppState,
+    ctx: UploadContext<'_>,
+    field: Field<'_>,
+) -> Result<UploadResponse, AppError> {
+    let (filename, data, sha256) = read_field(state, ctx, field).await?;
+    process(state, ctx, &filename, data, sha256).await
+}
 
-        let mut field = field;
-        loop {
-            let chunk = match field.chunk().await {
-                Ok(Some(chunk)) => chunk,
-                Ok(None) => break,
-                Err(_) => {
-                    let _ = fs::remove_file(&tmp_path).await;
-                    state.metrics.upload_fail.fetch_add(1, Ordering::Relaxed);
-                    warn!(ip = %ip, request_id, elapsed_ms = started.elapsed().as_millis(), result = "fail", reason = "multipart_read", "upload rejected");
-                    return Err(AppError::BadRequest);
-                }
-            };
+/// Receives a `file` field, returning its file name, bytes and sha256.
+async fn read_field(
+    state: &AppState,
+    ctx: UploadContext<'_>,
+    field: Field<'_>,
+) -> Result<(String, Vec<u8>, String), AppError> {
+    let UploadContext {
+        ip,
+        request_id,
+        started,
+        auth,
+        ..
+    } = ctx;
 
-            size = size.saturating_add(chunk.len() as u64);
-            if size > state.config.max_upload_bytes as u64 {
-                let _ = fs::remove_file(&tmp_path).await;
-                state.metrics.upload_fail.fetch_add(1, Ordering::Relaxed);
-                warn!(ip = %ip, request_id, size, elapsed_ms = started.elapsed().as_millis(), result = "fail", reason = "too_large", "upload rejected");
-                return Err(AppError::FileTooLarge);
-            }
+    if field.name() != Some("file") {
+        state.metrics.upload_failed(&auth.name, "invalid_field");
+        warn!(ip = %ip, request_id, elapsed_ms = started.elapsed().as_millis(), result = "fail", reason = "invalid_field", "upload rejected");
+        return Err(AppError::BadRequest);
+    }
 
-            if header.len() < 12 {
-                let need = 12 - header.len();
-                let take = need.min(chunk.len());
-                header.extend_from_slice(&chunk[..take]);
-            }
+    let filename = field.file_name().map(str::to_owned).ok_or_else(|| {
+        state.metrics.upload_failed(&auth.name, "missing_filename");
+        warn!(ip = %ip, request_id, elapsed_ms = started.elapsed().as_millis(), result = "fail", reason = "missing_filename", "upload rejected");
+        AppError::BadRequest
+    })?;
 
-            if writer.write_all(&chunk).await.is_err() {
-                let _ = fs::remove_file(&tmp_path).await;
-                state.metrics.upload_fail.fetch_add(1, Ordering::Relaxed);
-                error!(ip = %ip, request_id, elapsed_ms = started.elapsed().as_millis(), result = "fail", reason = "tmp_write", "upload failed");
-                return Err(AppError::Internal);
-            }
-            hasher.update(&chunk);
+    let (data, sha256) = receive(state, ctx, &filename, field, "multipart_read").await?;
+    Ok((filename, data, sha256))
+}
+
+/// `POST /upload/url`: fetches an image over HTTP(S) and stores it as if it
+/// had been uploaded, with the same limits and checks.
+pub async fn upload_url_handler(
+    State(state): State<AppState>,
+    ClientIp(ip): ClientIp,
+    Extension(auth): Extension<AuthorizedToken>,
+    ticket: Option<Extension<TicketClaims>>,
+    Query(params): Query<UploadParams>,
+    headers: HeaderMap,
+    Json(req): Json<UrlUploadRequest>,
+) -> Result<Json<UploadResponse>, AppError> {
+    let started = Instant::now();
+    let request_id = headers
+        .get("x-request-id")
+        .and_then(|v| v.to_str().ok())
+        .unwrap_or("-");
+    let ctx = UploadContext {
+        ip,
+        request_id,
+        started,
+        auth: &auth,
+        ticket: ticket.as_ref().map(|Extension(ticket)| ticket),
+        params: &params,
+    };
+
+    let resp = match state.fetcher.get(&req.url).await {
+        Ok(resp) => resp,
+        Err(FetchError::InvalidUrl) => {
+            state.metrics.upload_failed(&auth.name, "invalid_url");
+            warn!(ip = %ip, request_id, elapsed_ms = started.elapsed().as_millis(), result = "fail", reason = "invalid_url", "upload rejected");
+            return Err(AppError::BadRequest);
+        }
+        Err(FetchError::Blocked) => {
+            state.metrics.upload_failed(&auth.name, "blocked_address");
+            warn!(ip = %ip, request_id, url = %req.url, elapsed_ms = started.elapsed().as_millis(), result = "fail", reason = "blocked_address", "upload rejected");
+            return Err(AppError::Forbidden);
+        }
+        Err(FetchError::Upstream(detail)) => {
+            state.metrics.upload_failed(&auth.name, "fetch");
+            warn!(ip = %ip, request_id, url = %req.url, detail = %detail, elapsed_ms = started.elapsed().as_millis(), result = "fail", reason = "fetch", "upload rejected");
+            return Err(AppError::FetchFailed(detail));
+        }
+    };
+
+    let content_type = resp
+        .headers()
+        .get(header::CONTENT_TYPE)
+        .and_then(|v| v.to_str().ok())
+        .and_then(ImageFormat::from_content_type);
+    let filename = fetched_filename(resp.url(), content_type);
+    // A read error here is the remote side failing, not the client.
+    let (data, sha256) = receive(&state, ctx, &filename, resp.bytes_stream(), "fetch_read")
+        .await
+        .map_err(|err| match err {
+            AppError::BadRequest => AppError::FetchFailed("read".to_owned()),
+            err => err,
+        })?;
+    process(&state, ctx, &filename, data, sha256)
+        .await
+        .map(Json)
+}
+
+/// The last path segment of the final URL, given an extension from the
+/// `Content-Type` when it has no image extension of its own.
+fn fetched_filename(url: &reqwest::Url, content_type: Option<ImageFormat>) -> String {
+    let name = url
+        .path_segments()
+        .and_then(|mut segments| segments.next_back())
+        .filter(|name| !name.is_empty())
+        .unwrap_or("upload");
+    match content_type {
+        Some(format) if ImageFormat::from_filename(name).is_none() => {
+            format!("{name}.{}", format.extension())
         }
+        _ => name.to_owned(),
+    }
+}
 
-        if writer.flush().await.is_err() {
-            let _ = fs::remove_file(&tmp_path).await;
-            state.metrics.upload_fail.fetch_add(1, Ordering::Relaxed);
-            error!(ip = %ip, request_id, elapsed_ms = started.elapsed().as_millis(), result = "fail", reason = "tmp_flush", "upload failed");
-            return Err(AppError::Internal);
+/// Collects an upload in memory while hashing it, enforcing the extension
+/// and size limits as it arrives, and returns the bytes with their sha256.
+/// `read_reason` labels a failure of the incoming stream itself.
+async fn receive<S, E>(
+    state: &AppState,
+    ctx: UploadContext<'_>,
+    filename: &str,
+    body: S,
+    read_reason: &'static str,
+) -> Result<(Vec<u8>, String), AppError>
+where
+    S: Stream<Item = Result<Bytes, E>>,
+{
+    let UploadContext {
+        ip,
+        request_id,
+        started,
+        auth,
+        ..
+    } = ctx;
+
+    let allowed = &state.config.allowed_formats;
+    if !ImageFormat::from_filename(filename).is_some_and(|f| allowed.contains(&f)) {
+        state.metrics.upload_failed(&auth.name, "extension");
+        warn!(ip = %ip, request_id, elapsed_ms = started.elapsed().as_millis(), result = "fail", reason = "extension", "upload rejected");
+        return Err(AppError::UnsupportedMediaType);
+    }
+
+    let max_bytes = auth
+        .max_upload_bytes
+        .map_or(state.config.max_upload_bytes, |limit| {
+            limit.min(state.config.max_upload_bytes)
+        }) as u64;
+
+    let mut data = Vec::new();
+    let mut hasher = Sha256::new();
+
+    let mut body = pin!(body);
+    while let Some(chunk) = body.next().await {
+        let Ok(chunk) = chunk else {
+            state.metrics.upload_failed(&auth.name, read_reason);
+            warn!(ip = %ip, request_id, elapsed_ms = started.elapsed().as_millis(), result = "fail", reason = read_reason, "upload rejected");
+            return Err(AppError::BadRequest);
+        };
+
+        let size = (data.len() + chunk.len()) as u64;
+        if size > max_bytes {
+            state.metrics.upload_failed(&auth.name, "too_large");
+            warn!(ip = %ip, request_id, size, elapsed_ms = started.elapsed().as_millis(), result = "fail", reason = "too_large", "upload rejected");
+            return Err(AppError::FileTooLarge);
         }
-        drop(writer);
+        hasher.update(&chunk);
+        data.extend_from_slice(&chunk);
+    }
+
+    Ok((data, hex::encode(hasher.finalize())))
+}
+
+/// Who is uploading, threaded through [`process`] for checks, logs and metrics.
+#[derive(Clone, Copy)]
+pub(crate) struct UploadContext<'a> {
+    pub ip: IpAddr,
+    pub request_id: &'a str,
+    pub started: Instant,
+    pub auth: &'a AuthorizedToken,
+    pub ticket: Option<&'a TicketClaims>,
+    pub params: &'a UploadParams,
+}
+
+/// Validates, converts and stores a fully received upload. `sha256` is the
+/// digest of `data`, computed while it was received.
+pub(crate) async fn process(
+    state: &AppState,
+    ctx: UploadContext<'_>,
+    filename: &str,
+    mut data: Vec<u8>,
+    mut sha256: String,
+) -> Result<UploadResponse, AppError> {
+    let UploadContext {
+        ip,
+        request_id,
+        started,
+        auth,
+        ticket,
+        params,
+    } = ctx;
+    let allowed = &state.config.allowed_formats;
+    let mut size = data.len() as u64;
+
+    let Some(mut format) = ImageFormat::detect(&data[..data.len().min(SNIFF_LEN)]) else {
+        state.metrics.upload_failed(&auth.name, "signature");
+        warn!(ip = %ip, request_id, size, elapsed_ms = started.elapsed().as_millis(), result = "fail", reason = "signature", "upload rejected");
+        return Err(AppError::UnsupportedMediaType);
+    };
 
-        if !webp::has_webp_signature(&header) {
-            let _ = fs::remove_file(&tmp_path).await;
-            state.metrics.upload_fail.fetch_add(1, Ordering::Relaxed);
-            warn!(ip = %ip, request_id, size, elapsed_ms = started.elapsed().as_millis(), result = "fail", reason = "signature", "upload rejected");
+    if !allowed.contains(&format) {
+        state
+            .metrics
+            .upload_failed(&auth.name, "format_not_allowed");
+        warn!(ip = %ip, request_id, size, %format, elapsed_ms = started.elapsed().as_millis(), result = "fail", reason = "format_not_allowed", "upload rejected");
+        return Err(AppError::UnsupportedMediaType);
+    }
+
+    if let Some(ticket) = ticket {
+        if !ticket.allows_content_type(format.content_type()) {
+            state
+                .metrics
+                .upload_failed(&auth.name, "ticket_content_type");
+            warn!(ip = %ip, request_id, size, %format, elapsed_ms = started.elapsed().as_millis(), result = "fail", reason = "ticket_content_type", "upload rejected");
             return Err(AppError::UnsupportedMediaType);
         }
+    }
 
-        let sha256 = hex::encode(hasher.finalize());
-        let now = Utc::now();
-        let year = now.year();
-        let month = now.month();
-
-        let relative = format!("/{year:04}/{month:02}/{sha256}.webp");
-        let final_dir = state
-            .config
-            .data_dir
-            .join(format!("{year:04}"))
-            .join(format!("{month:02}"));
-        if fs::create_dir_all(&final_dir).await.is_err() {
-            let _ = fs::remove_file(&tmp_path).await;
-            state.metrics.upload_fail.fetch_add(1, Ordering::Relaxed);
-            error!(ip = %ip, request_id, elapsed_ms = started.elapsed().as_millis(), result = "fail", reason = "mkdir_final", "upload failed");
-            return Err(AppError::Internal);
-        }
-        let final_path = final_dir.join(format!("{sha256}.webp"));
-
-        match fs::try_exists(&final_path).await {
-            Ok(true) => {
-                let _ = fs::remove_file(&tmp_path).await;
+    let mut image = match inspect(format, &data) {
+        Ok(image) => image,
+        Err(detail) => {
+            state.metrics.upload_failed(&auth.name, "invalid_image");
+            warn!(ip = %ip, request_id, size, %format, elapsed_ms = started.elapsed().as_millis(), result = "fail", reason = "invalid_image", detail = %detail, "upload rejected");
+            return Err(AppError::InvalidImage(detail));
+        }
+    };
+
+    if let Some(encoding) = state.config.webp_conversion {
+        if format != ImageFormat::Webp {
+            if !transcode::can_transcode(format) {
+                state.metrics.upload_failed(&auth.name, "not_convertible");
+                warn!(ip = %ip, request_id, size, %format, elapsed_ms = started.elapsed().as_millis(), result = "fail", reason = "not_convertible", "upload rejected");
+                return Err(AppError::UnsupportedMediaType);
             }
-            Ok(false) => match fs::rename(&tmp_path, &final_path).await {
-                Ok(()) => {}
-                Err(err) if err.kind() == std::io::ErrorKind::AlreadyExists => {
-                    let _ = fs::remove_file(&tmp_path).await;
+
+            let original = std::mem::take(&mut data);
+            let source = format;
+            let converted = match state
+                .transcoder
+                .run(move || transcode::to_webp(&original, source, encoding))
+                .await
+            {
+                Ok(bytes) => bytes,
+                Err(TranscodeError::Decode(err)) => {
+                    state.metrics.upload_failed(&auth.name, "transcode_decode");
+                    warn!(ip = %ip, request_id, size, %format, error = %err, elapsed_ms = started.elapsed().as_millis(), result = "fail", reason = "transcode_decode", "upload rejected");
+                    return Err(AppError::UnsupportedMediaType);
                 }
                 Err(err) => {
-                    error!(ip = %ip, request_id, error = %err, elapsed_ms = started.elapsed().as_millis(), result = "fail", reason = "rename_final", "upload failed");
-                    let _ = fs::remove_file(&tmp_path).await;
-                    state.metrics.upload_fail.fetch_add(1, Ordering::Relaxed);
+                    state.metrics.upload_failed(&auth.name, "transcode");
+                    error!(ip = %ip, request_id, size, %format, error = %err, elapsed_ms = started.elapsed().as_millis(), result = "fail", reason = "transcode", "upload failed");
                     return Err(AppError::Internal);
                 }
-            },
+            };
+
+            info!(request_id, from = %format, original_size = size, size = converted.len(), "upload transcoded to webp");
+            sha256 = hex::encode(Sha256::digest(&converted));
+            size = converted.len() as u64;
+            format = ImageFormat::Webp;
+            image = match inspect(format, &converted) {
+                Ok(image) => image,
+                Err(detail) => {
+                    state.metrics.upload_failed(&auth.name, "transcode_output");
+                    error!(ip = %ip, request_id, detail = %detail, elapsed_ms = started.elapsed().as_millis(), result = "fail", reason = "transcode_output", "upload failed");
+                    return Err(AppError::Internal);
+                }
+            };
+            data = converted;
+        }
+    }
+
+    let strip = state.config.strip_metadata || params.strip_metadata;
+    // Only WebP has a stripper; storing other formats with their metadata
+    // would silently ignore the request.
+    if strip && format != ImageFormat::Webp {
+        state.metrics.upload_failed(&auth.name, "strip_unsupported");
+        warn!(ip = %ip, request_id, size, %format, elapsed_ms = started.elapsed().as_millis(), result = "fail", reason = "strip_unsupported", "upload rejected");
+        return Err(AppError::StripUnsupported);
+    }
+    if strip {
+        let strip_icc = state.config.strip_icc || params.strip_icc;
+        match webp::strip_metadata(&data, strip_icc) {
+            Ok(Some(stripped)) => {
+                info!(
+                    request_id,
+                    original_size = size,
+                    size = stripped.len(),
+                    strip_icc,
+                    "upload metadata stripped"
+                );
+                sha256 = hex::encode(Sha256::digest(&stripped));
+                size = stripped.len() as u64;
+                data = stripped;
+            }
+            Ok(None) => {}
             Err(err) => {
-                error!(ip = %ip, request_id, error = %err, elapsed_ms = started.elapsed().as_millis(), result = "fail", reason = "check_final_exists", "upload failed");
-                let _ = fs::remove_file(&tmp_path).await;
-                state.metrics.upload_fail.fetch_add(1, Ordering::Relaxed);
+                state.metrics.upload_failed(&auth.name, "invalid_image");
+                warn!(ip = %ip, request_id, size, elapsed_ms = started.elapsed().as_millis(), result = "fail", reason = "invalid_image", detail = %err, "upload rejected");
+                return Err(AppError::InvalidImage(err.to_string()));
+            }
+        }
+    }
+
+    let now = Utc::now();
+    // Quota is held before the object is published and released if storing
+    // fails, so an upload over quota never becomes visible. Re-uploading
+    // content the token already references is never refused.
+    let reservation = match state
+        .index
+        .reserve(&auth.token_id, &sha256, size, auth.quota)
+        .await
+    {
+        Ok(reservation) => reservation,
+        Err(Refusal::OverQuota) => {
+            state.metrics.upload_failed(&auth.name, "quota");
+            warn!(ip = %ip, request_id, token = %auth.name, size, elapsed_ms = started.elapsed().as_millis(), result = "fail", reason = "quota", "upload rejected");
+            return Err(AppError::QuotaExceeded);
+        }
+        Err(Refusal::Deleting) => {
+            state.metrics.upload_failed(&auth.name, "deleting");
+            warn!(ip = %ip, request_id, sha256 = %sha256, elapsed_ms = started.elapsed().as_millis(), result = "fail", reason = "deleting", "upload rejected");
+            return Err(AppError::Conflict);
+        }
+    };
+    // Stored content keeps the path it was first stored under, even when a
+    // ticket asks for another prefix, so the returned URL always points at
+    // the object that exists.
+    let relative = match reservation.existing() {
+        Some(existing) => existing.path.clone(),
+        None => {
+            let mut key = format!(
+                "{:04}/{:02}/{sha256}.{}",
+                now.year(),
+                now.month(),
+                format.extension()
+            );
+            if let Some(prefix) = ticket.and_then(|t| t.path_prefix.as_deref()) {
+                key = format!("{prefix}/{key}");
+            }
+            if let Err(err) = state.storage.put_if_absent(&key, data).await {
+                state.metrics.upload_failed(&auth.name, "store");
+                error!(ip = %ip, request_id, error = %err, elapsed_ms = started.elapsed().as_millis(), result = "fail", reason = "store", "upload failed");
                 return Err(AppError::Internal);
             }
+            format!("/{key}")
         }
+    };
 
-        state.metrics.upload_ok.fetch_add(1, Ordering::Relaxed);
-
-        let url = format!(
-            "{}{}",
-            state.config.public_base_url.trim_end_matches('/'),
-            relative
-        );
-        info!(
-            ip = %ip,
-            request_id,
-            sha256 = %sha256,
-            size,
-            path = %relative,
-            elapsed_ms = started.elapsed().as_millis(),
-            result = "ok",
-            "upload finished"
-        );
-
-        return Ok(Json(UploadResponse {
-            url,
-            path: relative,
-            sha256,
-            size,
-        }));
-    }
-
-    state.metrics.upload_fail.fetch_add(1, Ordering::Relaxed);
-    warn!(ip = %ip, request_id, elapsed_ms = started.elapsed().as_millis(), result = "fail", reason = "missing_file", "upload rejected");
-    Err(AppError::BadRequest)
-}
+    let record = ImageRecord {
+        sha256: sha256.clone(),
+        token_id: auth.token_id.clone(),
+        token_name: auth.name.clone(),
+        client_ip: Some(ip),
+        original_filename: Some(filename.chars().take(MAX_FILENAME_CHARS).collect()),
+        size,
+        content_type: format.content_type().to_owned(),
+        image: Some(image),
+        path: relative.clone(),
+        created_at: now,
+    };
+    if let Err(err) = state.index.commit(record, reservation).await {
+        error!(ip = %ip, request_id, error = %err, elapsed_ms = started.elapsed().as_millis(), result = "fail", reason = "index_write", "upload failed");
+        state.metrics.upload_failed(&auth.name, "index_write");
+        return Err(AppError::Internal);
+    }
+
+    state.metrics.upload_succeeded(&auth.name, size);
+
+    let url = format!(
+        "{}{}",
+        state.config.public_base_url.trim_end_matches('/'),
+        relative
+    );
+    info!(
+        ip = %ip,
+        request_id,
+        sha256 = %sha256,
+        size,
+        %format,
+        width = image.width,
+        height = image.height,
+        frame_count = image.frame_count,
+        path = %relative,
+        elapsed_ms = started.elapsed().as_millis(),
+        result = "ok",
+        "upload finished"
+    );
 
-async fn create_new_file(path: &Path) -> Result<File, AppError> {
-    Ok(OpenOptions::new()
-        .create_new(true)
-        .write(true)
-        .open(PathBuf::from(path))
-        .await?)
+    Ok(UploadResponse {
+        url,
+        path: relative,
+        sha256,
+        size,
+        image,
+    })
 }
@@ -1,13 +1,524 @@
-use std::path::Path;
+use thiserror::Error;
 
-pub fn has_webp_extension(filename: &str) -> bool {
-    Path::new(filename)
-        .extension()
-        .and_then(|s| s.to_str())
-        .map(|s| s.eq_ignore_ascii_case("webp"))
-        .unwrap_or(false)
+use crate::format::ImageInfo;
+
+pub const VP8X_FLAG_ANIMATION: u8 = 0x02;
+pub const VP8X_FLAG_XMP: u8 = 0x04;
+pub const VP8X_FLAG_EXIF: u8 = 0x08;
+pub const VP8X_FLAG_ALPHA: u8 = 0x10;
+pub const VP8X_FLAG_ICC: u8 = 0x20;
+
+/// Why a file failed container validation. The display string is the
+/// machine-readable reason returned to clients.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Error)]
+pub enum WebpError {
+    #[error("bad_signature")]
+    BadSignature,
+    #[error("truncated_file")]
+    TruncatedFile,
+    #[error("riff_size_mismatch")]
+    RiffSizeMismatch,
+    #[error("truncated_chunk")]
+    TruncatedChunk,
+    #[error("unexpected_first_chunk")]
+    UnexpectedFirstChunk,
+    #[error("misplaced_vp8x")]
+    MisplacedVp8x,
+    #[error("invalid_vp8x")]
+    InvalidVp8x,
+    #[error("invalid_vp8")]
+    InvalidVp8,
+    #[error("invalid_vp8l")]
+    InvalidVp8l,
+    #[error("invalid_alph")]
+    InvalidAlph,
+    #[error("invalid_anim")]
+    InvalidAnim,
+    #[error("invalid_anmf")]
+    InvalidAnmf,
+    #[error("missing_image_data")]
+    MissingImageData,
+}
+
+#[derive(Debug, Clone, Copy)]
+pub struct Chunk<'a> {
+    pub fourcc: [u8; 4],
+    pub payload: &'a [u8],
+}
+
+/// A RIFF/WEBP file whose chunk layout has been validated.
+#[derive(Debug)]
+pub struct Container<'a> {
+    pub chunks: Vec<Chunk<'a>>,
+}
+
+impl Container<'_> {
+    /// VP8X feature flags, or zero for the simple (single-chunk) format.
+    pub fn vp8x_flags(&self) -> u8 {
+        self.chunks
+            .first()
+            .filter(|c| &c.fourcc == b"VP8X")
+            .map(|c| c.payload[0])
+            .unwrap_or(0)
+    }
+
+    /// Canvas size, alpha and animation details. Only meaningful for a
+    /// container returned by [`parse`].
+    pub fn info(&self) -> ImageInfo {
+        let first = &self.chunks[0];
+        match &first.fourcc {
+            b"VP8X" => {
+                let flags = first.payload[0];
+                let width = read_u24_le(&first.payload[4..7]) + 1;
+                let height = read_u24_le(&first.payload[7..10]) + 1;
+                let animated = flags & VP8X_FLAG_ANIMATION != 0;
+                let frame_count = if animated {
+                    self.chunks.iter().filter(|c| &c.fourcc == b"ANMF").count() as u32
+                } else {
+                    1
+                };
+                let has_alpha = flags & VP8X_FLAG_ALPHA != 0
+                    || self.chunks.iter().any(|c| {
+                        &c.fourcc == b"ALPH"
+                            || (&c.fourcc == b"VP8L"
+                                && vp8l_header(c.payload).is_ok_and(|(_, _, alpha)| alpha))
+                    });
+                ImageInfo {
+                    width,
+                    height,
+                    animated,
+                    frame_count,
+                    has_alpha,
+                }
+            }
+            b"VP8L" => {
+                let (width, height, has_alpha) =
+                    vp8l_header(first.payload).unwrap_or((0, 0, false));
+                ImageInfo {
+                    width,
+                    height,
+                    animated: false,
+                    frame_count: 1,
+                    has_alpha,
+                }
+            }
+            _ => {
+                let (width, height) = vp8_dimensions(first.payload).unwrap_or((0, 0));
+                ImageInfo {
+                    width,
+                    height,
+                    animated: false,
+                    frame_count: 1,
+                    has_alpha: false,
+                }
+            }
+        }
+    }
 }
 
 pub fn has_webp_signature(header: &[u8]) -> bool {
     header.len() >= 12 && &header[0..4] == b"RIFF" && &header[8..12] == b"WEBP"
 }
+
+/// Parses and validates a complete WebP file: the RIFF size must match the
+/// received length, every chunk must fit, and the image chunks required by
+/// the simple or extended layout must be present and well-formed.
+pub fn parse(data: &[u8]) -> Result<Container<'_>, WebpError> {
+    if !has_webp_signature(data) {
+        return Err(WebpError::BadSignature);
+    }
+
+    let riff_size = read_u32_le(&data[4..8]) as usize;
+    let declared = riff_size
+        .checked_add(8)
+        .ok_or(WebpError::RiffSizeMismatch)?;
+    if declared > data.len() {
+        return Err(WebpError::TruncatedFile);
+    }
+    if declared != data.len() || !riff_size.is_multiple_of(2) {
+        return Err(WebpError::RiffSizeMismatch);
+    }
+
+    let chunks = read_chunks(&data[12..])?;
+    let first = chunks.first().ok_or(WebpError::MissingImageData)?;
+
+    match &first.fourcc {
+        b"VP8 " => validate_vp8(first.payload)?,
+        b"VP8L" => validate_vp8l(first.payload)?,
+        b"VP8X" => validate_extended(&chunks)?,
+        _ => return Err(WebpError::UnexpectedFirstChunk),
+    }
+
+    Ok(Container { chunks })
+}
+
+/// Rewrites an extended-format file without `EXIF`/`XMP ` chunks (and
+/// `ICCP` when `strip_icc` is set), clearing the matching VP8X flags and
+/// recomputing the RIFF size. Returns `None` when nothing was removed.
+pub fn strip_metadata(data: &[u8], strip_icc: bool) -> Result<Option<Vec<u8>>, WebpError> {
+    let container = parse(data)?;
+    let is_metadata = |chunk: &Chunk<'_>| match &chunk.fourcc {
+        b"EXIF" | b"XMP " => true,
+        b"ICCP" => strip_icc,
+        _ => false,
+    };
+    let mut clear = VP8X_FLAG_EXIF | VP8X_FLAG_XMP;
+    if strip_icc {
+        clear |= VP8X_FLAG_ICC;
+    }
+    if !container.chunks.iter().any(is_metadata) && container.vp8x_flags() & clear == 0 {
+        return Ok(None);
+    }
+
+    let mut body = Vec::with_capacity(data.len());
+    for chunk in container.chunks.iter().filter(|c| !is_metadata(c)) {
+        body.extend_from_slice(&chunk.fourcc);
+        body.extend_from_slice(&(chunk.payload.len() as u32).to_le_bytes());
+        if &chunk.fourcc == b"VP8X" {
+            body.push(chunk.payload[0] & !clear);
+            body.extend_from_slice(&chunk.payload[1..]);
+        } else {
+            body.extend_from_slice(chunk.payload);
+        }
+        if !chunk.payload.len().is_multiple_of(2) {
+            body.push(0);
+        }
+    }
+
+    let mut out = Vec::with_capacity(body.len() + 12);
+    out.extend_from_slice(b"RIFF");
+    out.extend_from_slice(&(body.len() as u32 + 4).to_le_bytes());
+    out.extend_from_slice(b"WEBP");
+    out.extend_from_slice(&body);
+    Ok(Some(out))
+}
+
+fn read_chunks(mut body: &[u8]) -> Result<Vec<Chunk<'_>>, WebpError> {
+    let mut chunks = Vec::new();
+    while !body.is_empty() {
+        if body.len() < 8 {
+            return Err(WebpError::TruncatedChunk);
+        }
+        let fourcc = [body[0], body[1], body[2], body[3]];
+        let size = read_u32_le(&body[4..8]) as usize;
+        let padded = size + (size & 1);
+        if body.len() - 8 < padded {
+            return Err(WebpError::TruncatedChunk);
+        }
+        chunks.push(Chunk {
+            fourcc,
+            payload: &body[8..8 + size],
+        });
+        body = &body[8 + padded..];
+    }
+    Ok(chunks)
+}
+
+fn validate_extended(chunks: &[Chunk<'_>]) -> Result<(), WebpError> {
+    let vp8x = chunks[0].payload;
+    if vp8x.len() < 10 {
+        return Err(WebpError::InvalidVp8x);
+    }
+    let canvas_width = read_u24_le(&vp8x[4..7]) as u64 + 1;
+    let canvas_height = read_u24_le(&vp8x[7..10]) as u64 + 1;
+    if canvas_width * canvas_height > u32::MAX as u64 {
+        return Err(WebpError::InvalidVp8x);
+    }
+
+    if chunks[1..].iter().any(|c| &c.fourcc == b"VP8X") {
+        return Err(WebpError::MisplacedVp8x);
+    }
+
+    if vp8x[0] & VP8X_FLAG_ANIMATION != 0 {
+        let anim = chunks
+            .iter()
+            .find(|c| &c.fourcc == b"ANIM")
+            .ok_or(WebpError::InvalidAnim)?;
+        if anim.payload.len() < 6 {
+            return Err(WebpError::InvalidAnim);
+        }
+
+        let mut frames = 0usize;
+        for frame in chunks.iter().filter(|c| &c.fourcc == b"ANMF") {
+            if frame.payload.len() < 16 {
+                return Err(WebpError::InvalidAnmf);
+            }
+            let nested = read_chunks(&frame.payload[16..]).map_err(|_| WebpError::InvalidAnmf)?;
+            validate_image_chunks(&nested)?;
+            frames += 1;
+        }
+        if frames == 0 {
+            return Err(WebpError::MissingImageData);
+        }
+        return Ok(());
+    }
+
+    validate_image_chunks(&chunks[1..])
+}
+
+/// Checks the still-image bitstream: an optional `ALPH` followed by `VP8 `,
+/// or a single `VP8L`.
+fn validate_image_chunks(chunks: &[Chunk<'_>]) -> Result<(), WebpError> {
+    let image = chunks
+        .iter()
+        .position(|c| &c.fourcc == b"VP8 " || &c.fourcc == b"VP8L")
+        .ok_or(WebpError::MissingImageData)?;
+
+    if let Some(alph) = chunks.iter().position(|c| &c.fourcc == b"ALPH") {
+        if alph > image || &chunks[image].fourcc != b"VP8 " || chunks[alph].payload.is_empty() {
+            return Err(WebpError::InvalidAlph);
+        }
+    }
+
+    let chunk = &chunks[image];
+    if &chunk.fourcc == b"VP8 " {
+        validate_vp8(chunk.payload)
+    } else {
+        validate_vp8l(chunk.payload)
+    }
+}
+
+/// Lossy bitstream: a key-frame tag followed by the `9d 01 2a` start code
+/// and non-zero 14-bit dimensions.
+fn validate_vp8(payload: &[u8]) -> Result<(), WebpError> {
+    vp8_dimensions(payload).map(|_| ())
+}
+
+fn vp8_dimensions(payload: &[u8]) -> Result<(u32, u32), WebpError> {
+    if payload.len() < 10 {
+        return Err(WebpError::InvalidVp8);
+    }
+    let tag = read_u24_le(&payload[0..3]);
+    let key_frame = tag & 1 == 0;
+    let first_partition = (tag >> 5) as usize;
+    if !key_frame || payload[3..6] != [0x9d, 0x01, 0x2a] || first_partition > payload.len() - 10 {
+        return Err(WebpError::InvalidVp8);
+    }
+    let width = u16::from_le_bytes([payload[6], payload[7]]) & 0x3fff;
+    let height = u16::from_le_bytes([payload[8], payload[9]]) & 0x3fff;
+    if width == 0 || height == 0 {
+        return Err(WebpError::InvalidVp8);
+    }
+    Ok((width as u32, height as u32))
+}
+
+/// Lossless bitstream: the `0x2f` signature byte, 14-bit dimensions minus
+/// one, an alpha hint and a version field that must be zero.
+fn validate_vp8l(payload: &[u8]) -> Result<(), WebpError> {
+    vp8l_header(payload).map(|_| ())
+}
+
+fn vp8l_header(payload: &[u8]) -> Result<(u32, u32, bool), WebpError> {
+    if payload.len() < 5 || payload[0] != 0x2f {
+        return Err(WebpError::InvalidVp8l);
+    }
+    let bits = read_u32_le(&payload[1..5]);
+    let width = (bits & 0x3fff) + 1;
+    let height = ((bits >> 14) & 0x3fff) + 1;
+    let alpha = (bits >> 28) & 1 == 1;
+    let version = bits >> 29;
+    if version != 0 {
+        return Err(WebpError::InvalidVp8l);
+    }
+    Ok((width, height, alpha))
+}
+
+fn read_u32_le(b: &[u8]) -> u32 {
+    u32::from_le_bytes([b[0], b[1], b[2], b[3]])
+}
+
+fn read_u24_le(b: &[u8]) -> u32 {
+    u32::from_le_bytes([b[0], b[1], b[2], 0])
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{
+        parse, strip_metadata, WebpError, VP8X_FLAG_ALPHA, VP8X_FLAG_ANIMATION, VP8X_FLAG_EXIF,
+        VP8X_FLAG_ICC, VP8X_FLAG_XMP,
+    };
+
+    fn chunk(fourcc: &[u8; 4], payload: &[u8]) -> Vec<u8> {
+        let mut out = fourcc.to_vec();
+        out.extend_from_slice(&(payload.len() as u32).to_le_bytes());
+        out.extend_from_slice(payload);
+        if payload.len() % 2 == 1 {
+            out.push(0);
+        }
+        out
+    }
+
+    fn riff(chunks: &[Vec<u8>]) -> Vec<u8> {
+        let body: Vec<u8> = chunks.concat();
+        let mut out = b"RIFF".to_vec();
+        out.extend_from_slice(&(body.len() as u32 + 4).to_le_bytes());
+        out.extend_from_slice(b"WEBP");
+        out.extend_from_slice(&body);
+        out
+    }
+
+    fn vp8(width: u16, height: u16) -> Vec<u8> {
+        // Key frame, version 0, show_frame, first partition of 2 bytes.
+        let mut payload = vec![0x50, 0x00, 0x00, 0x9d, 0x01, 0x2a];
+        payload.extend_from_slice(&width.to_le_bytes());
+        payload.extend_from_slice(&height.to_le_bytes());
+        payload.extend_from_slice(&[0, 0]);
+        chunk(b"VP8 ", &payload)
+    }
+
+    fn vp8l(width: u32, height: u32) -> Vec<u8> {
+        let bits = (width - 1) | ((height - 1) << 14);
+        let mut payload = vec![0x2f];
+        payload.extend_from_slice(&bits.to_le_bytes());
+        payload.push(0);
+        chunk(b"VP8L", &payload)
+    }
+
+    fn vp8x(flags: u8, width: u32, height: u32) -> Vec<u8> {
+        let mut payload = vec![flags, 0, 0, 0];
+        payload.extend_from_slice(&(width - 1).to_le_bytes()[..3]);
+        payload.extend_from_slice(&(height - 1).to_le_bytes()[..3]);
+        chunk(b"VP8X", &payload)
+    }
+
+    #[test]
+    fn accepts_simple_lossy_and_lossless() {
+        assert!(parse(&riff(&[vp8(3, 2)])).is_ok());
+        assert!(parse(&riff(&[vp8l(3, 2)])).is_ok());
+    }
+
+    #[test]
+    fn accepts_extended_with_metadata() {
+        let file = riff(&[
+            vp8x(0x2c, 3, 2),
+            chunk(b"ICCP", b"icc"),
+            chunk(b"ALPH", &[0, 1, 2]),
+            vp8(3, 2),
+            chunk(b"EXIF", b"exif"),
+            chunk(b"XMP ", b"<x/>"),
+        ]);
+        let container = parse(&file).expect("valid");
+        assert_eq!(container.chunks.len(), 6);
+        assert_eq!(container.vp8x_flags(), 0x2c);
+    }
+
+    #[test]
+    fn accepts_animation_frames() {
+        let mut frame = vec![0u8; 16];
+        frame.extend_from_slice(&vp8l(3, 2));
+        let file = riff(&[
+            vp8x(VP8X_FLAG_ANIMATION, 3, 2),
+            chunk(b"ANIM", &[0; 6]),
+            chunk(b"ANMF", &frame),
+        ]);
+        assert!(parse(&file).is_ok());
+
+        let no_frames = riff(&[vp8x(VP8X_FLAG_ANIMATION, 3, 2), chunk(b"ANIM", &[0; 6])]);
+        assert_eq!(parse(&no_frames).unwrap_err(), WebpError::MissingImageData);
+    }
+
+    #[test]
+    fn info_reads_dimensions_alpha_and_frames() {
+        let info = parse(&riff(&[vp8(3, 2)])).unwrap().info();
+        assert_eq!((info.width, info.height, info.has_alpha), (3, 2, false));
+
+        let mut frame = vec![0u8; 16];
+        frame.extend_from_slice(&vp8l(3, 2));
+        let file = riff(&[
+            vp8x(VP8X_FLAG_ANIMATION | VP8X_FLAG_ALPHA, 300, 200),
+            chunk(b"ANIM", &[0; 6]),
+            chunk(b"ANMF", &frame),
+            chunk(b"ANMF", &frame),
+        ]);
+        let info = parse(&file).unwrap().info();
+        assert_eq!((info.width, info.height), (300, 200));
+        assert!(info.animated && info.has_alpha);
+        assert_eq!(info.frame_count, 2);
+    }
+
+    #[test]
+    fn strip_metadata_drops_chunks_and_flags() {
+        let file = riff(&[
+            vp8x(VP8X_FLAG_ICC | VP8X_FLAG_EXIF | VP8X_FLAG_XMP, 3, 2),
+            chunk(b"ICCP", b"icc"),
+            vp8(3, 2),
+            chunk(b"EXIF", b"GPS data"),
+            chunk(b"XMP ", b"<x/>"),
+        ]);
+
+        let stripped = strip_metadata(&file, false).unwrap().expect("rewritten");
+        let container = parse(&stripped).expect("still valid");
+        let kinds: Vec<_> = container.chunks.iter().map(|c| &c.fourcc).collect();
+        assert_eq!(kinds, [b"VP8X", b"ICCP", b"VP8 "]);
+        assert_eq!(container.vp8x_flags(), VP8X_FLAG_ICC);
+
+        let stripped = strip_metadata(&file, true).unwrap().expect("rewritten");
+        let container = parse(&stripped).expect("still valid");
+        assert_eq!(container.chunks.len(), 2);
+        assert_eq!(container.vp8x_flags(), 0);
+        assert!(!stripped.windows(3).any(|w| w == b"GPS"));
+    }
+
+    #[test]
+    fn strip_metadata_leaves_clean_files_alone() {
+        assert_eq!(strip_metadata(&riff(&[vp8(3, 2)]), true), Ok(None));
+        let icc_only = riff(&[vp8x(VP8X_FLAG_ICC, 3, 2), chunk(b"ICCP", b"icc"), vp8(3, 2)]);
+        assert_eq!(strip_metadata(&icc_only, false), Ok(None));
+    }
+
+    #[test]
+    fn rejects_twelve_byte_stub() {
+        let mut stub = b"RIFF".to_vec();
+        stub.extend_from_slice(&[0x10, 0, 0, 0]);
+        stub.extend_from_slice(b"WEBPVP8 ");
+        stub.extend_from_slice(&[0, 0, 0, 0]);
+        assert_eq!(parse(&stub).unwrap_err(), WebpError::TruncatedFile);
+
+        let mut empty_vp8 = stub.clone();
+        empty_vp8[4] = 0x0c;
+        assert_eq!(parse(&empty_vp8).unwrap_err(), WebpError::InvalidVp8);
+    }
+
+    #[test]
+    fn rejects_size_mismatch_and_truncated_chunks() {
+        let mut file = riff(&[vp8(3, 2)]);
+        file.push(0);
+        assert_eq!(parse(&file).unwrap_err(), WebpError::RiffSizeMismatch);
+
+        let mut file = riff(&[vp8(3, 2)]);
+        // Claim a longer VP8 payload than the file contains.
+        file[16] = 0x40;
+        assert_eq!(parse(&file).unwrap_err(), WebpError::TruncatedChunk);
+    }
+
+    #[test]
+    fn rejects_malformed_layouts() {
+        assert_eq!(
+            parse(&riff(&[chunk(b"EXIF", b"ex"), vp8(3, 2)])).unwrap_err(),
+            WebpError::UnexpectedFirstChunk
+        );
+        assert_eq!(
+            parse(&riff(&[vp8x(0, 3, 2), vp8x(0, 3, 2), vp8(3, 2)])).unwrap_err(),
+            WebpError::MisplacedVp8x
+        );
+        assert_eq!(
+            parse(&riff(&[vp8x(0, 3, 2), chunk(b"EXIF", b"ex")])).unwrap_err(),
+            WebpError::MissingImageData
+        );
+        assert_eq!(
+            parse(&riff(&[vp8x(0, 3, 2), chunk(b"ALPH", &[1]), vp8l(3, 2)])).unwrap_err(),
+            WebpError::InvalidAlph
+        );
+        assert_eq!(
+            parse(&riff(&[vp8x(VP8X_FLAG_ANIMATION, 3, 2), vp8(3, 2)])).unwrap_err(),
+            WebpError::InvalidAnim
+        );
+
+        let mut bad_version = vp8l(3, 2);
+        bad_version[12] |= 0x20;
+        assert_eq!(
+            parse(&riff(&[bad_version])).unwrap_err(),
+            WebpError::InvalidVp8l
+        );
+    }
+}
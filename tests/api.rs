@@ -0,0 +1,439 @@
+mod common;
+
+use axum::{
+    body::Body,
+    http::{Request, StatusCode},
+    Router,
+};
+use common::*;
+use http_body_util::BodyExt;
+use imgd::build_app;
+use serde_json::Value;
+use tower::ServiceExt;
+
+fn app_with_tokens(root: &std::path::Path) -> Router {
+    app_with_config(test_config(root))
+}
+
+fn app_with_config(mut config: imgd::config::AppConfig) -> Router {
+    config.tokens_file = Some(write_tokens_file(
+        &config.data_dir,
+        &[
+            ("alice", "alice-token", false),
+            ("bob", "bob-token", false),
+            ("ops", "admin-token", true),
+        ],
+    ));
+    build_app(state_with_config(config))
+}
+
+async fn send_delete(app: Router, sha256: &str, token: Option<&str>) -> StatusCode {
+    let mut req = Request::builder()
+        .method("DELETE")
+        .uri(format!("/api/images/{sha256}"));
+    if let Some(token) = token {
+        req = req.header("x-upload-token", token);
+    }
+    app.oneshot(req.body(Body::empty()).expect("request"))
+        .await
+        .expect("response")
+        .status()
+}
+
+#[tokio::test]
+async fn delete_respects_ownership() {
+    let tmp = tempfile::tempdir().expect("tmpdir");
+    let app = app_with_tokens(tmp.path());
+
+    let (status, body) = send_upload_as(
+        app.clone(),
+        "/upload",
+        "alice-token",
+        "a.webp",
+        &webp_fixture(),
+    )
+    .await;
+    assert_eq!(status, StatusCode::OK);
+    let sha = body["sha256"].as_str().expect("sha256").to_owned();
+    let stored = tmp
+        .path()
+        .join(body["path"].as_str().unwrap().trim_start_matches('/'));
+    assert!(stored.exists());
+
+    assert_eq!(
+        send_delete(app.clone(), &sha, None).await,
+        StatusCode::UNAUTHORIZED
+    );
+    assert_eq!(
+        send_delete(app.clone(), &sha, Some("bob-token")).await,
+        StatusCode::FORBIDDEN
+    );
+    assert!(stored.exists());
+
+    assert_eq!(
+        send_delete(app.clone(), &sha, Some("alice-token")).await,
+        StatusCode::NO_CONTENT
+    );
+    assert!(!stored.exists());
+    assert_eq!(
+        send_delete(app.clone(), &sha, Some("alice-token")).await,
+        StatusCode::NOT_FOUND
+    );
+    assert_eq!(
+        send_delete(app, "not-a-hash", Some("alice-token")).await,
+        StatusCode::NOT_FOUND
+    );
+}
+
+#[tokio::test]
+async fn shared_content_is_owned_per_token() {
+    let tmp = tempfile::tempdir().expect("tmpdir");
+    let app = app_with_tokens(tmp.path());
+
+    let mut bodies = Vec::new();
+    for token in ["alice-token", "bob-token"] {
+        let (status, body) =
+            send_upload_as(app.clone(), "/upload", token, "a.webp", &webp_fixture()).await;
+        assert_eq!(status, StatusCode::OK);
+        bodies.push(body);
+    }
+    assert_eq!(bodies[0]["path"], bodies[1]["path"]);
+    let stored = tmp
+        .path()
+        .join(bodies[0]["path"].as_str().unwrap().trim_start_matches('/'));
+    let sha = bodies[0]["sha256"].as_str().unwrap().to_owned();
+
+    for token in ["alice-token", "bob-token"] {
+        let (_, body) = list(app.clone(), "", token).await;
+        assert_eq!(shas(&body), vec![sha.clone()], "{token}");
+    }
+
+    // Alice drops her reference; Bob's copy stays.
+    assert_eq!(
+        send_delete(app.clone(), &sha, Some("alice-token")).await,
+        StatusCode::NO_CONTENT
+    );
+    assert!(stored.exists());
+    let (_, body) = list(app.clone(), "", "alice-token").await;
+    assert!(shas(&body).is_empty());
+    let (_, body) = list(app.clone(), "", "bob-token").await;
+    assert_eq!(shas(&body), vec![sha.clone()]);
+
+    assert_eq!(
+        send_delete(app.clone(), &sha, Some("bob-token")).await,
+        StatusCode::NO_CONTENT
+    );
+    assert!(!stored.exists());
+    assert_eq!(
+        send_delete(app, &sha, Some("admin-token")).await,
+        StatusCode::NOT_FOUND
+    );
+}
+
+#[tokio::test]
+async fn prometheus_metrics_label_uploads() {
+    let tmp = tempfile::tempdir().expect("tmpdir");
+    let mut config = test_config(tmp.path());
+    config.metrics_token_label = true;
+    let app = app_with_config(config);
+
+    let (status, _) = send_upload_as(
+        app.clone(),
+        "/upload",
+        "bob-token",
+        "b.png",
+        &encoded_png(3, 2),
+    )
+    .await;
+    assert_eq!(status, StatusCode::OK);
+    let (status, _) = send_upload_as(
+        app.clone(),
+        "/upload",
+        "alice-token",
+        "a.png",
+        b"not an image",
+    )
+    .await;
+    assert_eq!(status, StatusCode::UNSUPPORTED_MEDIA_TYPE);
+
+    let resp = app
+        .oneshot(
+            Request::builder()
+                .uri("/metrics")
+                .body(Body::empty())
+                .unwrap(),
+        )
+        .await
+        .expect("response");
+    assert_eq!(resp.status(), StatusCode::OK);
+    assert!(resp.headers()["content-type"]
+        .to_str()
+        .unwrap()
+        .starts_with("text/plain; version=0.0.4"));
+    let bytes = resp.into_body().collect().await.expect("body").to_bytes();
+    let text = String::from_utf8(bytes.to_vec()).expect("utf-8");
+
+    for line in [
+        "imgd_uploads_total{result=\"ok\",reason=\"\",token=\"bob\"} 1",
+        "imgd_uploads_total{result=\"fail\",reason=\"signature\",token=\"alice\"} 1",
+        "imgd_upload_size_bytes_count 1",
+        "imgd_upload_duration_seconds_count 2",
+        "imgd_uploads_in_flight 0",
+        "imgd_stored_files 1",
+        "imgd_rate_limiter_keys 1",
+    ] {
+        assert!(
+            text.lines().any(|l| l == line),
+            "missing {line:?} in:\n{text}"
+        );
+    }
+}
+
+#[tokio::test]
+async fn prometheus_metrics_omit_token_by_default() {
+    let tmp = tempfile::tempdir().expect("tmpdir");
+    let app = app_with_tokens(tmp.path());
+
+    let (status, _) = send_upload_as(
+        app.clone(),
+        "/upload",
+        "bob-token",
+        "b.png",
+        &encoded_png(3, 2),
+    )
+    .await;
+    assert_eq!(status, StatusCode::OK);
+
+    let resp = app
+        .oneshot(
+            Request::builder()
+                .uri("/metrics")
+                .body(Body::empty())
+                .unwrap(),
+        )
+        .await
+        .expect("response");
+    let bytes = resp.into_body().collect().await.expect("body").to_bytes();
+    let text = String::from_utf8(bytes.to_vec()).expect("utf-8");
+    assert!(
+        text.lines()
+            .any(|l| l == "imgd_uploads_total{result=\"ok\",reason=\"\"} 1"),
+        "{text}"
+    );
+    assert!(!text.contains("token="), "{text}");
+}
+
+#[tokio::test]
+async fn admin_deletes_any_upload_and_metrics_count() {
+    let tmp = tempfile::tempdir().expect("tmpdir");
+    let app = app_with_tokens(tmp.path());
+
+    let (status, body) = send_upload_as(
+        app.clone(),
+        "/upload",
+        "bob-token",
+        "b.png",
+        &encoded_png(3, 2),
+    )
+    .await;
+    assert_eq!(status, StatusCode::OK);
+    let sha = body["sha256"].as_str().expect("sha256").to_owned();
+
+    assert_eq!(
+        send_delete(app.clone(), &sha, Some("alice-token")).await,
+        StatusCode::FORBIDDEN
+    );
+    assert_eq!(
+        send_delete(app.clone(), &sha, Some("admin-token")).await,
+        StatusCode::NO_CONTENT
+    );
+
+    let resp = app
+        .oneshot(
+            Request::builder()
+                .uri("/metrics.json")
+                .body(Body::empty())
+                .unwrap(),
+        )
+        .await
+        .expect("response");
+    let bytes = resp.into_body().collect().await.expect("body").to_bytes();
+    let metrics: Value = serde_json::from_slice(&bytes).expect("json");
+    assert_eq!(metrics["delete_ok"], 1);
+    assert_eq!(metrics["delete_fail"], 1);
+
+    // Deletion is persisted: a fresh app over the same data dir no longer
+    // knows the image.
+    let app = app_with_tokens(tmp.path());
+    assert_eq!(
+        send_delete(app, &sha, Some("admin-token")).await,
+        StatusCode::NOT_FOUND
+    );
+}
+
+async fn list(app: Router, query: &str, token: &str) -> (StatusCode, Value) {
+    let req = Request::builder()
+        .uri(format!("/api/images{query}"))
+        .header("x-upload-token", token)
+        .body(Body::empty())
+        .expect("request");
+    let resp = app.oneshot(req).await.expect("response");
+    let status = resp.status();
+    let bytes = resp.into_body().collect().await.expect("body").to_bytes();
+    (
+        status,
+        serde_json::from_slice(&bytes).unwrap_or(Value::Null),
+    )
+}
+
+fn shas(body: &Value) -> Vec<String> {
+    body["items"]
+        .as_array()
+        .expect("items")
+        .iter()
+        .map(|item| item["sha256"].as_str().expect("sha256").to_owned())
+        .collect()
+}
+
+#[tokio::test]
+async fn list_paginates_and_filters_by_owner() {
+    let tmp = tempfile::tempdir().expect("tmpdir");
+    let app = app_with_tokens(tmp.path());
+
+    let mut uploaded = Vec::new();
+    for (w, name) in [(2, "a.png"), (3, "b.png"), (4, "c.png")] {
+        let (status, body) = send_upload_as(
+            app.clone(),
+            "/upload",
+            "alice-token",
+            name,
+            &encoded_png(w, 1),
+        )
+        .await;
+        assert_eq!(status, StatusCode::OK);
+        uploaded.push(body["sha256"].as_str().unwrap().to_owned());
+    }
+    let (status, _) = send_upload_as(
+        app.clone(),
+        "/upload",
+        "bob-token",
+        "d.webp",
+        &webp_fixture(),
+    )
+    .await;
+    assert_eq!(status, StatusCode::OK);
+
+    // Newest first by default, two per page.
+    let (status, page) = list(app.clone(), "?limit=2", "alice-token").await;
+    assert_eq!(status, StatusCode::OK);
+    assert_eq!(shas(&page), vec![uploaded[2].clone(), uploaded[1].clone()]);
+    let cursor = page["next_cursor"].as_str().expect("cursor");
+    let (_, page) = list(
+        app.clone(),
+        &format!("?limit=2&cursor={cursor}"),
+        "alice-token",
+    )
+    .await;
+    assert_eq!(shas(&page), vec![uploaded[0].clone()]);
+    assert!(page["next_cursor"].is_null());
+
+    let (_, page) = list(app.clone(), "?order=asc", "alice-token").await;
+    assert_eq!(shas(&page), uploaded);
+    assert_eq!(page["items"][0]["original_filename"], "a.png");
+
+    // Other tokens' uploads are invisible to non-admins, even when asked for.
+    let (_, page) = list(app.clone(), "?token=bob", "alice-token").await;
+    assert!(shas(&page).is_empty());
+
+    let (_, page) = list(app.clone(), "", "admin-token").await;
+    assert_eq!(shas(&page).len(), 4);
+    let (_, page) = list(
+        app.clone(),
+        "?token=bob&content_type=image/webp",
+        "admin-token",
+    )
+    .await;
+    assert_eq!(shas(&page).len(), 1);
+    let (_, page) = list(
+        app.clone(),
+        "?content_type=image/png&max_size=0",
+        "admin-token",
+    )
+    .await;
+    assert!(shas(&page).is_empty());
+    let (_, page) = list(
+        app.clone(),
+        "?from=2000-01-01T00:00:00Z&to=2000-01-02T00:00:00Z",
+        "admin-token",
+    )
+    .await;
+    assert!(shas(&page).is_empty());
+
+    let (status, _) = list(app.clone(), "?cursor=bogus", "admin-token").await;
+    assert_eq!(status, StatusCode::BAD_REQUEST);
+    let (status, _) = list(app, "", "wrong-token").await;
+    assert_eq!(status, StatusCode::UNAUTHORIZED);
+}
+
+#[tokio::test]
+async fn routes_enforce_token_scopes() {
+    let tmp = tempfile::tempdir().expect("tmpdir");
+    let tokens = tmp.path().join("tokens.json");
+    std::fs::write(
+        &tokens,
+        serde_json::json!({ "tokens": [
+            { "name": "ci", "token": "ci-token", "scopes": ["upload"] },
+            { "name": "monitor", "token": "mon-token", "scopes": ["metrics"] },
+        ]})
+        .to_string(),
+    )
+    .expect("tokens file");
+    let mut config = test_config(tmp.path());
+    config.tokens_file = Some(tokens);
+    config.metrics_auth = true;
+    let app = build_app(state_with_config(config));
+
+    let (status, body) = send_upload_as(
+        app.clone(),
+        "/upload",
+        "ci-token",
+        "a.webp",
+        &webp_fixture(),
+    )
+    .await;
+    assert_eq!(status, StatusCode::OK);
+    let sha = body["sha256"].as_str().expect("sha256").to_owned();
+    let (status, _) = send_upload_as(
+        app.clone(),
+        "/upload",
+        "mon-token",
+        "a.webp",
+        &webp_fixture(),
+    )
+    .await;
+    assert_eq!(status, StatusCode::FORBIDDEN);
+
+    assert_eq!(
+        send_delete(app.clone(), &sha, Some("ci-token")).await,
+        StatusCode::FORBIDDEN
+    );
+    let (status, _) = list(app.clone(), "", "ci-token").await;
+    assert_eq!(status, StatusCode::FORBIDDEN);
+
+    let metrics = |token: Option<&'static str>| {
+        let app = app.clone();
+        async move {
+            let mut req = Request::builder().uri("/metrics");
+            if let Some(token) = token {
+                req = req.header("x-upload-token", token);
+            }
+            app.oneshot(req.body(Body::empty()).unwrap())
+                .await
+                .expect("response")
+                .status()
+        }
+    };
+    assert_eq!(metrics(None).await, StatusCode::UNAUTHORIZED);
+    assert_eq!(metrics(Some("ci-token")).await, StatusCode::FORBIDDEN);
+    assert_eq!(metrics(Some("mon-token")).await, StatusCode::OK);
+}
@@ -0,0 +1,226 @@
+//! Helpers shared by the integration test binaries.
+#![allow(dead_code)]
+
+use axum::{
+    body::Body,
+    extract::connect_info::ConnectInfo,
+    http::{header, Request, StatusCode},
+};
+use http_body_util::BodyExt;
+use imgd::{
+    config::AppConfig, format::ImageFormat, index::ImageIndex, storage::StorageConfig,
+    token::TokenStore, AppState,
+};
+use serde_json::Value;
+use tower::ServiceExt;
+
+pub fn test_config(data_dir: &std::path::Path) -> AppConfig {
+    AppConfig {
+        bind_addr: "127.0.0.1:0".parse().expect("addr"),
+        upload_token: Some("secret".to_string()),
+        tokens_file: None,
+        public_base_url: "https://img.example.com/images".to_string(),
+        data_dir: data_dir.to_path_buf(),
+        storage: StorageConfig::Fs,
+        max_upload_bytes: 5 * 1024 * 1024,
+        max_concurrent_uploads: 4,
+        max_files_per_upload: 10,
+        rate_limit_per_minute: 100,
+        rate_limit_window: std::time::Duration::from_secs(60),
+        upload_session_ttl: std::time::Duration::from_secs(3600),
+        max_upload_sessions_per_token: 10,
+        allowed_formats: ImageFormat::ALL.to_vec(),
+        webp_conversion: None,
+        transcode_workers: 1,
+        strip_metadata: false,
+        strip_icc: false,
+        serve_images: false,
+        webp_quality: 80.0,
+        variant_sizes: Vec::new(),
+        tokens_reload_interval: std::time::Duration::from_millis(50),
+        metrics_auth: false,
+        metrics_token_label: false,
+        upload_ticket_secret: None,
+        trusted_proxies: Vec::new(),
+        forwarded_header: Default::default(),
+        fetch_timeout: std::time::Duration::from_secs(5),
+        fetch_max_redirects: 3,
+        fetch_allowed_networks: Vec::new(),
+    }
+}
+
+pub fn make_test_state(data_dir: &std::path::Path) -> AppState {
+    state_with_config(test_config(data_dir))
+}
+
+pub fn state_with_config(config: AppConfig) -> AppState {
+    let token_store = TokenStore::from_config(&config).expect("token store");
+    let index = ImageIndex::open(&config.data_dir).expect("index");
+    AppState::new(config, token_store, index)
+}
+
+pub fn webp_fixture() -> Vec<u8> {
+    webp::Encoder::from_rgb(&[255, 0, 0, 0, 255, 0], 2, 1)
+        .encode_lossless()
+        .to_vec()
+}
+
+pub fn webp_stub() -> Vec<u8> {
+    // Passes the RIFF....WEBP signature check but is not a complete file.
+    let mut data = Vec::from(*b"RIFF");
+    data.extend_from_slice(&[0x10, 0x00, 0x00, 0x00]);
+    data.extend_from_slice(b"WEBP");
+    data.extend_from_slice(b"VP8 ");
+    data.extend_from_slice(&[0x00, 0x00, 0x00, 0x00]);
+    data
+}
+
+/// Wraps a simple-format WebP in a VP8X container carrying an EXIF chunk.
+pub fn webp_with_exif() -> Vec<u8> {
+    let simple = webp_fixture();
+    let mut body = Vec::from(*b"VP8X");
+    body.extend_from_slice(&10u32.to_le_bytes());
+    body.extend_from_slice(&[0x08, 0, 0, 0, 0x01, 0, 0, 0x00, 0, 0]);
+    body.extend_from_slice(&simple[12..]);
+    body.extend_from_slice(b"EXIF");
+    body.extend_from_slice(&8u32.to_le_bytes());
+    body.extend_from_slice(b"GPS 51N ");
+
+    let mut data = Vec::from(*b"RIFF");
+    data.extend_from_slice(&(body.len() as u32 + 4).to_le_bytes());
+    data.extend_from_slice(b"WEBP");
+    data.extend_from_slice(&body);
+    data
+}
+
+pub fn stored_bytes(root: &std::path::Path, body: &Value) -> Vec<u8> {
+    let rel = body
+        .get("path")
+        .and_then(Value::as_str)
+        .expect("path")
+        .trim_start_matches('/');
+    std::fs::read(root.join(rel)).expect("stored file")
+}
+
+pub fn gif_fixture() -> Vec<u8> {
+    let mut data = Vec::from(*b"GIF89a");
+    data.extend_from_slice(&[0x01, 0x00, 0x01, 0x00, 0x00, 0x00, 0x00]);
+    data.extend_from_slice(&[0x2c, 0, 0, 0, 0, 0x01, 0x00, 0x01, 0x00, 0x00]);
+    data.extend_from_slice(&[0x02, 0x02, 0x44, 0x01, 0x00, 0x3b]);
+    data
+}
+
+pub fn multipart_body(boundary: &str, filename: &str, bytes: &[u8]) -> Vec<u8> {
+    multipart_files(boundary, &[(filename, bytes)])
+}
+
+/// A multipart body with one `file` field per `(filename, bytes)`.
+pub fn multipart_files(boundary: &str, files: &[(&str, &[u8])]) -> Vec<u8> {
+    let mut body = Vec::new();
+    for (filename, bytes) in files {
+        body.extend_from_slice(format!("--{boundary}\r\n").as_bytes());
+        body.extend_from_slice(
+            format!("Content-Disposition: form-data; name=\"file\"; filename=\"{filename}\"\r\n")
+                .as_bytes(),
+        );
+        body.extend_from_slice(b"Content-Type: image/webp\r\n\r\n");
+        body.extend_from_slice(bytes);
+        body.extend_from_slice(b"\r\n");
+    }
+    body.extend_from_slice(format!("--{boundary}--\r\n").as_bytes());
+    body
+}
+
+pub async fn send_upload(app: axum::Router, filename: &str, bytes: &[u8]) -> (StatusCode, Value) {
+    send_upload_to(app, "/upload", filename, bytes).await
+}
+
+pub async fn send_upload_to(
+    app: axum::Router,
+    uri: &str,
+    filename: &str,
+    bytes: &[u8],
+) -> (StatusCode, Value) {
+    send_upload_as(app, uri, "secret", filename, bytes).await
+}
+
+pub async fn send_upload_as(
+    app: axum::Router,
+    uri: &str,
+    token: &str,
+    filename: &str,
+    bytes: &[u8],
+) -> (StatusCode, Value) {
+    send_upload_with(app, uri, &[("x-upload-token", token)], filename, bytes).await
+}
+
+/// Uploads from peer `127.0.0.1:8080` with extra request headers.
+pub async fn send_upload_with(
+    app: axum::Router,
+    uri: &str,
+    headers: &[(&str, &str)],
+    filename: &str,
+    bytes: &[u8],
+) -> (StatusCode, Value) {
+    send_files_with(app, uri, headers, &[(filename, bytes)]).await
+}
+
+/// Uploads several files in one multipart request from peer `127.0.0.1:8080`.
+pub async fn send_files_with(
+    app: axum::Router,
+    uri: &str,
+    headers: &[(&str, &str)],
+    files: &[(&str, &[u8])],
+) -> (StatusCode, Value) {
+    let boundary = "----imgd-boundary";
+    let body = multipart_files(boundary, files);
+
+    let mut req = Request::builder().method("POST").uri(uri).header(
+        header::CONTENT_TYPE,
+        format!("multipart/form-data; boundary={boundary}"),
+    );
+    for (name, value) in headers {
+        req = req.header(*name, *value);
+    }
+    let mut req = req.body(Body::from(body)).expect("request");
+
+    req.extensions_mut().insert(ConnectInfo(
+        "127.0.0.1:8080"
+            .parse::<std::net::SocketAddr>()
+            .expect("socket"),
+    ));
+
+    let resp = app.oneshot(req).await.expect("response");
+    let status = resp.status();
+    let bytes = resp.into_body().collect().await.expect("body").to_bytes();
+    let json: Value = serde_json::from_slice(&bytes).expect("json body");
+    (status, json)
+}
+
+/// Writes a tokens file with `(name, token, admin)` entries.
+pub fn write_tokens_file(
+    dir: &std::path::Path,
+    tokens: &[(&str, &str, bool)],
+) -> std::path::PathBuf {
+    let entries: Vec<Value> = tokens
+        .iter()
+        .map(|(name, token, admin)| serde_json::json!({ "name": name, "token": token, "admin": admin }))
+        .collect();
+    let path = dir.join("tokens.json");
+    std::fs::write(&path, serde_json::json!({ "tokens": entries }).to_string())
+        .expect("tokens file");
+    path
+}
+
+pub fn encoded_png(width: u32, height: u32) -> Vec<u8> {
+    let image = image::DynamicImage::ImageRgb8(image::RgbImage::from_pixel(
+        width,
+        height,
+        image::Rgb([10, 120, 200]),
+    ));
+    let mut out = std::io::Cursor::new(Vec::new());
+    image
+        .write_to(&mut out, image::ImageFormat::Png)
+        .expect("encode png");
+    out.into_inner()
+}
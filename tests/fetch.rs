@@ -0,0 +1,110 @@
+mod common;
+
+use axum::{
+    body::Body,
+    extract::{ConnectInfo, Path},
+    http::{header, Request, StatusCode},
+    response::{IntoResponse, Redirect, Response},
+    routing::get,
+    Router,
+};
+use common::*;
+use http_body_util::BodyExt;
+use imgd::{build_app, client_ip::IpNet};
+use serde_json::Value;
+use tower::ServiceExt;
+
+/// A stand-in for the remote site images are fetched from.
+async fn spawn_origin() -> String {
+    let app = Router::new()
+        .route("/cat.png", get(|| async { encoded_png(5, 4) }))
+        .route(
+            "/render",
+            get(|| async { ([(header::CONTENT_TYPE, "image/png")], encoded_png(2, 2)) }),
+        )
+        .route("/big.png", get(|| async { vec![0u8; 64 * 1024] }))
+        .route(
+            "/hop/{n}",
+            get(|Path(n): Path<u32>| async move {
+                match n {
+                    0 => Redirect::temporary("/cat.png").into_response(),
+                    n => Redirect::temporary(&format!("/hop/{}", n - 1)).into_response(),
+                }
+            }),
+        )
+        .route(
+            "/internal",
+            get(|| async { Redirect::temporary("http://10.0.0.1/secret.png") }),
+        )
+        .fallback(|| async { StatusCode::NOT_FOUND });
+    let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let addr = listener.local_addr().unwrap();
+    tokio::spawn(async move { axum::serve(listener, app).await });
+    format!("http://{addr}")
+}
+
+async fn fetch(app: Router, url: &str) -> (StatusCode, Value) {
+    let mut req = Request::builder()
+        .method("POST")
+        .uri("/upload/url")
+        .header("x-upload-token", "secret")
+        .header(header::CONTENT_TYPE, "application/json")
+        .body(Body::from(serde_json::json!({ "url": url }).to_string()))
+        .unwrap();
+    req.extensions_mut().insert(ConnectInfo(
+        "127.0.0.1:8080".parse::<std::net::SocketAddr>().unwrap(),
+    ));
+    let resp: Response = app.oneshot(req).await.expect("response");
+    let status = resp.status();
+    let bytes = resp.into_body().collect().await.unwrap().to_bytes();
+    (status, serde_json::from_slice(&bytes).expect("json"))
+}
+
+#[tokio::test]
+async fn upload_by_url_fetches_through_the_pipeline() {
+    let tmp = tempfile::tempdir().expect("tmpdir");
+    let origin = spawn_origin().await;
+
+    // Loopback is refused until explicitly allowed.
+    let app = build_app(make_test_state(tmp.path()));
+    let (status, body) = fetch(app, &format!("{origin}/cat.png")).await;
+    assert_eq!(status, StatusCode::FORBIDDEN, "{body}");
+
+    let mut config = test_config(tmp.path());
+    config.fetch_allowed_networks = vec!["127.0.0.1/32".parse::<IpNet>().unwrap()];
+    config.fetch_max_redirects = 2;
+    config.max_upload_bytes = 32 * 1024;
+    let app = build_app(state_with_config(config));
+
+    let (status, body) = fetch(app.clone(), &format!("{origin}/cat.png")).await;
+    assert_eq!(status, StatusCode::OK, "{body}");
+    assert_eq!(body["width"], 5);
+    assert_eq!(stored_bytes(tmp.path(), &body), encoded_png(5, 4));
+
+    // No extension in the URL: the Content-Type supplies one.
+    let (status, body) = fetch(app.clone(), &format!("{origin}/render")).await;
+    assert_eq!(status, StatusCode::OK, "{body}");
+
+    let (status, _) = fetch(app.clone(), &format!("{origin}/hop/1")).await;
+    assert_eq!(status, StatusCode::OK);
+    let (status, body) = fetch(app.clone(), &format!("{origin}/hop/5")).await;
+    assert_eq!(status, StatusCode::BAD_GATEWAY);
+    assert_eq!(body["detail"], "too_many_redirects");
+
+    // Redirects are checked too, not just the first URL.
+    let (status, _) = fetch(app.clone(), &format!("{origin}/internal")).await;
+    assert_eq!(status, StatusCode::FORBIDDEN);
+    let (status, _) = fetch(app.clone(), "http://169.254.169.254/latest/meta-data").await;
+    assert_eq!(status, StatusCode::FORBIDDEN);
+
+    let (status, body) = fetch(app.clone(), &format!("{origin}/missing.png")).await;
+    assert_eq!(status, StatusCode::BAD_GATEWAY);
+    assert_eq!(body["error"], "fetch_failed");
+    assert_eq!(body["detail"], "status_404");
+
+    let (status, _) = fetch(app.clone(), &format!("{origin}/big.png")).await;
+    assert_eq!(status, StatusCode::PAYLOAD_TOO_LARGE);
+
+    let (status, _) = fetch(app, "file:///etc/passwd").await;
+    assert_eq!(status, StatusCode::BAD_REQUEST);
+}
@@ -0,0 +1,185 @@
+mod common;
+
+use axum::{
+    body::Body,
+    http::{header, Request, StatusCode},
+    Router,
+};
+use common::*;
+use http_body_util::BodyExt;
+use imgd::build_app;
+use serde_json::Value;
+use tower::ServiceExt;
+
+async fn app_with_upload(root: &std::path::Path) -> (Router, String, Vec<u8>) {
+    let mut config = test_config(root);
+    config.serve_images = true;
+    let app = build_app(state_with_config(config));
+
+    let bytes = webp_fixture();
+    let (status, body) = send_upload(app.clone(), "ok.webp", &bytes).await;
+    assert_eq!(status, StatusCode::OK);
+    let path = body.get("path").and_then(Value::as_str).expect("path");
+    (app, format!("/images{path}"), bytes)
+}
+
+async fn get(
+    app: Router,
+    method: &str,
+    uri: &str,
+    headers: &[(header::HeaderName, &str)],
+) -> axum::response::Response {
+    let mut req = Request::builder().method(method).uri(uri);
+    for (name, value) in headers {
+        req = req.header(name, *value);
+    }
+    app.oneshot(req.body(Body::empty()).expect("request"))
+        .await
+        .expect("response")
+}
+
+#[tokio::test]
+async fn serve_full_file_with_cache_headers() {
+    let tmp = tempfile::tempdir().expect("tmpdir");
+    let (app, uri, bytes) = app_with_upload(tmp.path()).await;
+
+    let resp = get(app.clone(), "GET", &uri, &[]).await;
+    assert_eq!(resp.status(), StatusCode::OK);
+    let headers = resp.headers().clone();
+    assert_eq!(headers[header::CONTENT_TYPE], "image/webp");
+    assert_eq!(
+        headers[header::CACHE_CONTROL],
+        "public, max-age=31536000, immutable"
+    );
+    assert_eq!(headers[header::CONTENT_LENGTH], bytes.len().to_string());
+    let sha = uri.rsplit('/').next().unwrap().trim_end_matches(".webp");
+    assert_eq!(headers[header::ETAG], format!("\"{sha}\""));
+    let body = resp.into_body().collect().await.expect("body").to_bytes();
+    assert_eq!(body.as_ref(), bytes.as_slice());
+
+    let resp = get(app, "HEAD", &uri, &[]).await;
+    assert_eq!(resp.status(), StatusCode::OK);
+    assert_eq!(
+        resp.headers()[header::CONTENT_LENGTH],
+        bytes.len().to_string()
+    );
+    let body = resp.into_body().collect().await.expect("body").to_bytes();
+    assert!(body.is_empty());
+}
+
+#[tokio::test]
+async fn serve_conditional_and_range_requests() {
+    let tmp = tempfile::tempdir().expect("tmpdir");
+    let (app, uri, bytes) = app_with_upload(tmp.path()).await;
+    let etag = get(app.clone(), "HEAD", &uri, &[]).await.headers()[header::ETAG]
+        .to_str()
+        .unwrap()
+        .to_owned();
+
+    let resp = get(app.clone(), "GET", &uri, &[(header::IF_NONE_MATCH, &etag)]).await;
+    assert_eq!(resp.status(), StatusCode::NOT_MODIFIED);
+
+    let resp = get(app.clone(), "GET", &uri, &[(header::RANGE, "bytes=4-11")]).await;
+    assert_eq!(resp.status(), StatusCode::PARTIAL_CONTENT);
+    assert_eq!(
+        resp.headers()[header::CONTENT_RANGE],
+        format!("bytes 4-11/{}", bytes.len())
+    );
+    let body = resp.into_body().collect().await.expect("body").to_bytes();
+    assert_eq!(body.as_ref(), &bytes[4..12]);
+
+    let resp = get(
+        app.clone(),
+        "GET",
+        &uri,
+        &[
+            (header::RANGE, "bytes=4-11"),
+            (header::IF_RANGE, "\"stale\""),
+        ],
+    )
+    .await;
+    assert_eq!(resp.status(), StatusCode::OK);
+
+    let far = format!("bytes={}-", bytes.len() + 10);
+    let resp = get(app, "GET", &uri, &[(header::RANGE, &far)]).await;
+    assert_eq!(resp.status(), StatusCode::RANGE_NOT_SATISFIABLE);
+    assert_eq!(
+        resp.headers()[header::CONTENT_RANGE],
+        format!("bytes */{}", bytes.len())
+    );
+}
+
+#[tokio::test]
+async fn serve_rejects_traversal_and_missing_files() {
+    let tmp = tempfile::tempdir().expect("tmpdir");
+    std::fs::write(tmp.path().join("secret.txt"), b"secret").expect("write");
+    let (app, uri, _) = app_with_upload(tmp.path()).await;
+
+    for bad in [
+        "/images/2024/01/..%2Fsecret.txt",
+        "/images/../../secret.txt",
+        "/images/2024/01/secret.txt",
+        &uri.replace(".webp", ".png"),
+    ] {
+        let resp = get(app.clone(), "GET", bad, &[]).await;
+        assert_eq!(resp.status(), StatusCode::NOT_FOUND, "{bad}");
+    }
+}
+
+#[tokio::test]
+async fn serve_route_disabled_by_default() {
+    let tmp = tempfile::tempdir().expect("tmpdir");
+    let app = build_app(make_test_state(tmp.path()));
+    let (status, body) = send_upload(app.clone(), "ok.webp", &webp_fixture()).await;
+    assert_eq!(status, StatusCode::OK);
+    let path = body.get("path").and_then(Value::as_str).expect("path");
+
+    let resp = get(app, "GET", &format!("/images{path}"), &[]).await;
+    assert_eq!(resp.status(), StatusCode::NOT_FOUND);
+}
+
+#[tokio::test]
+async fn serve_whitelisted_variants() {
+    let tmp = tempfile::tempdir().expect("tmpdir");
+    let mut config = test_config(tmp.path());
+    config.serve_images = true;
+    config.variant_sizes = vec![(4, 0), (2, 2)];
+    let app = build_app(state_with_config(config));
+
+    let (status, body) = send_upload(app.clone(), "photo.png", &encoded_png(8, 6)).await;
+    assert_eq!(status, StatusCode::OK);
+    let path = body.get("path").and_then(Value::as_str).expect("path");
+    let sha = body.get("sha256").and_then(Value::as_str).expect("sha256");
+    let uri = format!("/images{path}");
+
+    let resp = get(app.clone(), "GET", &format!("{uri}?w=4"), &[]).await;
+    assert_eq!(resp.status(), StatusCode::OK);
+    assert_eq!(resp.headers()[header::CONTENT_TYPE], "image/webp");
+    assert_eq!(
+        resp.headers()[header::ETAG],
+        format!("\"{sha}_4x0_contain\"")
+    );
+    let body = resp.into_body().collect().await.expect("body").to_bytes();
+    let info = imgd::format::inspect(imgd::format::ImageFormat::Webp, &body).expect("webp");
+    assert_eq!((info.width, info.height), (4, 3));
+
+    let cached = tmp
+        .path()
+        .join(path.trim_start_matches('/'))
+        .with_file_name(format!("{sha}_4x0_contain.webp"));
+    assert!(cached.exists());
+
+    let resp = get(
+        app.clone(),
+        "GET",
+        &format!("{uri}?w=2&h=2&fit=cover"),
+        &[(header::IF_NONE_MATCH, &format!("\"{sha}_2x2_cover\""))],
+    )
+    .await;
+    assert_eq!(resp.status(), StatusCode::NOT_MODIFIED);
+
+    for bad in ["?w=3", "?w=2&h=2&fit=stretch", "?h=2"] {
+        let resp = get(app.clone(), "GET", &format!("{uri}{bad}"), &[]).await;
+        assert_eq!(resp.status(), StatusCode::BAD_REQUEST, "{bad}");
+    }
+}
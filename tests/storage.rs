@@ -0,0 +1,179 @@
+mod common;
+
+use std::{
+    collections::{BTreeMap, HashMap},
+    sync::{Arc, Mutex},
+};
+
+use axum::{
+    body::{Body, Bytes},
+    extract::{Query, State},
+    http::{header, HeaderMap, Method, Request, StatusCode, Uri},
+    response::{IntoResponse, Response},
+    Router,
+};
+use common::*;
+use http_body_util::BodyExt;
+use imgd::{build_app, s3::S3Config, storage::StorageConfig};
+use tower::ServiceExt;
+
+type Objects = Arc<Mutex<BTreeMap<String, Vec<u8>>>>;
+
+/// Just enough of the S3 API for imgd: conditional PUT, HEAD, ranged GET,
+/// DELETE and ListObjectsV2 with a `/` delimiter.
+async fn fake_s3(
+    State(objects): State<Objects>,
+    method: Method,
+    uri: Uri,
+    Query(query): Query<HashMap<String, String>>,
+    headers: HeaderMap,
+    body: Bytes,
+) -> Response {
+    let auth = headers["authorization"].to_str().unwrap();
+    assert!(
+        auth.starts_with("AWS4-HMAC-SHA256 Credential=minio/") && auth.contains("Signature="),
+        "{auth}"
+    );
+    let key = uri.path().trim_start_matches('/').to_owned();
+    let mut objects = objects.lock().unwrap();
+
+    if method == Method::GET && query.get("list-type").map(String::as_str) == Some("2") {
+        let prefix = format!("{key}/{}", query["prefix"]);
+        let keys: String = objects
+            .keys()
+            .filter_map(|k| k.strip_prefix(&prefix).map(|rest| (k, rest)))
+            .filter(|(_, rest)| !rest.contains('/'))
+            .map(|(k, _)| format!("<Contents><Key>{}</Key></Contents>", &k[key.len() + 1..]))
+            .collect();
+        return format!(
+            "<ListBucketResult><IsTruncated>false</IsTruncated>{keys}</ListBucketResult>"
+        )
+        .into_response();
+    }
+
+    match method {
+        Method::PUT => {
+            if headers.contains_key("if-none-match") && objects.contains_key(&key) {
+                return StatusCode::PRECONDITION_FAILED.into_response();
+            }
+            objects.insert(key, body.to_vec());
+            StatusCode::OK.into_response()
+        }
+        Method::HEAD => match objects.get(&key) {
+            Some(data) => ([(header::CONTENT_LENGTH, data.len())]).into_response(),
+            None => StatusCode::NOT_FOUND.into_response(),
+        },
+        Method::GET => {
+            let Some(data) = objects.get(&key) else {
+                return StatusCode::NOT_FOUND.into_response();
+            };
+            let range = headers
+                .get(header::RANGE)
+                .and_then(|v| v.to_str().ok()?.strip_prefix("bytes="))
+                .and_then(|r| r.split_once('-'))
+                .map(|(a, b)| (a.parse::<usize>().unwrap(), b.parse::<usize>().unwrap()));
+            match range {
+                Some((start, end)) => {
+                    (StatusCode::PARTIAL_CONTENT, data[start..=end].to_vec()).into_response()
+                }
+                None => data.clone().into_response(),
+            }
+        }
+        Method::DELETE => {
+            objects.remove(&key);
+            StatusCode::NO_CONTENT.into_response()
+        }
+        _ => StatusCode::METHOD_NOT_ALLOWED.into_response(),
+    }
+}
+
+async fn spawn_fake_s3() -> (String, Objects) {
+    let objects = Objects::default();
+    let app = Router::new().fallback(fake_s3).with_state(objects.clone());
+    let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let addr = listener.local_addr().unwrap();
+    tokio::spawn(async move { axum::serve(listener, app).await });
+    (format!("http://{addr}"), objects)
+}
+
+async fn request(app: Router, method: &str, uri: &str, range: Option<&str>) -> Response {
+    let mut req = Request::builder()
+        .method(method)
+        .uri(uri)
+        .header(header::AUTHORIZATION, "Bearer secret");
+    if let Some(range) = range {
+        req = req.header(header::RANGE, range);
+    }
+    app.oneshot(req.body(Body::empty()).unwrap())
+        .await
+        .expect("response")
+}
+
+#[tokio::test]
+async fn s3_backend_stores_serves_and_deletes() {
+    let tmp = tempfile::tempdir().expect("tmpdir");
+    let (endpoint, objects) = spawn_fake_s3().await;
+    let mut config = test_config(tmp.path());
+    config.serve_images = true;
+    config.variant_sizes = vec![(2, 2)];
+    config.storage = StorageConfig::S3(Box::new(S3Config {
+        endpoint: imgd::s3::parse_endpoint(&endpoint).unwrap(),
+        bucket: "images".to_owned(),
+        region: "us-east-1".to_owned(),
+        access_key_id: "minio".to_owned(),
+        secret_access_key: "minio-secret".to_owned(),
+        prefix: "imgd/".to_owned(),
+        connect_timeout: std::time::Duration::from_secs(5),
+        timeout: std::time::Duration::from_secs(30),
+    }));
+    let app = build_app(state_with_config(config));
+
+    let png = encoded_png(8, 6);
+    let (status, body) = send_upload(app.clone(), "photo.png", &png).await;
+    assert_eq!(status, StatusCode::OK);
+    let path = body["path"].as_str().expect("path").to_owned();
+    let sha = body["sha256"].as_str().expect("sha256").to_owned();
+    let key = format!("images/imgd{path}");
+    assert_eq!(objects.lock().unwrap()[&key], png);
+    // Nothing but local state lands in the data dir.
+    assert!(!tmp.path().join(path.trim_start_matches('/')).exists());
+
+    // Re-uploading the same content keeps the existing object.
+    let (status, _) = send_upload(app.clone(), "again.png", &png).await;
+    assert_eq!(status, StatusCode::OK);
+    assert_eq!(objects.lock().unwrap().len(), 1);
+
+    let uri = format!("/images{path}");
+    let resp = request(app.clone(), "GET", &uri, None).await;
+    assert_eq!(resp.status(), StatusCode::OK);
+    assert_eq!(
+        resp.headers()[header::CONTENT_LENGTH],
+        png.len().to_string()
+    );
+    let bytes = resp.into_body().collect().await.unwrap().to_bytes();
+    assert_eq!(bytes.as_ref(), png.as_slice());
+
+    let resp = request(app.clone(), "GET", &uri, Some("bytes=1-3")).await;
+    assert_eq!(resp.status(), StatusCode::PARTIAL_CONTENT);
+    let bytes = resp.into_body().collect().await.unwrap().to_bytes();
+    assert_eq!(bytes.as_ref(), &png[1..=3]);
+
+    let resp = request(
+        app.clone(),
+        "GET",
+        &format!("{uri}?w=2&h=2&fit=cover"),
+        None,
+    )
+    .await;
+    assert_eq!(resp.status(), StatusCode::OK);
+    let dir = key.rsplit_once('/').unwrap().0;
+    let variant = format!("{dir}/{sha}_2x2_cover.webp");
+    assert!(objects.lock().unwrap().contains_key(&variant), "{variant}");
+
+    let resp = request(app.clone(), "DELETE", &format!("/api/images/{sha}"), None).await;
+    assert_eq!(resp.status(), StatusCode::NO_CONTENT);
+    assert!(objects.lock().unwrap().is_empty());
+
+    let resp = request(app, "GET", &uri, None).await;
+    assert_eq!(resp.status(), StatusCode::NOT_FOUND);
+}
@@ -0,0 +1,218 @@
+mod common;
+
+use std::{sync::atomic::Ordering, time::Duration};
+
+use axum::{
+    body::Body,
+    http::{Request, StatusCode},
+    Router,
+};
+use common::*;
+use http_body_util::BodyExt;
+use imgd::{build_app, token::watch_tokens};
+use serde_json::Value;
+use tower::ServiceExt;
+
+async fn eventually(mut check: impl FnMut() -> bool) -> bool {
+    for _ in 0..100 {
+        if check() {
+            return true;
+        }
+        tokio::time::sleep(Duration::from_millis(20)).await;
+    }
+    false
+}
+
+#[tokio::test]
+async fn tokens_file_changes_are_picked_up_live() {
+    let tmp = tempfile::tempdir().expect("tmpdir");
+    let mut config = test_config(tmp.path());
+    config.upload_token = None;
+    config.tokens_file = Some(write_tokens_file(
+        tmp.path(),
+        &[("alice", "alice-token", false)],
+    ));
+    let state = state_with_config(config.clone());
+    let store = state.token_store.clone();
+    tokio::spawn(watch_tokens(store.clone(), config, state.metrics.clone()));
+    // Let the watcher record the initial file state before touching it.
+    tokio::time::sleep(Duration::from_millis(100)).await;
+
+    assert!(store.authorize("alice-token").is_some());
+
+    write_tokens_file(
+        tmp.path(),
+        &[
+            ("alice", "alice-token", false),
+            ("bob", "bob-token-2", true),
+        ],
+    );
+    assert!(eventually(|| store.authorize("bob-token-2").is_some()).await);
+    assert!(store.authorize("bob-token-2").unwrap().is_admin());
+    assert_eq!(state.metrics.token_reload_ok.load(Ordering::Relaxed), 1);
+
+    // A broken file is rejected and the previous set stays active.
+    std::fs::write(tmp.path().join("tokens.json"), b"{ not json").expect("write");
+    assert!(eventually(|| state.metrics.token_reload_fail.load(Ordering::Relaxed) == 1).await);
+    assert!(store.authorize("alice-token").is_some());
+    assert!(store.authorize("bob-token-2").is_some());
+
+    write_tokens_file(tmp.path(), &[("carol", "carol-token", false)]);
+    assert!(eventually(|| store.authorize("carol-token").is_some()).await);
+    assert!(store.authorize("alice-token").is_none());
+}
+
+#[test]
+fn migrate_hashes_plaintext_tokens_file() {
+    let tmp = tempfile::tempdir().expect("tmpdir");
+    let path = write_tokens_file(tmp.path(), &[("alice", "alice-token", true)]);
+
+    imgd::token::token_cli(&[
+        "migrate".to_string(),
+        "--tokens-file".to_string(),
+        path.display().to_string(),
+    ])
+    .expect("migrate");
+
+    let data = std::fs::read_to_string(&path).expect("read");
+    assert!(!data.contains("alice-token"));
+    assert!(data.contains("\"token_hash\": \"sha256$"));
+
+    let mut config = test_config(tmp.path());
+    config.upload_token = None;
+    config.tokens_file = Some(path);
+    let store = imgd::token::TokenStore::from_config(&config).expect("store");
+    let auth = store.authorize("alice-token").expect("authorized");
+    assert_eq!(auth.name, "alice");
+    assert!(auth.is_admin());
+    assert!(store.authorize("alice-tokeN").is_none());
+}
+
+async fn admin_request(
+    app: Router,
+    method: &str,
+    uri: &str,
+    token: &str,
+    body: Option<Value>,
+) -> (StatusCode, Value) {
+    let req = Request::builder()
+        .method(method)
+        .uri(uri)
+        .header("x-upload-token", token)
+        .header("content-type", "application/json");
+    let body = body.map_or_else(Body::empty, |v| Body::from(v.to_string()));
+    let resp = app
+        .oneshot(req.body(body).expect("request"))
+        .await
+        .expect("response");
+    let status = resp.status();
+    let bytes = resp.into_body().collect().await.expect("body").to_bytes();
+    (
+        status,
+        serde_json::from_slice(&bytes).unwrap_or(Value::Null),
+    )
+}
+
+#[tokio::test]
+async fn admin_api_manages_tokens_live() {
+    let tmp = tempfile::tempdir().expect("tmpdir");
+    let mut config = test_config(tmp.path());
+    config.upload_token = None;
+    let path = write_tokens_file(
+        tmp.path(),
+        &[
+            ("ops", "admin-token", true),
+            ("alice", "alice-token", false),
+        ],
+    );
+    config.tokens_file = Some(path.clone());
+    let app = build_app(state_with_config(config));
+
+    let (status, _) = admin_request(app.clone(), "GET", "/admin/tokens", "alice-token", None).await;
+    assert_eq!(status, StatusCode::FORBIDDEN);
+
+    let (status, created) = admin_request(
+        app.clone(),
+        "POST",
+        "/admin/tokens",
+        "admin-token",
+        Some(serde_json::json!({ "name": "ci", "days": 30, "scopes": ["upload"] })),
+    )
+    .await;
+    assert_eq!(status, StatusCode::CREATED);
+    let token = created["token"].as_str().expect("token").to_owned();
+    let token_id = created["token_id"].as_str().expect("token_id").to_owned();
+    assert_eq!(created["scopes"], serde_json::json!(["upload"]));
+
+    // Usable immediately, and only its hash reaches the file.
+    let (status, _) =
+        send_upload_as(app.clone(), "/upload", &token, "a.webp", &webp_fixture()).await;
+    assert_eq!(status, StatusCode::OK);
+    let saved = std::fs::read_to_string(&path).expect("read");
+    assert!(!saved.contains(&token));
+
+    let (status, listed) =
+        admin_request(app.clone(), "GET", "/admin/tokens", "admin-token", None).await;
+    assert_eq!(status, StatusCode::OK);
+    let listed = listed.as_array().expect("array");
+    assert_eq!(listed.len(), 3);
+    assert!(listed
+        .iter()
+        .all(|t| t.get("token").is_none() && t.get("token_hash").is_none()));
+
+    let (status, updated) = admin_request(
+        app.clone(),
+        "PATCH",
+        &format!("/admin/tokens/{token_id}"),
+        "admin-token",
+        Some(
+            serde_json::json!({ "expires_at": "2000-01-01T00:00:00Z", "rate_limit_per_minute": 5 }),
+        ),
+    )
+    .await;
+    assert_eq!(status, StatusCode::OK);
+    assert_eq!(updated["rate_limit_per_minute"], 5);
+    for rate_limit in [0u64, 1 << 32] {
+        let (status, _) = admin_request(
+            app.clone(),
+            "PATCH",
+            &format!("/admin/tokens/{token_id}"),
+            "admin-token",
+            Some(serde_json::json!({ "rate_limit_per_minute": rate_limit })),
+        )
+        .await;
+        assert_eq!(status, StatusCode::BAD_REQUEST, "{rate_limit}");
+    }
+    let (status, _) =
+        send_upload_as(app.clone(), "/upload", &token, "a.webp", &webp_fixture()).await;
+    assert_eq!(status, StatusCode::UNAUTHORIZED);
+
+    let uri = format!("/admin/tokens/{token_id}");
+    let (status, _) = admin_request(app.clone(), "DELETE", &uri, "admin-token", None).await;
+    assert_eq!(status, StatusCode::NO_CONTENT);
+    let (status, _) = admin_request(app.clone(), "DELETE", &uri, "admin-token", None).await;
+    assert_eq!(status, StatusCode::NOT_FOUND);
+
+    let (status, _) = admin_request(
+        app.clone(),
+        "POST",
+        "/admin/tokens",
+        "admin-token",
+        Some(serde_json::json!({ "name": " " })),
+    )
+    .await;
+    assert_eq!(status, StatusCode::BAD_REQUEST);
+
+    // Out-of-range expiries are refused rather than overflowing.
+    for days in [0, -1, i64::MAX] {
+        let (status, _) = admin_request(
+            app.clone(),
+            "POST",
+            "/admin/tokens",
+            "admin-token",
+            Some(serde_json::json!({ "name": "ci", "days": days })),
+        )
+        .await;
+        assert_eq!(status, StatusCode::BAD_REQUEST, "{days}");
+    }
+}
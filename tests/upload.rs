@@ -1,93 +1,9 @@
-use std::{sync::Arc, time::Duration};
-
-use axum::{
-    body::Body,
-    extract::connect_info::ConnectInfo,
-    http::{header, Request, StatusCode},
-};
-use http_body_util::BodyExt;
-use imgd::{build_app, config::AppConfig, token::TokenStore, AppState, Metrics, SimpleRateLimiter};
-use serde_json::Value;
-use tokio::sync::Semaphore;
-use tower::ServiceExt;
-
-fn make_test_state(data_dir: &std::path::Path) -> AppState {
-    AppState {
-        config: AppConfig {
-            bind_addr: "127.0.0.1:0".parse().expect("addr"),
-            upload_token: Some("secret".to_string()),
-            tokens_file: None,
-            public_base_url: "https://img.example.com/images".to_string(),
-            data_dir: data_dir.to_path_buf(),
-            max_upload_bytes: 5 * 1024 * 1024,
-            max_concurrent_uploads: 4,
-            rate_limit_per_minute: 100,
-        },
-        upload_semaphore: Arc::new(Semaphore::new(4)),
-        rate_limiter: SimpleRateLimiter::new(Duration::from_secs(60)),
-        token_store: TokenStore::from_config(&AppConfig {
-            bind_addr: "127.0.0.1:0".parse().expect("addr"),
-            upload_token: Some("secret".to_string()),
-            tokens_file: None,
-            public_base_url: "https://img.example.com/images".to_string(),
-            data_dir: data_dir.to_path_buf(),
-            max_upload_bytes: 5 * 1024 * 1024,
-            max_concurrent_uploads: 4,
-            rate_limit_per_minute: 100,
-        })
-        .expect("token store"),
-        metrics: Arc::new(Metrics::default()),
-    }
-}
-
-fn webp_fixture() -> Vec<u8> {
-    // Minimal header that satisfies RIFF....WEBP signature check.
-    let mut data = Vec::from(*b"RIFF");
-    data.extend_from_slice(&[0x10, 0x00, 0x00, 0x00]);
-    data.extend_from_slice(b"WEBP");
-    data.extend_from_slice(b"VP8 ");
-    data.extend_from_slice(&[0x00, 0x00, 0x00, 0x00]);
-    data
-}
-
-fn multipart_body(boundary: &str, filename: &str, bytes: &[u8]) -> Vec<u8> {
-    let mut body = Vec::new();
-    body.extend_from_slice(format!("--{boundary}\r\n").as_bytes());
-    body.extend_from_slice(
-        format!("Content-Disposition: form-data; name=\"file\"; filename=\"{filename}\"\r\n")
-            .as_bytes(),
-    );
-    body.extend_from_slice(b"Content-Type: image/webp\r\n\r\n");
-    body.extend_from_slice(bytes);
-    body.extend_from_slice(b"\r\n");
-    body.extend_from_slice(format!("--{boundary}--\r\n").as_bytes());
-    body
-}
-
-async fn send_upload(app: axum::Router, filename: &str, bytes: &[u8]) -> (StatusCode, Value) {
-    let boundary = "----imgd-boundary";
-    let body = multipart_body(boundary, filename, bytes);
-
-    let mut req = Request::builder()
-        .method("POST")
-        .uri("/upload")
-        .header(
-            header::CONTENT_TYPE,
-            format!("multipart/form-data; boundary={boundary}"),
-        )
-        .header("x-upload-token", "secret")
-        .body(Body::from(body))
-        .expect("request");
+mod common;
 
-    req.extensions_mut()
-        .insert(ConnectInfo("127.0.0.1:8080".parse().expect("socket")));
-
-    let resp = app.oneshot(req).await.expect("response");
-    let status = resp.status();
-    let bytes = resp.into_body().collect().await.expect("body").to_bytes();
-    let json: Value = serde_json::from_slice(&bytes).expect("json body");
-    (status, json)
-}
+use axum::http::StatusCode;
+use common::*;
+use imgd::{build_app, format::ImageFormat, transcode::WebpEncoding};
+use serde_json::Value;
 
 #[tokio::test]
 async fn upload_webp_success_and_file_exists() {
@@ -97,6 +13,11 @@ async fn upload_webp_success_and_file_exists() {
 
     let (status, body) = send_upload(app, "ok.webp", &webp_fixture()).await;
     assert_eq!(status, StatusCode::OK);
+    assert_eq!(body.get("width").and_then(Value::as_u64), Some(2));
+    assert_eq!(body.get("height").and_then(Value::as_u64), Some(1));
+    assert_eq!(body.get("animated").and_then(Value::as_bool), Some(false));
+    assert_eq!(body.get("frame_count").and_then(Value::as_u64), Some(1));
+    assert_eq!(body.get("has_alpha").and_then(Value::as_bool), Some(false));
 
     let path = body.get("path").and_then(Value::as_str).expect("path");
     let rel = path.trim_start_matches('/');
@@ -117,6 +38,33 @@ async fn reject_fake_webp_text_payload() {
     );
 }
 
+#[tokio::test]
+async fn reject_truncated_webp_with_reason() {
+    let tmp = tempfile::tempdir().expect("tmpdir");
+    let state = make_test_state(tmp.path());
+    let app = build_app(state);
+
+    let (status, body) = send_upload(app.clone(), "stub.webp", &webp_stub()).await;
+    assert_eq!(status, StatusCode::UNPROCESSABLE_ENTITY);
+    assert_eq!(
+        body.get("error").and_then(Value::as_str),
+        Some("invalid_image")
+    );
+    assert_eq!(
+        body.get("detail").and_then(Value::as_str),
+        Some("truncated_file")
+    );
+
+    let mut oversized = webp_fixture();
+    oversized.extend_from_slice(&[0, 0]);
+    let (status, body) = send_upload(app, "pad.webp", &oversized).await;
+    assert_eq!(status, StatusCode::UNPROCESSABLE_ENTITY);
+    assert_eq!(
+        body.get("detail").and_then(Value::as_str),
+        Some("riff_size_mismatch")
+    );
+}
+
 #[tokio::test]
 async fn deduplicate_same_content_by_sha256() {
     let tmp = tempfile::tempdir().expect("tmpdir");
@@ -139,3 +87,782 @@ async fn deduplicate_same_content_by_sha256() {
         .trim_start_matches('/');
     assert!(tmp.path().join(rel).exists());
 }
+
+#[tokio::test]
+async fn reupload_in_a_later_month_reuses_the_stored_path() {
+    let tmp = tempfile::tempdir().expect("tmpdir");
+    let bytes = webp_fixture();
+    let (status, body) =
+        send_upload(build_app(make_test_state(tmp.path())), "a.webp", &bytes).await;
+    assert_eq!(status, StatusCode::OK);
+
+    // Pretend the first upload happened in an earlier month.
+    let current = body["path"].as_str().unwrap().to_owned();
+    let file = current.rsplit('/').next().unwrap();
+    let earlier = format!("/2020/01/{file}");
+    std::fs::create_dir_all(tmp.path().join("2020/01")).unwrap();
+    std::fs::rename(
+        tmp.path().join(current.trim_start_matches('/')),
+        tmp.path().join(earlier.trim_start_matches('/')),
+    )
+    .unwrap();
+    let index = tmp.path().join(".meta/index.jsonl");
+    let log = std::fs::read_to_string(&index).unwrap();
+    std::fs::write(&index, log.replace(&current, &earlier)).unwrap();
+
+    let (status, body) =
+        send_upload(build_app(make_test_state(tmp.path())), "b.webp", &bytes).await;
+    assert_eq!(status, StatusCode::OK);
+    assert_eq!(body["path"], earlier.as_str());
+    assert!(!tmp.path().join(current.trim_start_matches('/')).exists());
+}
+
+#[tokio::test]
+async fn upload_png_stored_with_png_extension() {
+    let tmp = tempfile::tempdir().expect("tmpdir");
+    let state = make_test_state(tmp.path());
+    let app = build_app(state);
+
+    let (status, body) = send_upload(app, "shot.png", &encoded_png(3, 2)).await;
+    assert_eq!(status, StatusCode::OK);
+
+    let path = body.get("path").and_then(Value::as_str).expect("path");
+    assert!(path.ends_with(".png"));
+    assert_eq!(body.get("width").and_then(Value::as_u64), Some(3));
+    assert_eq!(body.get("height").and_then(Value::as_u64), Some(2));
+    assert!(tmp.path().join(path.trim_start_matches('/')).exists());
+}
+
+#[tokio::test]
+async fn reject_format_outside_allowed_set() {
+    let tmp = tempfile::tempdir().expect("tmpdir");
+    let mut config = test_config(tmp.path());
+    config.allowed_formats = vec![ImageFormat::Webp];
+    let app = build_app(state_with_config(config));
+
+    let (status, _) = send_upload(app.clone(), "shot.png", &encoded_png(3, 2)).await;
+    assert_eq!(status, StatusCode::UNSUPPORTED_MEDIA_TYPE);
+
+    // A WebP-named file carrying PNG bytes is rejected by the signature check.
+    let (status, _) = send_upload(app, "shot.webp", &encoded_png(3, 2)).await;
+    assert_eq!(status, StatusCode::UNSUPPORTED_MEDIA_TYPE);
+}
+
+#[tokio::test]
+async fn convert_png_upload_to_webp() {
+    let tmp = tempfile::tempdir().expect("tmpdir");
+    let mut config = test_config(tmp.path());
+    config.webp_conversion = Some(WebpEncoding::Lossy { quality: 80.0 });
+    let app = build_app(state_with_config(config));
+
+    let (status, body) = send_upload(app, "shot.png", &encoded_png(8, 8)).await;
+    assert_eq!(status, StatusCode::OK);
+
+    let url = body.get("url").and_then(Value::as_str).expect("url");
+    assert!(url.ends_with(".webp"));
+
+    let rel = body
+        .get("path")
+        .and_then(Value::as_str)
+        .expect("path")
+        .trim_start_matches('/');
+    let stored = std::fs::read(tmp.path().join(rel)).expect("stored file");
+    assert_eq!(&stored[0..4], b"RIFF");
+    assert_eq!(&stored[8..12], b"WEBP");
+    assert_eq!(
+        body.get("size").and_then(Value::as_u64),
+        Some(stored.len() as u64)
+    );
+}
+
+#[tokio::test]
+async fn conversion_mode_rejects_unconvertible_formats() {
+    let tmp = tempfile::tempdir().expect("tmpdir");
+    let mut config = test_config(tmp.path());
+    config.webp_conversion = Some(WebpEncoding::Lossless);
+    let app = build_app(state_with_config(config));
+
+    let (status, _) = send_upload(app, "anim.gif", &gif_fixture()).await;
+    assert_eq!(status, StatusCode::UNSUPPORTED_MEDIA_TYPE);
+}
+
+#[tokio::test]
+async fn strip_metadata_from_config() {
+    let tmp = tempfile::tempdir().expect("tmpdir");
+    let mut config = test_config(tmp.path());
+    config.strip_metadata = true;
+    let app = build_app(state_with_config(config));
+
+    let original = webp_with_exif();
+    let (status, body) = send_upload(app, "phone.webp", &original).await;
+    assert_eq!(status, StatusCode::OK);
+
+    let stored = stored_bytes(tmp.path(), &body);
+    assert!(stored.len() < original.len());
+    assert!(!stored.windows(4).any(|w| w == b"EXIF"));
+    assert_eq!(stored[20] & 0x08, 0, "EXIF flag cleared");
+    assert_eq!(
+        body.get("size").and_then(Value::as_u64),
+        Some(stored.len() as u64)
+    );
+}
+
+#[tokio::test]
+async fn strip_metadata_per_request() {
+    let tmp = tempfile::tempdir().expect("tmpdir");
+    let app = build_app(make_test_state(tmp.path()));
+    let original = webp_with_exif();
+
+    let (status, kept) = send_upload(app.clone(), "phone.webp", &original).await;
+    assert_eq!(status, StatusCode::OK);
+    assert_eq!(stored_bytes(tmp.path(), &kept), original);
+
+    let (status, stripped) = send_upload_to(
+        app.clone(),
+        "/upload?strip_metadata=true",
+        "phone.webp",
+        &original,
+    )
+    .await;
+    assert_eq!(status, StatusCode::OK);
+    assert_ne!(kept.get("sha256"), stripped.get("sha256"));
+    assert!(!stored_bytes(tmp.path(), &stripped)
+        .windows(4)
+        .any(|w| w == b"EXIF"));
+
+    // Formats without a stripper are refused rather than stored as is.
+    let (status, body) = send_upload_to(
+        app,
+        "/upload?strip_metadata=true",
+        "a.png",
+        &encoded_png(2, 2),
+    )
+    .await;
+    assert_eq!(status, StatusCode::UNSUPPORTED_MEDIA_TYPE);
+    assert_eq!(body["detail"], "strip_metadata_unsupported");
+}
+
+#[tokio::test]
+async fn successful_upload_is_recorded_in_index() {
+    let tmp = tempfile::tempdir().expect("tmpdir");
+    let mut config = test_config(tmp.path());
+    config.webp_conversion = Some(WebpEncoding::Lossless);
+    let app = build_app(state_with_config(config));
+
+    let (status, body) = send_upload(app, "holiday photo.png", &encoded_png(3, 2)).await;
+    assert_eq!(status, StatusCode::OK);
+
+    let index = imgd::index::ImageIndex::open(tmp.path()).expect("index");
+    let record = index
+        .get(body["sha256"].as_str().expect("sha256"))
+        .await
+        .expect("record");
+    assert_eq!(record.token_name, "legacy-default");
+    assert_eq!(record.client_ip, Some("127.0.0.1".parse().unwrap()));
+    assert_eq!(
+        record.original_filename.as_deref(),
+        Some("holiday photo.png")
+    );
+    assert_eq!(record.content_type, "image/webp");
+    assert_eq!(Some(record.size), body["size"].as_u64());
+    assert_eq!(Some(record.path.as_str()), body["path"].as_str());
+    assert_eq!(record.image.map(|i| (i.width, i.height)), Some((3, 2)));
+}
+
+#[tokio::test]
+async fn per_token_size_limit_and_quotas() {
+    let tmp = tempfile::tempdir().expect("tmpdir");
+    let tokens = tmp.path().join("tokens.json");
+    std::fs::write(
+        &tokens,
+        serde_json::json!({ "tokens": [
+            { "name": "tiny", "token": "tiny-token", "max_upload_bytes": 10 },
+            { "name": "capped", "token": "capped-token", "quota_files": 1 },
+        ]})
+        .to_string(),
+    )
+    .expect("tokens file");
+    let mut config = test_config(tmp.path());
+    config.tokens_file = Some(tokens);
+    let app = build_app(state_with_config(config));
+
+    let (status, body) = send_upload_as(
+        app.clone(),
+        "/upload",
+        "tiny-token",
+        "a.webp",
+        &webp_fixture(),
+    )
+    .await;
+    assert_eq!(status, StatusCode::PAYLOAD_TOO_LARGE);
+    assert_eq!(body["error"], "file_too_large");
+
+    let (status, first) = send_upload_as(
+        app.clone(),
+        "/upload",
+        "capped-token",
+        "a.webp",
+        &webp_fixture(),
+    )
+    .await;
+    assert_eq!(status, StatusCode::OK);
+
+    let (status, body) = send_upload_as(
+        app.clone(),
+        "/upload",
+        "capped-token",
+        "b.png",
+        &encoded_png(3, 3),
+    )
+    .await;
+    assert_eq!(status, StatusCode::FORBIDDEN);
+    assert_eq!(body["error"], "quota_exceeded");
+
+    // Re-uploading content the token already owns costs nothing.
+    let (status, again) = send_upload_as(
+        app.clone(),
+        "/upload",
+        "capped-token",
+        "a.webp",
+        &webp_fixture(),
+    )
+    .await;
+    assert_eq!(status, StatusCode::OK);
+    assert_eq!(again["sha256"], first["sha256"]);
+
+    // Usage is rebuilt from the index, so the quota still holds after a restart.
+    let mut config = test_config(tmp.path());
+    config.tokens_file = Some(tmp.path().join("tokens.json"));
+    let app = build_app(state_with_config(config));
+    let (status, _) =
+        send_upload_as(app, "/upload", "capped-token", "b.png", &encoded_png(3, 3)).await;
+    assert_eq!(status, StatusCode::FORBIDDEN);
+    let stored: Vec<_> = walk_files(tmp.path());
+    assert!(!stored.iter().any(|p| p.ends_with(".png")), "{stored:?}");
+}
+
+#[tokio::test]
+async fn upload_tickets_are_single_use_and_scoped() {
+    use axum::{body::Body, http::Request};
+    use http_body_util::BodyExt;
+    use tower::ServiceExt;
+
+    let tmp = tempfile::tempdir().expect("tmpdir");
+    let mut config = test_config(tmp.path());
+    config.tokens_file = Some(write_tokens_file(
+        tmp.path(),
+        &[("web", "web-token", false)],
+    ));
+    config.upload_ticket_secret = Some("k".repeat(32));
+    let app = build_app(state_with_config(config));
+
+    let mint = |body: Value, credential: (&'static str, String)| {
+        let app = app.clone();
+        async move {
+            let req = Request::builder()
+                .method("POST")
+                .uri("/api/upload-tickets")
+                .header("content-type", "application/json")
+                .header(credential.0, credential.1)
+                .body(Body::from(body.to_string()))
+                .expect("request");
+            let resp = app.oneshot(req).await.expect("response");
+            let status = resp.status();
+            let bytes = resp.into_body().collect().await.expect("body").to_bytes();
+            (
+                status,
+                serde_json::from_slice::<Value>(&bytes).unwrap_or(Value::Null),
+            )
+        }
+    };
+    let token = || ("x-upload-token", "web-token".to_owned());
+
+    let (status, minted) = mint(
+        serde_json::json!({ "content_types": ["image/png"], "path_prefix": "avatars/web" }),
+        token(),
+    )
+    .await;
+    assert_eq!(status, StatusCode::CREATED);
+    let url = minted["upload_url"]
+        .as_str()
+        .expect("upload_url")
+        .to_owned();
+    let ticket = minted["ticket"].as_str().expect("ticket").to_owned();
+
+    // No token is sent: the ticket in the URL is the only credential.
+    let (status, body) = send_upload_as(app.clone(), &url, "", "a.png", &encoded_png(2, 2)).await;
+    assert_eq!(status, StatusCode::OK);
+    let path = body["path"].as_str().unwrap();
+    assert!(path.starts_with("/avatars/web/"), "{path}");
+    assert_eq!(stored_bytes(tmp.path(), &body), encoded_png(2, 2));
+    let index = imgd::index::ImageIndex::open(tmp.path()).expect("index");
+    let record = index.get(body["sha256"].as_str().unwrap()).await.unwrap();
+    assert_eq!(record.token_name, "web");
+    assert_eq!(record.path, path);
+
+    let (status, _) = send_upload_as(app.clone(), &url, "", "b.png", &encoded_png(3, 2)).await;
+    assert_eq!(status, StatusCode::UNAUTHORIZED, "ticket is single-use");
+
+    // A ticket covers one file; a request with more stores nothing.
+    let (_, minted) = mint(serde_json::json!({}), token()).await;
+    let url = minted["upload_url"].as_str().unwrap().to_owned();
+    let files: [(&str, &[u8]); 2] = [("e.png", &encoded_png(6, 2)), ("f.png", &encoded_png(7, 2))];
+    let (status, _) = send_files_with(app.clone(), &url, &[], &files).await;
+    assert_eq!(status, StatusCode::BAD_REQUEST);
+    let stored = walk_files(tmp.path());
+    assert_eq!(
+        stored.iter().filter(|p| p.ends_with(".png")).count(),
+        1,
+        "{stored:?}"
+    );
+
+    let (_, minted) = mint(
+        serde_json::json!({ "content_types": ["image/png"] }),
+        token(),
+    )
+    .await;
+    let url = minted["upload_url"].as_str().unwrap().to_owned();
+    let (status, _) = send_upload_as(app.clone(), &url, "", "a.webp", &webp_fixture()).await;
+    assert_eq!(status, StatusCode::UNSUPPORTED_MEDIA_TYPE);
+
+    let (_, minted) = mint(serde_json::json!({ "max_bytes": 10 }), token()).await;
+    let url = minted["upload_url"].as_str().unwrap().to_owned();
+    let (status, _) = send_upload_as(app.clone(), &url, "", "a.webp", &webp_fixture()).await;
+    assert_eq!(status, StatusCode::PAYLOAD_TOO_LARGE);
+
+    // A ticket cannot mint further tickets, and is not used up trying.
+    let (_, minted) = mint(serde_json::json!({}), token()).await;
+    let unbound = minted["ticket"].as_str().unwrap().to_owned();
+    let (status, _) = mint(serde_json::json!({}), ("x-upload-ticket", unbound)).await;
+    assert_eq!(status, StatusCode::FORBIDDEN);
+    let url = minted["upload_url"].as_str().unwrap().to_owned();
+    let (status, _) = send_upload_as(app.clone(), &url, "", "c.png", &encoded_png(4, 2)).await;
+    assert_eq!(status, StatusCode::OK);
+
+    let forged = format!("{}x", ticket);
+    let (status, _) = send_upload_as(
+        app.clone(),
+        &format!("/upload?ticket={forged}"),
+        "",
+        "d.png",
+        &encoded_png(5, 2),
+    )
+    .await;
+    assert_eq!(status, StatusCode::UNAUTHORIZED);
+
+    let (status, _) = mint(serde_json::json!({ "ttl_secs": 86400 }), token()).await;
+    assert_eq!(status, StatusCode::BAD_REQUEST);
+    let (status, _) = mint(
+        serde_json::json!({ "content_types": ["text/html"] }),
+        token(),
+    )
+    .await;
+    assert_eq!(status, StatusCode::BAD_REQUEST);
+    for prefix in ["/upload", "../etc", "a/../b", ".meta", "Avatars"] {
+        let (status, _) = mint(serde_json::json!({ "path_prefix": prefix }), token()).await;
+        assert_eq!(status, StatusCode::BAD_REQUEST, "{prefix}");
+    }
+}
+
+#[tokio::test]
+async fn rate_limited_ticket_stays_usable() {
+    use axum::{body::Body, http::Request};
+    use http_body_util::BodyExt;
+    use tower::ServiceExt;
+
+    let tmp = tempfile::tempdir().expect("tmpdir");
+    let mut config = test_config(tmp.path());
+    config.rate_limit_per_minute = 1;
+    config.upload_ticket_secret = Some("k".repeat(32));
+    let state = state_with_config(config);
+    let app = build_app(state.clone());
+
+    let req = Request::builder()
+        .method("POST")
+        .uri("/api/upload-tickets")
+        .header("content-type", "application/json")
+        .header("x-upload-token", "secret")
+        .body(Body::from("{}"))
+        .expect("request");
+    let resp = app.clone().oneshot(req).await.expect("response");
+    assert_eq!(resp.status(), StatusCode::CREATED);
+    let bytes = resp.into_body().collect().await.expect("body").to_bytes();
+    let minted: Value = serde_json::from_slice(&bytes).expect("json");
+    let url = minted["upload_url"].as_str().unwrap().to_owned();
+
+    let (status, _) = send_upload(app.clone(), "a.webp", &webp_fixture()).await;
+    assert_eq!(status, StatusCode::OK);
+    let (status, _) = send_upload_as(app, &url, "", "b.png", &encoded_png(2, 2)).await;
+    assert_eq!(status, StatusCode::TOO_MANY_REQUESTS);
+
+    // The 429 left the nonce unused.
+    let tickets = state.tickets.as_ref().expect("tickets");
+    let now = chrono::Utc::now().timestamp();
+    let claims = tickets
+        .verify(minted["ticket"].as_str().unwrap(), now)
+        .expect("valid");
+    assert!(tickets.redeem(&claims, now).await.expect("redeem"));
+}
+
+#[tokio::test]
+async fn batch_upload_reports_each_file() {
+    let tmp = tempfile::tempdir().expect("tmpdir");
+    let tokens = tmp.path().join("tokens.json");
+    std::fs::write(
+        &tokens,
+        serde_json::json!({ "tokens": [
+            { "name": "album", "token": "album-token", "quota_files": 2 },
+        ]})
+        .to_string(),
+    )
+    .expect("tokens file");
+    let mut config = test_config(tmp.path());
+    config.tokens_file = Some(tokens);
+    config.max_files_per_upload = 4;
+    let app = build_app(state_with_config(config));
+    let headers = [("x-upload-token", "album-token")];
+
+    let (png, webp, gif) = (encoded_png(3, 2), webp_fixture(), gif_fixture());
+    let (status, body) = send_files_with(
+        app.clone(),
+        "/upload",
+        &headers,
+        &[
+            ("a.png", &png),
+            ("fake.webp", b"hello, world"),
+            ("b.webp", &webp),
+            ("c.gif", &gif),
+            ("d.png", &png),
+        ],
+    )
+    .await;
+    assert_eq!(status, StatusCode::OK);
+    let items = body.as_array().expect("array");
+    assert_eq!(items.len(), 5);
+    assert_eq!(items[0]["width"], 3);
+    assert_eq!(items[1]["error"], "unsupported_media_type");
+    assert_eq!(items[2]["height"], 1);
+    // Quotas apply per file, not per request.
+    assert_eq!(items[3]["error"], "quota_exceeded");
+    assert_eq!(items[4]["error"], "bad_request");
+    assert_eq!(items[4]["detail"], "too_many_files");
+    assert_eq!(stored_bytes(tmp.path(), &items[0]), png);
+
+    // A single file still gets the plain object, unless the client opts in
+    // to the array.
+    let (status, body) =
+        send_files_with(app.clone(), "/upload", &headers, &[("a.png", &png)]).await;
+    assert_eq!(status, StatusCode::OK);
+    assert_eq!(body["sha256"], items[0]["sha256"]);
+    let (status, body) =
+        send_files_with(app, "/upload?batch=true", &headers, &[("a.png", &png)]).await;
+    assert_eq!(status, StatusCode::OK);
+    assert_eq!(body[0]["sha256"], items[0]["sha256"]);
+}
+
+#[tokio::test]
+async fn raw_body_upload_by_put_or_image_content_type() {
+    use axum::{body::Body, extract::ConnectInfo, http::Request};
+    use http_body_util::BodyExt;
+    use tower::ServiceExt;
+
+    let tmp = tempfile::tempdir().expect("tmpdir");
+    let app = build_app(make_test_state(tmp.path()));
+    let send = |method: &str, headers: &[(&str, &str)], body: Vec<u8>| {
+        let mut req = Request::builder()
+            .method(method)
+            .uri("/upload")
+            .header("x-upload-token", "secret");
+        for (name, value) in headers {
+            req = req.header(*name, *value);
+        }
+        let mut req = req.body(Body::from(body)).expect("request");
+        req.extensions_mut().insert(ConnectInfo(
+            "127.0.0.1:8080".parse::<std::net::SocketAddr>().unwrap(),
+        ));
+        let app = app.clone();
+        async move {
+            let resp = app.oneshot(req).await.expect("response");
+            let status = resp.status();
+            let bytes = resp.into_body().collect().await.expect("body").to_bytes();
+            (
+                status,
+                serde_json::from_slice::<Value>(&bytes).expect("json"),
+            )
+        }
+    };
+
+    let png = encoded_png(4, 3);
+    let (status, body) = send("PUT", &[("x-filename", "shot.png")], png.clone()).await;
+    assert_eq!(status, StatusCode::OK);
+    assert_eq!(body["width"], 4);
+    assert_eq!(stored_bytes(tmp.path(), &body), png);
+
+    // Same bytes, same pipeline: the multipart upload deduplicates onto it.
+    let (status, multipart) = send_upload(app.clone(), "shot.png", &png).await;
+    assert_eq!(status, StatusCode::OK);
+    assert_eq!(multipart["path"], body["path"]);
+
+    let webp = webp_fixture();
+    let (status, body) = send("POST", &[("content-type", "image/webp")], webp).await;
+    assert_eq!(status, StatusCode::OK);
+    assert!(body["path"].as_str().unwrap().ends_with(".webp"));
+
+    // The extension still has to match an allowed format.
+    let (status, _) = send("PUT", &[("x-filename", "notes.txt")], png.clone()).await;
+    assert_eq!(status, StatusCode::UNSUPPORTED_MEDIA_TYPE);
+    let (status, _) = send("POST", &[("content-type", "text/plain")], png.clone()).await;
+    assert_eq!(status, StatusCode::UNSUPPORTED_MEDIA_TYPE);
+    let (status, _) = send("PUT", &[], png).await;
+    assert_eq!(status, StatusCode::BAD_REQUEST);
+    let (status, body) = send("PUT", &[("content-type", "image/png")], b"hello".to_vec()).await;
+    assert_eq!(status, StatusCode::UNSUPPORTED_MEDIA_TYPE);
+    assert_eq!(body["error"], "unsupported_media_type");
+
+    // Bodies are read in memory and cut off at the per-file limit.
+    let oversized = vec![0u8; 5 * 1024 * 1024 + 1];
+    let (status, _) = send("PUT", &[("x-filename", "big.png")], oversized).await;
+    assert_eq!(status, StatusCode::PAYLOAD_TOO_LARGE);
+    let staged = std::fs::read_dir(tmp.path().join(".tmp")).expect(".tmp");
+    assert_eq!(staged.count(), 0, "nothing is staged on disk");
+}
+
+#[tokio::test]
+async fn resumable_upload_session_round_trip() {
+    use axum::{
+        body::Body,
+        extract::ConnectInfo,
+        http::{HeaderMap, Request},
+    };
+    use http_body_util::BodyExt;
+    use sha2::{Digest, Sha256};
+    use tower::ServiceExt;
+
+    let tmp = tempfile::tempdir().expect("tmpdir");
+    let mut config = test_config(tmp.path());
+    config.tokens_file = Some(write_tokens_file(
+        tmp.path(),
+        &[("web", "web-token", false), ("other", "other-token", false)],
+    ));
+    let app = build_app(state_with_config(config));
+
+    let send =
+        |method: &'static str, uri: String, headers: Vec<(&'static str, String)>, body: Vec<u8>| {
+            let app = app.clone();
+            async move {
+                let mut req = Request::builder().method(method).uri(uri);
+                for (name, value) in headers {
+                    req = req.header(name, value);
+                }
+                let mut req = req.body(Body::from(body)).expect("request");
+                req.extensions_mut().insert(ConnectInfo(
+                    "127.0.0.1:8080".parse::<std::net::SocketAddr>().unwrap(),
+                ));
+                let resp = app.oneshot(req).await.expect("response");
+                let status = resp.status();
+                let headers: HeaderMap = resp.headers().clone();
+                let bytes = resp.into_body().collect().await.expect("body").to_bytes();
+                let json = serde_json::from_slice::<Value>(&bytes).unwrap_or(Value::Null);
+                (status, headers, json)
+            }
+        };
+    let token = |t: &str| ("x-upload-token", t.to_owned());
+    let offset = |n: usize| ("upload-offset", n.to_string());
+    let create = |sha256: String, size: usize| {
+        send(
+            "POST",
+            "/upload/sessions".to_owned(),
+            vec![
+                token("web-token"),
+                ("content-type", "application/json".to_owned()),
+            ],
+            serde_json::json!({ "filename": "big.png", "size": size, "sha256": sha256 })
+                .to_string()
+                .into_bytes(),
+        )
+    };
+
+    let png = encoded_png(40, 30);
+    let sha = hex::encode(Sha256::digest(&png));
+    let (status, _, session) = create(sha.clone(), png.len()).await;
+    assert_eq!(status, StatusCode::CREATED);
+    let url = session["upload_url"]
+        .as_str()
+        .expect("upload_url")
+        .to_owned();
+    assert_eq!(session["offset"], 0);
+
+    let (first, rest) = png.split_at(png.len() / 2);
+    let (status, headers, _) = send(
+        "PATCH",
+        url.clone(),
+        vec![token("web-token"), offset(0)],
+        first.to_vec(),
+    )
+    .await;
+    assert_eq!(status, StatusCode::NO_CONTENT);
+    assert_eq!(headers["upload-offset"], first.len().to_string());
+
+    // A retried chunk is refused with the offset to resume from.
+    let (status, headers, _) = send(
+        "PATCH",
+        url.clone(),
+        vec![token("web-token"), offset(0)],
+        first.to_vec(),
+    )
+    .await;
+    assert_eq!(status, StatusCode::CONFLICT);
+    assert_eq!(headers["upload-offset"], first.len().to_string());
+
+    // Sessions belong to the token that created them.
+    let (status, _, _) = send("GET", url.clone(), vec![token("other-token")], Vec::new()).await;
+    assert_eq!(status, StatusCode::NOT_FOUND);
+
+    let complete = format!("{url}/complete");
+    let (status, _, _) = send(
+        "POST",
+        complete.clone(),
+        vec![token("web-token")],
+        Vec::new(),
+    )
+    .await;
+    assert_eq!(status, StatusCode::CONFLICT);
+
+    let (status, _, _) = send(
+        "PATCH",
+        url.clone(),
+        vec![token("web-token"), offset(first.len())],
+        rest.to_vec(),
+    )
+    .await;
+    assert_eq!(status, StatusCode::NO_CONTENT);
+    let (status, _, info) = send("GET", url.clone(), vec![token("web-token")], Vec::new()).await;
+    assert_eq!(status, StatusCode::OK);
+    assert_eq!(info["offset"], png.len());
+
+    let (status, _, body) = send("POST", complete, vec![token("web-token")], Vec::new()).await;
+    assert_eq!(status, StatusCode::OK);
+    assert_eq!(body["sha256"], sha);
+    assert_eq!(stored_bytes(tmp.path(), &body), png);
+    let (status, _, _) = send("GET", url, vec![token("web-token")], Vec::new()).await;
+    assert_eq!(status, StatusCode::NOT_FOUND);
+
+    // Bytes that do not match the declared digest are discarded.
+    let (_, _, session) = create("0".repeat(64), png.len()).await;
+    let url = session["upload_url"].as_str().unwrap().to_owned();
+    send(
+        "PATCH",
+        url.clone(),
+        vec![token("web-token"), offset(0)],
+        png.clone(),
+    )
+    .await;
+    let (status, _, _) = send(
+        "POST",
+        format!("{url}/complete"),
+        vec![token("web-token")],
+        Vec::new(),
+    )
+    .await;
+    assert_eq!(status, StatusCode::BAD_REQUEST);
+    let (status, _, _) = send("GET", url, vec![token("web-token")], Vec::new()).await;
+    assert_eq!(status, StatusCode::NOT_FOUND);
+
+    // Chunks may not run past the declared size.
+    let (_, _, session) = create(sha.clone(), 4).await;
+    let url = session["upload_url"].as_str().unwrap().to_owned();
+    let (status, _, _) = send(
+        "PATCH",
+        url,
+        vec![token("web-token"), offset(0)],
+        png.clone(),
+    )
+    .await;
+    assert_eq!(status, StatusCode::PAYLOAD_TOO_LARGE);
+}
+
+#[tokio::test]
+async fn forwarded_client_ip_only_from_trusted_proxies() {
+    let tmp = tempfile::tempdir().expect("tmpdir");
+    let mut config = test_config(tmp.path());
+    config.rate_limit_per_minute = 1;
+    let app = build_app(state_with_config(config.clone()));
+
+    // Direct clients cannot dodge the per-IP limit by inventing hops.
+    let spoof = |ip: &'static str| [("x-upload-token", "secret"), ("x-forwarded-for", ip)];
+    let (status, _) = send_upload_with(
+        app.clone(),
+        "/upload",
+        &spoof("198.51.100.1"),
+        "a.webp",
+        &webp_fixture(),
+    )
+    .await;
+    assert_eq!(status, StatusCode::OK);
+    let (status, _) = send_upload_with(
+        app,
+        "/upload",
+        &spoof("198.51.100.2"),
+        "b.png",
+        &encoded_png(2, 2),
+    )
+    .await;
+    assert_eq!(status, StatusCode::TOO_MANY_REQUESTS);
+
+    let tmp = tempfile::tempdir().expect("tmpdir");
+    config.data_dir = tmp.path().to_path_buf();
+    config.trusted_proxies = imgd::client_ip::parse_net_list("127.0.0.1").unwrap();
+    let app = build_app(state_with_config(config));
+    let via_proxy = |xff: &'static str| [("x-upload-token", "secret"), ("x-forwarded-for", xff)];
+    let (status, first) = send_upload_with(
+        app.clone(),
+        "/upload",
+        &via_proxy("6.6.6.6, 198.51.100.1"),
+        "a.webp",
+        &webp_fixture(),
+    )
+    .await;
+    assert_eq!(status, StatusCode::OK);
+    let (status, _) = send_upload_with(
+        app.clone(),
+        "/upload",
+        &via_proxy("198.51.100.2"),
+        "b.png",
+        &encoded_png(2, 2),
+    )
+    .await;
+    assert_eq!(status, StatusCode::OK);
+
+    // nginx passes a client's own Forwarded header through; it must not
+    // buy a fresh bucket.
+    let (status, _) = send_upload_with(
+        app,
+        "/upload",
+        &[
+            ("x-upload-token", "secret"),
+            ("forwarded", "for=9.9.9.9"),
+            ("x-forwarded-for", "198.51.100.1"),
+        ],
+        "c.png",
+        &encoded_png(3, 3),
+    )
+    .await;
+    assert_eq!(status, StatusCode::TOO_MANY_REQUESTS);
+
+    let index = imgd::index::ImageIndex::open(tmp.path()).expect("index");
+    let record = index.get(first["sha256"].as_str().unwrap()).await.unwrap();
+    assert_eq!(record.client_ip, Some("198.51.100.1".parse().unwrap()));
+}
+
+fn walk_files(root: &std::path::Path) -> Vec<String> {
+    let mut out = Vec::new();
+    for entry in std::fs::read_dir(root).expect("read_dir").flatten() {
+        let path = entry.path();
+        if path.is_dir() {
+            out.extend(walk_files(&path));
+        } else {
+            out.push(path.display().to_string());
+        }
+    }
+    out
+}